@@ -1,37 +1,39 @@
-use crate::backup::consolidate::{self, BackupComponentMeta};
+use crate::backup::consolidate::{self, BackupComponentMeta, ComponentSource};
 use crate::cli::BackupArgs;
+use crate::config::profile;
 use crate::events::{BackupPhase, ProgressEvent};
 use crate::flatpak::flatpak;
+use crate::pm::packages::{self, InstallReason};
 use crate::pm::paru;
+use crate::prune::prune;
 use crate::system::{home, keys};
-use crate::utils::compression::ARCHIVE_EXT;
 use crossbeam_channel::Sender;
 use dialoguer::{Confirm, Password};
-use regex::Regex;
-use std::fs;
 use std::io;
 use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
-/// Clean up temporary backup files in current directory
-fn cleanup_backup_files() {
-    let pattern = format!(r".*_backup\.{}", ARCHIVE_EXT);
-    let re = Regex::new(&pattern).unwrap();
-
-    if let Ok(entries) = fs::read_dir(".") {
-        for entry in entries.flatten() {
-            let path = entry.path();
-            if let Some(file_name) = path.file_name().and_then(|f| f.to_str()) {
-                if re.is_match(file_name) || file_name == "apps.txt" || file_name == "flatpak_apps.txt" {
-                    let _ = fs::remove_file(path);
+/// Runs a backup. Errors are also sent to `tx` as `ProgressEvent::Error`.
+pub fn handle_backup_with_tx(
+    args: &BackupArgs,
+    tx: Option<&Sender<ProgressEvent>>,
+) -> io::Result<()> {
+    if args.profile.is_some() {
+        return match profile::resolve_backup_args(args) {
+            Ok(resolved) => handle_backup_with_tx(&resolved, tx),
+            Err(e) => {
+                if let Some(sender) = tx {
+                    let _ = sender.send(ProgressEvent::Error(e.to_string()));
                 }
+                Err(io::Error::new(
+                    e.kind(),
+                    format!("Failed to load profile: {}", e),
+                ))
             }
-        }
+        };
     }
-}
 
-pub fn handle_backup_with_tx(args: &BackupArgs, tx: Option<&Sender<ProgressEvent>>) {
     let home_dir = std::env::var("HOME").expect("HOME environment variable not set");
     let home_path = Path::new(&home_dir);
 
@@ -78,20 +80,20 @@ pub fn handle_backup_with_tx(args: &BackupArgs, tx: Option<&Sender<ProgressEvent
     }
 
     if interrupted.load(Ordering::SeqCst) {
-        exit_gracefully();
         if let Some(sender) = tx {
             let _ = sender.send(ProgressEvent::Error("Operation canceled by user".to_string()));
         }
-        return;
+        return Err(canceled());
     }
 
     if !backup_components.is_empty() {
         if let Some(sender) = tx {
             let _ = sender.send(ProgressEvent::PhaseChanged(BackupPhase::Compressing));
         }
-        match consolidate::consolidate_backups(&backup_components, args) {
+        match consolidate::consolidate_backups(&backup_components, args, &interrupted) {
             Ok(archive_path) => {
                 println!("All backups consolidated successfully into PBAR container.");
+                prune_after_backup(&archive_path, args);
                 if let Some(sender) = tx {
                     let _ = sender.send(ProgressEvent::StatusMessage(format!(
                         "Archive saved to {}",
@@ -100,11 +102,17 @@ pub fn handle_backup_with_tx(args: &BackupArgs, tx: Option<&Sender<ProgressEvent
                     let _ = sender.send(ProgressEvent::Completed);
                 }
             }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                if let Some(sender) = tx {
+                    let _ = sender.send(ProgressEvent::Error("Operation canceled by user".to_string()));
+                }
+                return Err(canceled());
+            }
             Err(e) => {
-                eprintln!("Failed to consolidate backups: {}", e);
                 if let Some(sender) = tx {
                     let _ = sender.send(ProgressEvent::Error(e.to_string()));
                 }
+                return Err(consolidate_failed(e));
             }
         }
     } else {
@@ -136,6 +144,7 @@ pub fn handle_backup_with_tx(args: &BackupArgs, tx: Option<&Sender<ProgressEvent
             backup_keys(&mut backup_components, home_path, &interrupted);
 
             let new_args = BackupArgs {
+                profile: None,
                 archive_path: args.archive_path.clone(),
                 apps: true,
                 home: true,
@@ -144,38 +153,108 @@ pub fn handle_backup_with_tx(args: &BackupArgs, tx: Option<&Sender<ProgressEvent
                 keys: true,
                 encrypt: backup_key.is_some(),
                 encrypt_key: backup_key,
+                compression: args.compression,
+                cipher: args.cipher,
+                sign_key: args.sign_key.clone(),
+                kdf_memory: args.kdf_memory,
+                kdf_iterations: args.kdf_iterations,
+                kdf_parallelism: args.kdf_parallelism,
+                incremental_from: args.incremental_from.clone(),
+                repo: args.repo.clone(),
+                retention: args.retention.clone(),
             };
 
-            match consolidate::consolidate_backups(&backup_components, &new_args) {
-                Ok(archive_path) => println!("Archive saved to {}", archive_path.display()),
-                Err(e) => eprintln!("Failed to consolidate backups: {}", e),
+            match consolidate::consolidate_backups(&backup_components, &new_args, &interrupted) {
+                Ok(archive_path) => {
+                    println!("Archive saved to {}", archive_path.display());
+                    prune_after_backup(&archive_path, &new_args);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => return Err(canceled()),
+                Err(e) => return Err(consolidate_failed(e)),
             }
         } else {
             exit_gracefully();
         }
     }
+    Ok(())
 }
 
-pub fn handle_backup(args: &BackupArgs) {
-    handle_backup_with_tx(args, None);
+pub fn handle_backup(args: &BackupArgs) -> io::Result<()> {
+    handle_backup_with_tx(args, None)
+}
+
+/// Applies the `--keep-*` options to the directory the new archive was written to.
+fn prune_after_backup(archive_path: &Path, args: &BackupArgs) {
+    if !args.retention.is_set() {
+        return;
+    }
+    let dir = match archive_path.parent() {
+        Some(dir) => dir,
+        None => return,
+    };
+    println!("Pruning {}...", dir.display());
+    let passphrase = args.encrypt_key.as_deref().filter(|_| args.encrypt);
+    match prune::prune_archives(dir, &args.retention, passphrase, false) {
+        Ok(deleted) if args.repo.is_some() && !deleted.is_empty() => {
+            println!("Run `repo gc` to free the chunks of the deleted snapshots.");
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("Failed to prune old archives: {}", e),
+    }
+}
+
+/// Stores `names` one per line as `<category>/<file_name>`.
+fn name_list(
+    category: &'static str,
+    file_name: &'static str,
+    names: &[String],
+    extra_info: Option<String>,
+) -> BackupComponentMeta {
+    let contents = names.join("\n").into_bytes();
+    BackupComponentMeta {
+        category,
+        count: names.len(),
+        size_bytes: contents.len() as u64,
+        source: ComponentSource::Data { file_name, contents },
+        extra_info,
+    }
 }
 
 fn backup_apps(components: &mut Vec<BackupComponentMeta>) {
     println!("Backing up installed apps...");
-    match paru::list_installed_apps() {
-        Ok((file, count, pm_name)) => {
-            println!("Installed apps ({}) backed up successfully.", count);
-            let size_bytes = fs::metadata(&file).map(|m| m.len()).unwrap_or(0);
-            components.push(BackupComponentMeta {
-                category: "appsb",
-                path: file,
-                count,
-                size_bytes,
-                extra_info: Some(pm_name),
-            });
+    let packages = match packages::list_packages() {
+        Ok(packages) => packages,
+        Err(e) => {
+            eprintln!("Failed to backup installed apps: {}", e);
+            return;
         }
-        Err(e) => eprintln!("Failed to backup installed apps: {}", e),
-    }
+    };
+    let contents = match serde_json::to_vec_pretty(&packages) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("Failed to backup installed apps: {}", e);
+            return;
+        }
+    };
+    let explicit = packages
+        .iter()
+        .filter(|p| p.reason == InstallReason::Explicit)
+        .count();
+    println!(
+        "Installed apps ({} explicit, {} in total) backed up successfully.",
+        explicit,
+        packages.len()
+    );
+    components.push(BackupComponentMeta {
+        category: "appsb",
+        count: explicit,
+        size_bytes: contents.len() as u64,
+        source: ComponentSource::Data {
+            file_name: packages::PACKAGES_FILE,
+            contents,
+        },
+        extra_info: Some(paru::detect_package_manager().to_string()),
+    });
 }
 
 fn backup_home(
@@ -184,23 +263,23 @@ fn backup_home(
     exclude_dirs: &[String],
     interrupted: &Arc<AtomicBool>,
 ) {
-    println!("Backing up home directory...");
+    println!("Scanning home directory...");
     match home::backup_home(home_path, exclude_dirs, interrupted) {
-        Ok(file) => {
-            println!("Home directory backed up successfully.");
-            let size_bytes = fs::metadata(&file).map(|m| m.len()).unwrap_or(0);
+        Ok((source, summary)) => {
+            println!(
+                "Home directory: {} files, {} bytes to back up.",
+                summary.files, summary.bytes
+            );
             components.push(BackupComponentMeta {
                 category: "homeb",
-                path: file,
-                count: 1,
-                size_bytes,
+                source,
+                count: summary.files,
+                size_bytes: summary.bytes,
                 extra_info: None,
             });
         }
         Err(e) => {
-            if e.kind() == io::ErrorKind::Interrupted {
-                cleanup_backup_files();
-            } else {
+            if e.kind() != io::ErrorKind::Interrupted {
                 eprintln!("Failed to backup home directory: {}", e);
             }
         }
@@ -210,16 +289,14 @@ fn backup_home(
 fn backup_flatpak(components: &mut Vec<BackupComponentMeta>) {
     println!("Backing up Flatpak applications...");
     match flatpak::list_installed_flatpak_apps() {
-        Ok((file, count)) => {
-            println!("Installed Flatpak apps ({}) backed up successfully.", count);
-            let size_bytes = fs::metadata(&file).map(|m| m.len()).unwrap_or(0);
-            components.push(BackupComponentMeta {
-                category: "flatpakb",
-                path: file,
-                count,
-                size_bytes,
-                extra_info: None,
-            });
+        Ok(apps) => {
+            println!("Installed Flatpak apps ({}) backed up successfully.", apps.len());
+            components.push(name_list(
+                "flatpakb",
+                flatpak::FLATPAK_APPS_LIST_FILE,
+                &apps,
+                None,
+            ));
         }
         Err(e) => eprintln!("Failed to backup Flatpak apps: {}", e),
     }
@@ -232,36 +309,37 @@ fn backup_keys(
 ) {
     println!("Backing up GPG keys...");
     match keys::backup_gpg_keys(home_path, interrupted) {
-        Ok(file) => {
-            let size_bytes = fs::metadata(&file).map(|m| m.len()).unwrap_or(0);
-            components.push(BackupComponentMeta {
-                category: "gnupgb",
-                path: file,
-                count: 1,
-                size_bytes,
-                extra_info: None,
-            });
-        }
+        Ok((source, summary)) => components.push(BackupComponentMeta {
+            category: "gnupgb",
+            source,
+            count: summary.files,
+            size_bytes: summary.bytes,
+            extra_info: None,
+        }),
         Err(e) => eprintln!("Failed to backup GPG keys: {}", e),
     }
 
     println!("Backing up SSH keys...");
     match keys::backup_ssh_keys(home_path, interrupted) {
-        Ok(file) => {
-            let size_bytes = fs::metadata(&file).map(|m| m.len()).unwrap_or(0);
-            components.push(BackupComponentMeta {
-                category: "sshb",
-                path: file,
-                count: 1,
-                size_bytes,
-                extra_info: None,
-            });
-        }
+        Ok((source, summary)) => components.push(BackupComponentMeta {
+            category: "sshb",
+            source,
+            count: summary.files,
+            size_bytes: summary.bytes,
+            extra_info: None,
+        }),
         Err(e) => eprintln!("Failed to backup SSH keys: {}", e),
     }
 }
 
 fn exit_gracefully() {
-    cleanup_backup_files();
     eprintln!("Operation canceled.");
 }
+
+fn canceled() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "Operation canceled by user")
+}
+
+fn consolidate_failed(e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("Failed to consolidate backups: {}", e))
+}
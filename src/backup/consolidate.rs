@@ -1,23 +1,118 @@
 use chrono::Utc;
-use flate2::write::GzEncoder;
-use flate2::Compression;
+use sha2::{Digest, Sha256};
 use std::fs::{self, File};
-use std::io::{self, Write};
+use std::io::{self, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
 
-use crate::cli::BackupArgs;
+use crate::backup::incremental::{self, FileIndex};
+use crate::cli::{BackupArgs, DEFAULT_ARCHIVE_PATH};
 use crate::pbar::manifest::{
-    ArchiveContents, ComponentInfo, HomeInfo, KeysInfo, PbarManifest, SecurityInfo,
+    ArchiveContents, ComponentInfo, FileRecord, HomeInfo, IncrementInfo, KeysInfo, PbarManifest,
+    RepositoryInfo, SecurityInfo,
 };
-use crate::pbar::{derive_argon2_key, PbarChunkWriter, PbarHeader};
+use crate::pbar::index::ArchiveIndex;
+use crate::pbar::signature::{load_signing_key, prepare_signed_header, sign_header, HashingWriter};
+use crate::pbar::{PbarChunkWriter, PbarHeader};
+use crate::repo::chunker::ChunkerParams;
+use crate::repo::snapshot::{self, TREE_ENTRY};
+use crate::repo::store::Repository;
 use crate::system::info::collect_system_info;
+use crate::utils::compression;
 
 pub const PBAR_EXT: &str = "pbar";
 
+/// Where the data of a backup component comes from. Every source is read
+/// straight into the payload tarball, below the component directory.
+#[derive(Debug, Clone)]
+pub enum ComponentSource {
+    /// A file generated in memory, such as a package list.
+    Data {
+        file_name: &'static str,
+        contents: Vec<u8>,
+    },
+    /// A directory tree, stored as `<category>/<relative path>`. Paths in
+    /// `exclude_paths` are absolute and left out with everything below them.
+    Directory {
+        root: PathBuf,
+        exclude_paths: Vec<PathBuf>,
+    },
+    /// An existing file, stored as `<category>/<file name>`.
+    File(PathBuf),
+}
+
+impl ComponentSource {
+    /// Location of the component inside the payload, as recorded in the manifest.
+    fn archive_path(&self, category: &str) -> String {
+        match self {
+            ComponentSource::Data { file_name, .. } => format!("{}/{}", category, file_name),
+            ComponentSource::Directory { .. } => format!("{}/", category),
+            ComponentSource::File(path) => format!(
+                "{}/{}",
+                category,
+                path.file_name().unwrap_or_default().to_string_lossy()
+            ),
+        }
+    }
+
+    /// Streams the component into `tar` and returns the index records of the
+    /// files written. Paths in `skip` are left out of directory trees, so
+    /// that an archive written inside one does not include itself, and files
+    /// of a tree are only added when `keep` accepts their payload path.
+    fn append_to<W: Write>(
+        &self,
+        tar: &mut tar::Builder<W>,
+        category: &str,
+        skip: &[PathBuf],
+        keep: &dyn Fn(&Path) -> bool,
+        should_stop: &AtomicBool,
+    ) -> io::Result<Vec<FileRecord>> {
+        let dir = Path::new(category);
+        match self {
+            ComponentSource::Data { file_name, contents } => {
+                let mtime = Utc::now().timestamp().max(0) as u64;
+                let mut header = tar::Header::new_gnu();
+                header.set_size(contents.len() as u64);
+                header.set_mode(0o644);
+                header.set_mtime(mtime);
+                header.set_cksum();
+                tar.append_data(&mut header, dir.join(file_name), &contents[..])?;
+                Ok(vec![FileRecord {
+                    path: self.archive_path(category),
+                    size: contents.len() as u64,
+                    mode: 0o644,
+                    mtime,
+                    sha256: hex::encode(Sha256::digest(contents)),
+                    stored_in: None,
+                }])
+            }
+            ComponentSource::Directory {
+                root,
+                exclude_paths,
+            } => {
+                let mut exclude_paths = exclude_paths.clone();
+                exclude_paths.extend_from_slice(skip);
+                compression::append_directory(tar, root, dir, &exclude_paths, keep, should_stop)
+            }
+            ComponentSource::File(path) => {
+                let file_name = path.file_name().ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Not a file: {}", path.display()),
+                    )
+                })?;
+                let mut f = File::open(path)?;
+                let record = compression::append_file_indexed(tar, &dir.join(file_name), &mut f)?;
+                Ok(record.into_iter().collect())
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BackupComponentMeta {
     pub category: &'static str,
-    pub path: PathBuf,
+    pub source: ComponentSource,
     pub count: usize,
     pub size_bytes: u64,
     pub extra_info: Option<String>,
@@ -36,10 +131,15 @@ pub fn expand_user_path(p: &str) -> PathBuf {
     PathBuf::from(p)
 }
 
-/// Consolidates individual backup files into a single `.pbar` container archive.
+/// Streams the backup components into a single `.pbar` container archive.
+/// Every file is read once, and indexed from the bytes that went into the
+/// payload. Nothing is staged on disk; a partly written archive is removed
+/// when this fails or `should_stop` is raised. With `--repo` the directory components
+/// go to the repository as chunks and the archive becomes a snapshot in it.
 pub fn consolidate_backups(
     components: &[BackupComponentMeta],
     args: &BackupArgs,
+    should_stop: &AtomicBool,
 ) -> io::Result<PathBuf> {
     let timestamp = Utc::now().format("%Y-%m-%d-%H-%M-%S").to_string();
     let mut flags_str = String::new();
@@ -58,11 +158,22 @@ pub fn consolidate_backups(
     if args.encrypt {
         flags_str.push('e');
     }
+    if args.incremental_from.is_some() {
+        flags_str.push('i');
+    }
 
     let archive_name = format!("backup-{}-{}.{}", timestamp, flags_str, PBAR_EXT);
 
-    let raw_archive_dir = args.archive_path.as_deref().unwrap_or("~/Backups");
-    let archive_dir = expand_user_path(raw_archive_dir);
+    let repository = match args.repo.as_deref() {
+        Some(dir) => Some(open_repository(&expand_user_path(dir), args)?),
+        None => None,
+    };
+    let _lock = repository.as_ref().map(Repository::lock).transpose()?;
+
+    let archive_dir = match &repository {
+        Some(repo) => repo.snapshots_dir(),
+        None => expand_user_path(args.archive_path.as_deref().unwrap_or(DEFAULT_ARCHIVE_PATH)),
+    };
 
     if !archive_dir.exists() {
         fs::create_dir_all(&archive_dir)?;
@@ -70,6 +181,49 @@ pub fn consolidate_backups(
     }
 
     let archive_path = archive_dir.join(&archive_name);
+    // Left out of the directory trees, which might hold the archive
+    let skip = [archive_path.clone()];
+
+    let base = match args.incremental_from.as_deref() {
+        Some(spec) => Some(incremental::load_base(
+            spec,
+            &archive_dir,
+            args.encrypt_key.as_deref(),
+        )?),
+        None => None,
+    };
+    let (mut index, tree) = match &repository {
+        Some(repo) => {
+            let mut tree = snapshot::store_tree(repo, components, should_stop)?;
+            println!(
+                "Repository {}: {} new chunks ({} bytes), {} reused.",
+                repo.root().display(),
+                tree.new_chunks,
+                tree.new_bytes,
+                tree.reused_chunks
+            );
+            let index = FileIndex {
+                records: std::mem::take(&mut tree.records),
+                ..Default::default()
+            };
+            let tree_json = serde_json::to_vec(&tree.entries).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("Snapshot tree error: {}", e))
+            })?;
+            (index, Some(tree_json))
+        }
+        None => match &base {
+            Some(base) => (
+                incremental::plan_increment(components, base, &skip, should_stop)?,
+                None,
+            ),
+            None => (FileIndex::default(), None),
+        },
+    };
+
+    let signing_key = match args.sign_key.as_deref() {
+        Some(path) => Some(load_signing_key(&expand_user_path(path))?),
+        None => None,
+    };
 
     // Build PbarManifest
     let sys_info = collect_system_info();
@@ -90,6 +244,7 @@ pub fn consolidate_backups(
         included: false,
         uncompressed_size_bytes: 0,
         file_path: None,
+        exclude_dir: Vec::new(),
     };
     let mut gpg_included = false;
     let mut ssh_included = false;
@@ -100,17 +255,24 @@ pub fn consolidate_backups(
                 apps_info.included = true;
                 apps_info.count = meta.count;
                 apps_info.package_manager = meta.extra_info.clone();
-                apps_info.file_path = Some("appsb/apps.txt".to_string());
+                apps_info.file_path = Some(meta.source.archive_path(meta.category));
             }
             "flatpakb" => {
                 flatpak_info.included = true;
                 flatpak_info.count = meta.count;
-                flatpak_info.file_path = Some("flatpakb/flatpak_apps.txt".to_string());
+                flatpak_info.file_path = Some(meta.source.archive_path(meta.category));
             }
             "homeb" => {
                 home_info.included = true;
                 home_info.uncompressed_size_bytes = meta.size_bytes;
-                home_info.file_path = Some("homeb/home_backup.tar.gz".to_string());
+                home_info.file_path = Some(meta.source.archive_path(meta.category));
+                if let ComponentSource::Directory { root, exclude_paths } = &meta.source {
+                    home_info.exclude_dir = exclude_paths
+                        .iter()
+                        .filter_map(|path| path.strip_prefix(root).ok())
+                        .map(|dir| dir.to_string_lossy().to_string())
+                        .collect();
+                }
             }
             "gnupgb" => {
                 gpg_included = true;
@@ -123,15 +285,15 @@ pub fn consolidate_backups(
     }
 
     let manifest = PbarManifest {
-        format_version: "1.2".to_string(),
+        format_version: "1.3".to_string(),
         created_at: Utc::now().to_rfc3339(),
         creator: "Parch Backup v0.1.0".to_string(),
         security: SecurityInfo {
             encrypted: args.encrypt,
-            signed: false,
-            signature_type: "None".to_string(),
+            signed: signing_key.is_some(),
+            signature_type: if signing_key.is_some() { "Ed25519" } else { "None" }.to_string(),
             kdf: "Argon2id".to_string(),
-            cipher: if args.encrypt { "AES-256-GCM" } else { "None" }.to_string(),
+            cipher: if args.encrypt { args.cipher_kind().name() } else { "None" }.to_string(),
         },
         system_info: sys_info,
         archive_contents: ArchiveContents {
@@ -150,18 +312,28 @@ pub fn consolidate_backups(
                 file_path: None,
             },
         },
+        increment: base.as_ref().map(|base| IncrementInfo {
+            base: base.name.clone(),
+        }),
+        repository: repository.as_ref().map(|repo| RepositoryInfo {
+            id: repo.id.clone(),
+        }),
     };
 
-    let manifest_bytes = manifest.to_json_bytes().map_err(|e| {
-        io::Error::new(io::ErrorKind::InvalidData, format!("Manifest error: {}", e))
-    })?;
-
     // Prepare PbarHeader
-    let header = PbarHeader::new(args.encrypt, true, manifest_bytes.len() as u32);
+    let mut header = PbarHeader::new(args.encrypt, true, 0);
+    header.feature_flags |= args.compression_codec().header_flag();
+    if args.encrypt {
+        header.feature_flags |= args.cipher_kind().header_flag();
+        header.set_kdf_params(args.kdf_params());
+    }
+    if let Some(ref key) = signing_key {
+        prepare_signed_header(&mut header, key);
+    }
 
     let derived_key = if args.encrypt {
         if let Some(ref pass) = args.encrypt_key {
-            Some(derive_argon2_key(pass.as_bytes(), &header.salt)?)
+            Some(header.derive_key(pass.as_bytes())?)
         } else {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
@@ -172,45 +344,131 @@ pub fn consolidate_backups(
         None
     };
 
-    let archive_file = File::create(&archive_path)?;
-    let mut pbar_writer = PbarChunkWriter::new(archive_file, derived_key, header.base_nonce);
+    let manifest_bytes = manifest.to_json_bytes().map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("Manifest error: {}", e))
+    })?;
+
+    // Manifest block after the header, AEAD-sealed on its own when encrypting
+    let manifest_block = manifest.to_block(&header, derived_key.as_ref())?;
+    header.manifest_size = manifest_block.len() as u32;
+    // Reserves the index offset, filled in once the payload is written
+    header.set_index_offset(0);
+
+    let mut write_archive = || -> io::Result<()> {
+        let mut archive_file = File::create(&archive_path)?;
+
+        // 1. Write PBAR Header in plaintext, its index offset and signature are filled in later
+        header.write_to(&mut archive_file)?;
 
-    // 1. Write PBAR Header into chunk writer stream
-    header.write_to(&mut pbar_writer)?;
+        // 2. Write Manifest block outside the chunk stream
+        let mut hashing_writer = HashingWriter::new(archive_file);
+        hashing_writer.write_all(&manifest_block)?;
 
-    // 2. Write Manifest bytes
-    pbar_writer.write_all(&manifest_bytes)?;
+        let mut pbar_writer = PbarChunkWriter::with_cipher(
+            hashing_writer,
+            derived_key,
+            args.cipher_kind(),
+            header.base_nonce,
+        );
 
-    // 3. Write POSIX inner tarball compressed stream into payload
-    {
-        let enc = GzEncoder::new(&mut pbar_writer, Compression::default());
-        let mut tar_builder = tar::Builder::new(enc);
+        // 3. Stream the components as one compressed POSIX tarball into the payload
+        {
+            let enc = args.compression_codec().encoder(&mut pbar_writer)?;
+            let mut tar_builder = tar::Builder::new(enc);
 
-        // Add manifest.json as first file in tarball
-        let mut manifest_header = tar::Header::new_gnu();
-        manifest_header.set_size(manifest_bytes.len() as u64);
-        manifest_header.set_mode(0o644);
-        manifest_header.set_cksum();
-        tar_builder.append_data(&mut manifest_header, "manifest.json", &manifest_bytes[..])?;
+            // Add manifest.json as first file in tarball
+            let mut manifest_header = tar::Header::new_gnu();
+            manifest_header.set_size(manifest_bytes.len() as u64);
+            manifest_header.set_mode(0o644);
+            manifest_header.set_cksum();
+            tar_builder.append_data(&mut manifest_header, "manifest.json", &manifest_bytes[..])?;
 
-        // Add each backup component to tarball
-        for meta in components {
-            let file_name = meta.path.file_name().unwrap();
-            let subdir = meta.category;
-            let path_in_archive = Path::new(subdir).join(file_name);
+            if let Some(ref tree_json) = tree {
+                let mut tree_header = tar::Header::new_gnu();
+                tree_header.set_size(tree_json.len() as u64);
+                tree_header.set_mode(0o644);
+                tree_header.set_mtime(Utc::now().timestamp().max(0) as u64);
+                tree_header.set_cksum();
+                tar_builder.append_data(&mut tree_header, TREE_ENTRY, &tree_json[..])?;
+            }
 
-            let mut f = File::open(&meta.path)?;
-            tar_builder.append_file(&path_in_archive, &mut f)?;
+            let keep = |path: &Path| {
+                base.is_none() || index.stored.contains(path.to_string_lossy().as_ref())
+            };
+            let mut written = Vec::new();
+            for meta in components {
+                if tree.is_some() && matches!(meta.source, ComponentSource::Directory { .. }) {
+                    continue; // Stored in the repository
+                }
+                written.extend(meta.source.append_to(
+                    &mut tar_builder,
+                    meta.category,
+                    &skip,
+                    &keep,
+                    should_stop,
+                )?);
+            }
+            index.complete(written, base.as_ref());
 
-            // Clean up temporary component file after packing
-            let _ = fs::remove_file(&meta.path);
+            tar_builder.into_inner()?.finish()?;
         }
+        let mut hashing_writer = pbar_writer.finish()?;
 
-        tar_builder.finish()?;
-    }
+        // 4. The file index follows the payload, made while writing it
+        header.set_index_offset(hashing_writer.get_mut().stream_position()?);
+        let archive_index = ArchiveIndex {
+            files: std::mem::take(&mut index.records),
+            deleted: index.deleted.clone(),
+        };
+        let hashing_writer = archive_index.write_to(hashing_writer, &header, derived_key)?;
+        let (mut archive_file, content_digest) = hashing_writer.finalize();
+
+        // 5. Sign header and content digest, then rewrite the header in place
+        if let Some(ref key) = signing_key {
+            sign_header(&mut header, key, &content_digest)?;
+        }
+        archive_file.seek(SeekFrom::Start(0))?;
+        header.write_to(&mut archive_file)?;
+        archive_file.flush()?;
+        Ok(())
+    };
 
-    let _final_file = pbar_writer.finish()?;
+    if let Err(e) = write_archive() {
+        let _ = fs::remove_file(&archive_path);
+        return Err(e);
+    }
+    if let Some(ref base) = base {
+        println!(
+            "Incremental backup on top of {}: {} new or changed files, {} deleted.",
+            base.name,
+            index.stored.len(),
+            index.deleted.len()
+        );
+    }
 
     println!("PBAR archive created successfully: {}", archive_path.display());
     Ok(archive_path)
 }
+
+/// Opens the `--repo` repository, creating it on first use with the
+/// encryption settings of `args`.
+fn open_repository(root: &Path, args: &BackupArgs) -> io::Result<Repository> {
+    let passphrase = args.encrypt_key.as_deref().filter(|_| args.encrypt);
+    if !Repository::exists(root) {
+        return Repository::init(
+            root,
+            passphrase,
+            args.cipher_kind(),
+            args.kdf_params(),
+            ChunkerParams::default(),
+        );
+    }
+    let repo = Repository::open(root, passphrase)?;
+    if !repo.is_encrypted() && args.encrypt {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Repository {} is not encrypted, drop --encrypt", root.display()),
+        ));
+    }
+    Ok(repo)
+}
@@ -0,0 +1,248 @@
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::backup::consolidate::{
+    expand_user_path, BackupComponentMeta, ComponentSource, PBAR_EXT,
+};
+use crate::pbar::manifest::FileRecord;
+use crate::pbar::PbarArchive;
+use crate::utils::compression;
+
+/// `--incremental-from` value that picks the newest archive in the archive directory.
+pub const AUTO_BASE: &str = "auto";
+
+/// File index of the archive an incremental backup is built on.
+pub struct Base {
+    /// File name of the base archive.
+    pub name: String,
+    pub files: HashMap<String, FileRecord>,
+}
+
+/// Records of the files captured by a backup, and which of them the new
+/// archive has to store.
+#[derive(Default)]
+pub struct FileIndex {
+    pub records: Vec<FileRecord>,
+    pub stored: HashSet<String>,
+    /// Files of the base that no longer exist.
+    pub deleted: Vec<String>,
+}
+
+impl FileIndex {
+    /// Adds the records of the files the payload stored, made while writing
+    /// them, and lists the files of `base` that are recorded nowhere.
+    pub fn complete(&mut self, written: Vec<FileRecord>, base: Option<&Base>) {
+        self.records.extend(written);
+        if let Some(base) = base {
+            let current: HashSet<&str> = self.records.iter().map(|r| r.path.as_str()).collect();
+            self.deleted = base
+                .files
+                .keys()
+                .filter(|path| !current.contains(path.as_str()))
+                .cloned()
+                .collect();
+            self.deleted.sort();
+        }
+    }
+}
+
+/// Opens the base archive named by `spec`, either a path or [`AUTO_BASE`].
+pub fn load_base(spec: &str, archive_dir: &Path, passphrase: Option<&str>) -> io::Result<Base> {
+    let path = if spec == AUTO_BASE {
+        newest_archive(archive_dir)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "No archive in {} to build an incremental backup on",
+                    archive_dir.display()
+                ),
+            )
+        })?
+    } else {
+        let path = expand_user_path(spec);
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        // Restoring an increment looks for its base next to it
+        if fs::canonicalize(parent)? != fs::canonicalize(archive_dir)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Base archive {} must be in the archive directory {}",
+                    path.display(),
+                    archive_dir.display()
+                ),
+            ));
+        }
+        path
+    };
+
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Base archive path has no file name")
+        })?;
+    let index = PbarArchive::open(&path, passphrase)?
+        .read_index()?
+        .filter(|index| !index.files.is_empty())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} has no file index, run a full backup first", path.display()),
+            )
+        })?;
+
+    let files = index
+        .files
+        .into_iter()
+        .map(|record| (record.path.clone(), record))
+        .collect();
+    Ok(Base { name, files })
+}
+
+/// Newest `.pbar` file in `dir`, going by the timestamp in the generated names.
+pub fn newest_archive(dir: &Path) -> io::Result<Option<PathBuf>> {
+    if !dir.exists() {
+        return Ok(None);
+    }
+    let mut archives: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file() && p.extension().is_some_and(|ext| ext == PBAR_EXT))
+        .collect();
+    archives.sort();
+    Ok(archives.pop())
+}
+
+/// Decides which files an incremental backup on top of `base` stores. Files
+/// whose size and mtime are unchanged are recorded from `base` without being
+/// read, and files whose contents turn out unchanged are recorded with the
+/// archive holding them. New and changed files only go into `stored`; their
+/// records are made when the payload is written, see [`FileIndex::complete`].
+/// Package lists and single files are always stored. Paths in `skip` are
+/// left out like excluded directories.
+pub fn plan_increment(
+    components: &[BackupComponentMeta],
+    base: &Base,
+    skip: &[PathBuf],
+    should_stop: &AtomicBool,
+) -> io::Result<FileIndex> {
+    scan(components, base, skip, false, should_stop)
+}
+
+/// Records every file of the directory components as they are now, reading
+/// only the files whose size or mtime differ from `base`.
+pub fn index_files(
+    components: &[BackupComponentMeta],
+    base: &Base,
+    skip: &[PathBuf],
+    should_stop: &AtomicBool,
+) -> io::Result<FileIndex> {
+    scan(components, base, skip, true, should_stop)
+}
+
+fn scan(
+    components: &[BackupComponentMeta],
+    base: &Base,
+    skip: &[PathBuf],
+    hash_new: bool,
+    should_stop: &AtomicBool,
+) -> io::Result<FileIndex> {
+    let mut index = FileIndex::default();
+
+    for meta in components {
+        let (root, exclude_paths) = match &meta.source {
+            ComponentSource::Directory {
+                root,
+                exclude_paths,
+            } => (root, exclude_paths),
+            _ => continue,
+        };
+        let mut exclude_paths = exclude_paths.clone();
+        exclude_paths.extend_from_slice(skip);
+
+        for entry in compression::walk_tree(root, &exclude_paths) {
+            compression::check_stop(should_stop)?;
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            let relative = match entry.path().strip_prefix(root) {
+                Ok(p) => p,
+                Err(_) => continue,
+            };
+            let path = Path::new(meta.category)
+                .join(relative)
+                .to_string_lossy()
+                .to_string();
+
+            // Like the archive writer, follow symlinks and skip files that vanished
+            let metadata = match fs::metadata(entry.path()) {
+                Ok(m) => m,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let size = metadata.len();
+            let mode = metadata.permissions().mode() & 0o7777;
+            let mtime = unix_time(metadata.modified().ok());
+
+            let previous = base.files.get(&path);
+            let sha256 = match previous {
+                Some(r) if r.size == size && r.mtime == mtime => r.sha256.clone(),
+                None if !hash_new => {
+                    index.stored.insert(path);
+                    continue;
+                }
+                _ => match hash_file(entry.path()) {
+                    Ok(digest) => digest,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e),
+                },
+            };
+
+            let stored_in = match previous {
+                Some(r) if r.sha256 == sha256 => {
+                    Some(r.stored_in.clone().unwrap_or_else(|| base.name.clone()))
+                }
+                _ => {
+                    index.stored.insert(path.clone());
+                    None
+                }
+            };
+            if stored_in.is_none() && !hash_new {
+                continue; // Recorded when the payload is written
+            }
+
+            index.records.push(FileRecord {
+                path,
+                size,
+                mode,
+                mtime,
+                sha256,
+                stored_in,
+            });
+        }
+    }
+    Ok(index)
+}
+
+/// Seconds since the epoch, 0 for missing or earlier times.
+fn unix_time(time: Option<SystemTime>) -> u64 {
+    time.and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Hex SHA-256 of the contents of `path`.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
@@ -1,6 +1,7 @@
 #![allow(deprecated)]
 
 use std::cell::RefCell;
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::thread;
 
@@ -11,8 +12,13 @@ use gtk4::glib;
 use gtk4::prelude::*;
 use libadwaita::prelude::*;
 
-use parch_backup::cli::{BackupArgs, RestoreArgs};
+use parch_backup::cli::{BackupArgs, RestoreArgs, DEFAULT_ARCHIVE_PATH};
+use parch_backup::config::profile::{
+    config_path, load_config, save_config, BackupItem, Config, Profile,
+};
 use parch_backup::events::{BackupPhase, ProgressEvent};
+use parch_backup::pbar::CipherKind;
+use parch_backup::utils::compression::CompressionCodec;
 
 fn main() {
     let app = libadwaita::Application::builder()
@@ -55,6 +61,85 @@ fn show_about_dialog(parent: &libadwaita::ApplicationWindow) {
     about.present();
 }
 
+/// Backup page rows that a profile fills in and is saved from.
+#[derive(Clone)]
+struct BackupForm {
+    apps: libadwaita::SwitchRow,
+    home: libadwaita::SwitchRow,
+    flatpak: libadwaita::SwitchRow,
+    keys: libadwaita::SwitchRow,
+    encrypt: libadwaita::SwitchRow,
+    chacha: libadwaita::SwitchRow,
+    zstd: libadwaita::SwitchRow,
+    exclude: libadwaita::EntryRow,
+    dest_row: libadwaita::ActionRow,
+    dest: Rc<RefCell<String>>,
+}
+
+impl BackupForm {
+    fn items(&self) -> [(&libadwaita::SwitchRow, BackupItem); 4] {
+        [
+            (&self.apps, BackupItem::Apps),
+            (&self.home, BackupItem::Home),
+            (&self.flatpak, BackupItem::Flatpak),
+            (&self.keys, BackupItem::Keys),
+        ]
+    }
+
+    fn fill(&self, profile: &Profile) {
+        for (switch, item) in self.items() {
+            switch.set_active(profile.components.contains(&item));
+        }
+        self.exclude.set_text(&profile.exclude.join(" "));
+        let dest = profile
+            .destination
+            .clone()
+            .unwrap_or_else(|| DEFAULT_ARCHIVE_PATH.to_string());
+        self.dest_row.set_subtitle(&dest);
+        *self.dest.borrow_mut() = dest;
+        self.zstd
+            .set_active(profile.compression == Some(CompressionCodec::Zstd));
+        self.chacha
+            .set_active(profile.cipher == Some(CipherKind::ChaCha20Poly1305));
+        self.encrypt.set_active(profile.encrypt_key_file.is_some());
+    }
+
+    /// `base` with the settings shown on the page. The options the page has
+    /// no rows for, like retention, are kept.
+    fn profile(&self, base: &Profile) -> Profile {
+        Profile {
+            components: self
+                .items()
+                .into_iter()
+                .filter(|(switch, _)| switch.is_active())
+                .map(|(_, item)| item)
+                .collect(),
+            exclude: self
+                .exclude
+                .text()
+                .split_whitespace()
+                .map(|s| s.to_string())
+                .collect(),
+            destination: Some(self.dest.borrow().clone()),
+            compression: Some(if self.zstd.is_active() {
+                CompressionCodec::Zstd
+            } else {
+                CompressionCodec::Gzip
+            }),
+            cipher: Some(if self.chacha.is_active() {
+                CipherKind::ChaCha20Poly1305
+            } else {
+                CipherKind::Aes256Gcm
+            }),
+            encrypt_key_file: base
+                .encrypt_key_file
+                .clone()
+                .filter(|_| self.encrypt.is_active()),
+            ..base.clone()
+        }
+    }
+}
+
 fn build_ui(app: &libadwaita::Application, initial_archive_path: Option<String>) {
     let window = libadwaita::ApplicationWindow::builder()
         .application(app)
@@ -104,6 +189,26 @@ fn build_ui(app: &libadwaita::Application, initial_archive_path: Option<String>)
     // ----------------------------------------------------
     let backup_page = libadwaita::PreferencesPage::new();
 
+    // Profile Group
+    let profile_group = libadwaita::PreferencesGroup::new();
+    profile_group.set_title("Profile");
+    profile_group.set_description(Some("Saved in ~/.config/parch-backup/config.toml"));
+
+    let profile_model = gtk4::StringList::new(&["None"]);
+    let profile_row = libadwaita::ComboRow::builder()
+        .title("Profile")
+        .model(&profile_model)
+        .build();
+
+    let save_profile_entry = libadwaita::EntryRow::builder()
+        .title("Save as Profile")
+        .show_apply_button(true)
+        .build();
+
+    profile_group.add(&profile_row);
+    profile_group.add(&save_profile_entry);
+    backup_page.add(&profile_group);
+
     // Backup Items Group
     let backup_group = libadwaita::PreferencesGroup::new();
     backup_group.set_title("Backup Items");
@@ -181,7 +286,7 @@ fn build_ui(app: &libadwaita::Application, initial_archive_path: Option<String>)
 
     let encrypt_switch = libadwaita::SwitchRow::builder()
         .title("Encrypt Archive")
-        .subtitle("Argon2id KDF + AES-256-GCM AEAD stream")
+        .subtitle("Argon2id KDF + chunked AEAD stream")
         .active(false)
         .build();
 
@@ -190,11 +295,26 @@ fn build_ui(app: &libadwaita::Application, initial_archive_path: Option<String>)
         .visible(false)
         .build();
 
+    let chacha_switch = libadwaita::SwitchRow::builder()
+        .title("ChaCha20-Poly1305 Cipher")
+        .subtitle("Faster on CPUs without AES-NI")
+        .active(false)
+        .visible(false)
+        .build();
+
     let password_entry_clone = password_entry.clone();
+    let chacha_switch_clone = chacha_switch.clone();
     encrypt_switch.connect_active_notify(move |sw| {
         password_entry_clone.set_visible(sw.is_active());
+        chacha_switch_clone.set_visible(sw.is_active());
     });
 
+    let zstd_switch = libadwaita::SwitchRow::builder()
+        .title("Zstd Compression")
+        .subtitle("Multi-threaded zstd instead of gzip")
+        .active(false)
+        .build();
+
     let exclude_entry = libadwaita::EntryRow::builder()
         .title("Exclude Directories (space separated)")
         .build();
@@ -202,9 +322,103 @@ fn build_ui(app: &libadwaita::Application, initial_archive_path: Option<String>)
     storage_group.add(&dest_row);
     storage_group.add(&encrypt_switch);
     storage_group.add(&password_entry);
+    storage_group.add(&chacha_switch);
+    storage_group.add(&zstd_switch);
     storage_group.add(&exclude_entry);
     backup_page.add(&storage_group);
 
+    let form = BackupForm {
+        apps: switch_apps.clone(),
+        home: switch_home.clone(),
+        flatpak: switch_flatpak.clone(),
+        keys: switch_keys.clone(),
+        encrypt: encrypt_switch.clone(),
+        chacha: chacha_switch.clone(),
+        zstd: zstd_switch.clone(),
+        exclude: exclude_entry.clone(),
+        dest_row: dest_row.clone(),
+        dest: Rc::clone(&selected_dest_path),
+    };
+
+    let config = Rc::new(RefCell::new(Config::default()));
+    match config_path().and_then(|path| load_config(&path)) {
+        Ok(loaded) => {
+            for name in loaded.profiles.keys() {
+                profile_model.append(name);
+            }
+            *config.borrow_mut() = loaded;
+        }
+        Err(e) => status_label.set_text(&format!("Cannot load profiles: {}", e)),
+    }
+
+    // The profile the backup starts from, empty for "None"
+    let selected_profile = Rc::new(RefCell::new(Profile::default()));
+    let form_for_profile = form.clone();
+    let config_for_profile = Rc::clone(&config);
+    let selected_profile_clone = Rc::clone(&selected_profile);
+    profile_row.connect_selected_notify(move |row| {
+        let profile = if row.selected() == 0 {
+            Profile::default()
+        } else {
+            let profile = row
+                .selected_item()
+                .and_downcast::<gtk4::StringObject>()
+                .and_then(|item| {
+                    config_for_profile
+                        .borrow()
+                        .profiles
+                        .get(item.string().as_str())
+                        .cloned()
+                });
+            match profile {
+                Some(profile) => {
+                    form_for_profile.fill(&profile);
+                    profile
+                }
+                None => return,
+            }
+        };
+        *selected_profile_clone.borrow_mut() = profile;
+    });
+
+    let form_for_save = form.clone();
+    let config_for_save = Rc::clone(&config);
+    let selected_profile_for_save = Rc::clone(&selected_profile);
+    let profile_row_for_save = profile_row.clone();
+    let status_label_for_save = status_label.clone();
+    save_profile_entry.connect_apply(move |entry| {
+        let name = entry.text().trim().to_string();
+        if name.is_empty() {
+            return;
+        }
+        // Re-read the file so that profiles edited meanwhile are not lost
+        let result = config_path().and_then(|path| {
+            let mut latest = load_config(&path)?;
+            let base = latest
+                .profiles
+                .get(&name)
+                .cloned()
+                .unwrap_or_else(|| selected_profile_for_save.borrow().clone());
+            latest
+                .profiles
+                .insert(name.clone(), form_for_save.profile(&base));
+            save_config(&path, &latest)?;
+            Ok(latest)
+        });
+        match result {
+            Ok(latest) => {
+                let names: Vec<&str> = latest.profiles.keys().map(String::as_str).collect();
+                let position = names.iter().position(|n| *n == name).unwrap_or(0) as u32 + 1;
+                *config_for_save.borrow_mut() = latest.clone();
+                profile_model.splice(1, profile_model.n_items() - 1, &names);
+                profile_row_for_save.set_selected(position);
+                entry.set_text("");
+                status_label_for_save.set_text(&format!("Saved profile {}", name));
+            }
+            Err(e) => status_label_for_save.set_text(&format!("Cannot save profile: {}", e)),
+        }
+    });
+
     // Backup Action Group
     let backup_action_group = libadwaita::PreferencesGroup::new();
     let backup_action_box = gtk4::Box::new(gtk4::Orientation::Vertical, 10);
@@ -379,10 +593,10 @@ fn build_ui(app: &libadwaita::Application, initial_archive_path: Option<String>)
                         BackupPhase::Scanning => "Scanning system...",
                         BackupPhase::Packages => "Backing up package list...",
                         BackupPhase::Flatpaks => "Backing up Flatpaks...",
-                        BackupPhase::Home => "Compressing home directory...",
+                        BackupPhase::Home => "Scanning home directory...",
                         BackupPhase::Keys => "Backing up security keys...",
                         BackupPhase::Compressing => "Building PBAR container...",
-                        BackupPhase::Encrypting => "Encrypting stream with AES-256-GCM...",
+                        BackupPhase::Encrypting => "Encrypting archive stream...",
                         BackupPhase::Restoring => "Restoring archive files...",
                         BackupPhase::Done => "Operation complete!",
                     };
@@ -415,24 +629,21 @@ fn build_ui(app: &libadwaita::Application, initial_archive_path: Option<String>)
         glib::ControlFlow::Continue
     });
 
-    let selected_dest_for_backup = Rc::clone(&selected_dest_path);
+    let status_label_for_backup = status_label.clone();
     let start_backup_btn_for_click = start_backup_btn.clone();
     let start_restore_btn_for_backup = start_restore_btn.clone();
     let tx_backup = tx.clone();
 
     start_backup_btn.connect_clicked(move |_| {
-        let dest = selected_dest_for_backup.borrow().clone();
-        let apps = switch_apps.is_active();
-        let home = switch_home.is_active();
-        let flatpak = switch_flatpak.is_active();
-        let keys = switch_keys.is_active();
-        let encrypt = encrypt_switch.is_active();
+        // The rows show the selected profile, so they replace its settings
+        let profile = form.profile(&selected_profile.borrow());
         let password = password_entry.text().to_string();
-        let excludes: Vec<String> = exclude_entry
-            .text()
-            .split_whitespace()
-            .map(|s| s.to_string())
-            .collect();
+        let encrypt_key = Some(password).filter(|p| encrypt_switch.is_active() && !p.is_empty());
+        let has_key = encrypt_key.is_some() || profile.encrypt_key_file.is_some();
+        if encrypt_switch.is_active() && !has_key {
+            status_label_for_backup.set_text("Please enter an encryption password first!");
+            return;
+        }
 
         start_backup_btn_for_click.set_sensitive(false);
         start_restore_btn_for_backup.set_sensitive(false);
@@ -441,16 +652,19 @@ fn build_ui(app: &libadwaita::Application, initial_archive_path: Option<String>)
 
         thread::spawn(move || {
             let args = BackupArgs {
-                archive_path: Some(dest),
-                apps,
-                home,
-                exclude_dir: excludes,
-                flatpak,
-                keys,
-                encrypt,
-                encrypt_key: if encrypt { Some(password) } else { None },
+                encrypt: encrypt_key.is_some(),
+                encrypt_key,
+                ..Default::default()
             };
-            parch_backup::backup::backup::handle_backup_with_tx(&args, Some(&tx));
+            match profile.apply(&args) {
+                // Errors reach the window as ProgressEvent::Error
+                Ok(args) => {
+                    let _ = parch_backup::backup::backup::handle_backup_with_tx(&args, Some(&tx));
+                }
+                Err(e) => {
+                    let _ = tx.send(ProgressEvent::Error(e.to_string()));
+                }
+            }
         });
     });
 
@@ -476,9 +690,10 @@ fn build_ui(app: &libadwaita::Application, initial_archive_path: Option<String>)
 
         thread::spawn(move || {
             let args = RestoreArgs {
-                archive_path,
+                archive_path: Some(PathBuf::from(archive_path)),
                 decrypt,
                 decrypt_key: if decrypt { Some(password) } else { None },
+                ..Default::default()
             };
             if let Err(e) = parch_backup::restore::restore::handle_restore_with_tx(&args, Some(&tx)) {
                 let _ = tx.send(ProgressEvent::Error(e.to_string()));
@@ -1,4 +1,11 @@
 use clap::{Args, Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::pbar::component::Component;
+use crate::pbar::{CipherKind, KdfParams};
+use crate::restore::conflict::ConflictPolicy;
+use crate::utils::compression::CompressionCodec;
 
 #[derive(Parser)]
 #[command(
@@ -21,13 +28,37 @@ pub enum Commands {
     Backup(BackupArgs),
     /// Restore functionality
     Restore(RestoreArgs),
-    // Schedule(ScheduleArgs),
+    /// Verify archive signature and contents
+    Verify(VerifyArgs),
+    /// Show archive header and manifest without extracting
+    Inspect(InspectArgs),
+    /// List files stored in an archive
+    List(ListArgs),
+    /// Show what changed between two archives, or since an archive
+    Diff(DiffArgs),
+    /// Generate an Ed25519 archive signing key
+    Keygen(KeygenArgs),
+    /// Manage a deduplicating snapshot repository
+    Repo(RepoArgs),
+    /// Delete archives that fall outside a retention policy
+    Prune(PruneArgs),
+    /// Run backups periodically with systemd user timers
+    Schedule(ScheduleArgs),
 }
 
-#[derive(Args)]
+/// Where `backup` writes its archives unless told otherwise.
+pub const DEFAULT_ARCHIVE_PATH: &str = "~/Backups";
+
+#[derive(Args, Default, Clone)]
 pub struct BackupArgs {
+    /// Named profile from the config file
+    #[arg(
+        long,
+        help = "Start from this profile of ~/.config/parch-backup/config.toml, options given here add to or override it"
+    )]
+    pub profile: Option<String>,
     /// Backup archive location
-    #[arg(long, help = "Backup archive location", default_value = "~/Backups")]
+    #[arg(long, help = "Backup archive location (default ~/Backups)")]
     pub archive_path: Option<String>,
     /// Backup installed apps names
     #[arg(long, help = "Backup installed apps names")]
@@ -54,13 +85,82 @@ pub struct BackupArgs {
     /// Encryption key
     #[arg(long, help = "Encryption key", requires = "encrypt")]
     pub encrypt_key: Option<String>,
+    /// Payload compression codec
+    #[arg(long, value_enum, help = "Payload compression codec (default gzip)")]
+    pub compression: Option<CompressionCodec>,
+    /// Payload cipher
+    #[arg(long, value_enum, help = "Payload cipher used with --encrypt (default aes256-gcm)")]
+    pub cipher: Option<CipherKind>,
+    /// Ed25519 signing key file
+    #[arg(long, help = "Sign the archive with this Ed25519 key file")]
+    pub sign_key: Option<String>,
+    /// Argon2id memory cost
+    #[arg(long, help = "Argon2id memory cost in KiB (default 19456)", requires = "encrypt")]
+    pub kdf_memory: Option<u32>,
+    /// Argon2id iterations
+    #[arg(long, help = "Argon2id iterations (default 2)", requires = "encrypt")]
+    pub kdf_iterations: Option<u32>,
+    /// Argon2id parallelism
+    #[arg(long, help = "Argon2id parallelism (default 1)", requires = "encrypt")]
+    pub kdf_parallelism: Option<u32>,
+    /// Base archive for an incremental backup
+    #[arg(
+        long,
+        help = "Only store files changed since this archive in the archive directory, or 'auto' for the newest one"
+    )]
+    pub incremental_from: Option<String>,
+    /// Deduplicating repository
+    #[arg(
+        long,
+        help = "Store the backup as a snapshot in this deduplicating repository, created on first use",
+        conflicts_with = "incremental_from"
+    )]
+    pub repo: Option<String>,
+    /// Prune after a successful backup
+    #[command(flatten)]
+    pub retention: RetentionArgs,
 }
 
-#[derive(Args)]
+/// Which archives `prune` keeps, by the time in their file names.
+#[derive(Args, Default, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionArgs {
+    /// Newest archives to keep
+    #[arg(long, help = "Prune archives, keeping the N newest", default_value_t = 0)]
+    pub keep_last: usize,
+    /// Days to keep one archive for
+    #[arg(long, help = "Prune archives, keeping the newest of each of the last N days", default_value_t = 0)]
+    pub keep_daily: usize,
+    /// Weeks to keep one archive for
+    #[arg(long, help = "Prune archives, keeping the newest of each of the last N weeks", default_value_t = 0)]
+    pub keep_weekly: usize,
+    /// Months to keep one archive for
+    #[arg(long, help = "Prune archives, keeping the newest of each of the last N months", default_value_t = 0)]
+    pub keep_monthly: usize,
+}
+
+impl RetentionArgs {
+    /// Whether any `--keep-*` option was given.
+    pub fn is_set(&self) -> bool {
+        self.keep_last + self.keep_daily + self.keep_weekly + self.keep_monthly > 0
+    }
+}
+
+#[derive(Args, Default)]
 pub struct RestoreArgs {
     /// Archive path
-    #[arg(help = "Archive path")]
-    pub archive_path: String,
+    #[arg(help = "Archive path", required_unless_present = "repo")]
+    pub archive_path: Option<PathBuf>,
+    /// Repository to restore from
+    #[arg(
+        long,
+        help = "Restore a snapshot from this repository instead of an archive",
+        conflicts_with = "archive_path"
+    )]
+    pub repo: Option<String>,
+    /// Snapshot name
+    #[arg(long, help = "Snapshot to restore from --repo, the newest by default", requires = "repo")]
+    pub snapshot: Option<String>,
     /// Use Decryption
     #[arg(
         long,
@@ -71,44 +171,277 @@ pub struct RestoreArgs {
     /// Decryption key
     #[arg(long, help = "Decryption key", requires = "decrypt")]
     pub decrypt_key: Option<String>,
+    /// Trusted signing public keys
+    #[arg(
+        long,
+        help = "Only accept archives signed by this public key (hex or file), repeatable"
+    )]
+    pub trusted_key: Vec<String>,
+    /// Continue on signature failures
+    #[arg(long, help = "Warn instead of refusing on missing or bad signatures")]
+    pub ignore_signature: bool,
+    /// Path globs to restore
+    #[arg(
+        long,
+        help = "Only restore paths matching this glob, relative to home (e.g. '~/.config/nvim'), repeatable"
+    )]
+    pub include: Vec<String>,
+    /// Path globs to skip
+    #[arg(long, help = "Skip paths matching this glob, repeatable")]
+    pub exclude: Vec<String>,
+    /// Components to restore
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        help = "Only restore these components (e.g. home,ssh)"
+    )]
+    pub only: Vec<Component>,
+    /// Alternate restore root
+    #[arg(
+        long,
+        help = "Restore under this directory instead of $HOME, package reinstall is skipped unless --install-packages"
+    )]
+    pub target: Option<String>,
+    /// Reinstall packages for --target restores
+    #[arg(long, help = "Reinstall packages and Flatpaks even with --target", requires = "target")]
+    pub install_packages: bool,
+    /// Install the archived package versions
+    #[arg(
+        long,
+        help = "Install the package versions of the archive where the pacman cache still has them"
+    )]
+    pub pin_versions: bool,
+    /// Conflict policy
+    #[arg(
+        long,
+        value_enum,
+        help = "What to do with files that already exist",
+        default_value_t = ConflictPolicy::Overwrite
+    )]
+    pub conflict: ConflictPolicy,
+    /// Remove files deleted since the base
+    #[arg(
+        long,
+        help = "Remove the files an incremental backup records as deleted since its base, following --conflict"
+    )]
+    pub delete: bool,
+    /// Report what would change
+    #[arg(long, help = "Print what would be restored and installed without writing anything")]
+    pub dry_run: bool,
+}
+
+#[derive(Args, Default)]
+pub struct VerifyArgs {
+    /// Archive path
+    #[arg(help = "Archive path")]
+    pub archive_path: String,
+    /// Trusted signing public keys
+    #[arg(
+        long,
+        help = "Only accept archives signed by this public key (hex or file), repeatable"
+    )]
+    pub trusted_key: Vec<String>,
+    /// Decryption key
+    #[arg(long, help = "Decryption key, needed to check the contents of encrypted archives")]
+    pub decrypt_key: Option<String>,
+    /// Skip the content check
+    #[arg(long, help = "Only check the signature, without reading the payload")]
+    pub signature_only: bool,
 }
 
-// #[derive(Args)]
-// pub struct ScheduleArgs {
-//     /// Cron expression for scheduling
-//     /// sec  min   hour   day of month   month   day of week   year
-//     #[arg(
-//         short,
-//         long,
-//         help = "Cron expression for scheduling backup functionality: \nExample: 'sec  min   hour   day of month   month   day of week   year'\n"
-//     )]
-//     pub cron: String,
-//     /// Backup installed apps names
-//     #[arg(long, help = "Backup installed apps names")]
-//     pub apps: bool,
-//     /// Backup home directory
-//     #[arg(long, help = "Backup home directory")]
-//     pub home: bool,
-//     /// Execluded directories from backup
-//     #[arg(long, help = "Excluded directories from backup", num_args(1..))]
-//     pub exclude_dir: Vec<String>,
-//     /// Backup flatpak applications
-//     #[arg(long, help = "Backup flatpak applications")]
-//     pub flatpak: bool,
-//     /// Backup keys
-//     #[arg(long, help = "Backup keys")]
-//     pub keys: bool,
-//     /// Use Encryption
-//     #[arg(
-//         long,
-//         help = "Use Encryption, pass the password",
-//         requires = "encrypt_key"
-//     )]
-//     pub encrypt: bool,
-//     /// Encryption key
-//     #[arg(long, help = "Encryption key", requires = "encrypt")]
-//     pub encrypt_key: Option<String>,
-// }
+#[derive(Args)]
+pub struct InspectArgs {
+    /// Archive path
+    #[arg(help = "Archive path")]
+    pub archive_path: String,
+    /// Decryption key
+    #[arg(long, help = "Decryption key, needed to show the manifest of encrypted archives")]
+    pub decrypt_key: Option<String>,
+    /// JSON output
+    #[arg(long, help = "Print the report as JSON")]
+    pub json: bool,
+}
+
+#[derive(Args)]
+pub struct ListArgs {
+    /// Archive path
+    #[arg(help = "Archive path")]
+    pub archive_path: String,
+    /// Path globs to list
+    #[arg(help = "Only list paths matching these globs, relative to home (e.g. '.config/nvim')")]
+    pub patterns: Vec<String>,
+    /// Path globs to skip
+    #[arg(long, help = "Skip paths matching this glob, repeatable")]
+    pub exclude: Vec<String>,
+    /// Decryption key
+    #[arg(long, help = "Decryption key for encrypted archives")]
+    pub decrypt_key: Option<String>,
+    /// Read the archive file index instead of the payload
+    #[arg(
+        long,
+        help = "List the files of the archive index without reading the payload, directories and links are left out"
+    )]
+    pub index: bool,
+}
+
+#[derive(Args, Default)]
+pub struct DiffArgs {
+    /// Archive path
+    #[arg(help = "Archive to compare")]
+    pub old: String,
+    /// Second archive path
+    #[arg(help = "Later archive to compare with, the live system when left out")]
+    pub new: Option<String>,
+    /// Path globs to compare
+    #[arg(
+        long = "path",
+        help = "Only compare paths matching this glob, relative to home, repeatable"
+    )]
+    pub patterns: Vec<String>,
+    /// Path globs to skip
+    #[arg(long, help = "Skip paths matching this glob, repeatable")]
+    pub exclude: Vec<String>,
+    /// Directories left out of the live scan
+    #[arg(
+        long,
+        help = "Directories under home left out of the live scan, like backup --exclude-dir",
+        num_args(1..)
+    )]
+    pub exclude_dir: Vec<String>,
+    /// Decryption key
+    #[arg(long, help = "Decryption key for encrypted archives")]
+    pub decrypt_key: Option<String>,
+}
+
+#[derive(Args)]
+pub struct KeygenArgs {
+    /// Output path for the secret key
+    #[arg(help = "Secret key output path, the public key is written to <PATH>.pub")]
+    pub output: String,
+}
+
+#[derive(Args)]
+pub struct PruneArgs {
+    /// Archive directory
+    #[arg(long, help = "Archive directory to prune", default_value = DEFAULT_ARCHIVE_PATH)]
+    pub archive_path: String,
+    /// Repository whose snapshots to prune
+    #[arg(
+        long,
+        help = "Prune the snapshots of this repository instead, run `repo gc` afterwards",
+        conflicts_with = "archive_path"
+    )]
+    pub repo: Option<String>,
+    #[command(flatten)]
+    pub retention: RetentionArgs,
+    /// Report what would be deleted
+    #[arg(long, help = "Print which archives would be kept and deleted without deleting any")]
+    pub dry_run: bool,
+    /// Decryption key
+    #[arg(
+        long,
+        help = "Key of encrypted incremental archives, needed to find the archives they build on"
+    )]
+    pub decrypt_key: Option<String>,
+}
+
+#[derive(Args)]
+pub struct RepoArgs {
+    #[command(subcommand)]
+    pub command: RepoCommand,
+}
+
+#[derive(Subcommand)]
+pub enum RepoCommand {
+    /// List the snapshots of a repository
+    Snapshots {
+        #[arg(help = "Repository directory")]
+        repo: String,
+    },
+    /// Delete a snapshot, its chunks are freed by the next gc
+    Forget {
+        #[arg(help = "Repository directory")]
+        repo: String,
+        #[arg(help = "Snapshot file name")]
+        snapshot: String,
+    },
+    /// Delete chunks that no snapshot refers to
+    Gc {
+        #[arg(help = "Repository directory")]
+        repo: String,
+        /// Decryption key
+        #[arg(long, help = "Key of an encrypted repository")]
+        decrypt_key: Option<String>,
+    },
+}
+
+#[derive(Args)]
+pub struct ScheduleArgs {
+    #[command(subcommand)]
+    pub command: ScheduleCommand,
+}
+
+#[derive(Subcommand)]
+pub enum ScheduleCommand {
+    /// Write and enable a timer that runs `backup` with the given options
+    Add {
+        #[arg(help = "Schedule name, used in the unit names", default_value = "default")]
+        name: String,
+        /// systemd calendar expression
+        #[arg(
+            long,
+            help = "When to run, as a systemd OnCalendar expression (e.g. 'daily', 'Mon *-*-* 03:00')",
+            default_value = "daily"
+        )]
+        on_calendar: String,
+        /// Randomized delay
+        #[arg(
+            long,
+            help = "Spread the start over this time span (e.g. '30min', '0' to disable)",
+            default_value = "1h"
+        )]
+        randomized_delay: String,
+        /// Only write the units
+        #[arg(long, help = "Write the unit files without enabling the timer")]
+        no_enable: bool,
+        /// Options passed to `backup`
+        #[arg(
+            last = true,
+            help = "Backup options, after `--` (e.g. -- --home --keys --keep-daily 7)"
+        )]
+        backup_args: Vec<String>,
+    },
+    /// List the installed schedules
+    List,
+    /// Disable a timer and delete its units
+    Remove {
+        #[arg(help = "Schedule name", default_value = "default")]
+        name: String,
+    },
+}
+
+impl BackupArgs {
+    /// Argon2id parameters requested on the command line, defaults filled in.
+    pub fn kdf_params(&self) -> KdfParams {
+        let defaults = KdfParams::default();
+        KdfParams {
+            memory_kib: self.kdf_memory.unwrap_or(defaults.memory_kib),
+            iterations: self.kdf_iterations.unwrap_or(defaults.iterations),
+            parallelism: self.kdf_parallelism.unwrap_or(defaults.parallelism),
+        }
+    }
+
+    /// Payload codec requested on the command line, or the default.
+    pub fn compression_codec(&self) -> CompressionCodec {
+        self.compression.unwrap_or_default()
+    }
+
+    /// Payload cipher requested on the command line, or the default.
+    pub fn cipher_kind(&self) -> CipherKind {
+        self.cipher.unwrap_or_default()
+    }
+}
 
 pub fn parse_cli() -> Cli {
     Cli::parse()
@@ -0,0 +1,201 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::backup::consolidate::expand_user_path;
+use crate::cli::{BackupArgs, RetentionArgs};
+use crate::pbar::CipherKind;
+use crate::utils::compression::CompressionCodec;
+
+/// Name of the config file inside `$XDG_CONFIG_HOME/parch-backup`.
+pub const CONFIG_FILE: &str = "config.toml";
+
+/// What a profile backs up, named like the `backup` flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupItem {
+    Apps,
+    Home,
+    Flatpak,
+    Keys,
+}
+
+/// Saved `backup` options, a `[profiles.<name>]` table of the config file.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub components: Vec<BackupItem>,
+    /// Directories left out of the home backup, like `--exclude-dir`.
+    pub exclude: Vec<String>,
+    /// Archive directory or file, like `--archive-path`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<CompressionCodec>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cipher: Option<CipherKind>,
+    /// File holding the passphrase, which turns on encryption.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encrypt_key_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sign_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub incremental_from: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repo: Option<String>,
+    pub retention: RetentionArgs,
+}
+
+/// Contents of `config.toml`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// `$XDG_CONFIG_HOME`, or `~/.config` when it is unset.
+pub fn config_home() -> io::Result<PathBuf> {
+    match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => std::env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".config"))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "HOME is not set")),
+    }
+}
+
+/// `~/.config/parch-backup/config.toml`, shared by the CLI and the GUI.
+pub fn config_path() -> io::Result<PathBuf> {
+    Ok(config_home()?.join("parch-backup").join(CONFIG_FILE))
+}
+
+/// Reads the config at `path`. A missing file is an empty config.
+pub fn load_config(path: &Path) -> io::Result<Config> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => return Err(e),
+    };
+    toml::from_str(&text).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid config {}: {}", path.display(), e),
+        )
+    })
+}
+
+/// Writes `config` to `path`, replacing the file atomically.
+pub fn save_config(path: &Path, config: &Config) -> io::Result<()> {
+    let text = toml::to_string_pretty(config)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("toml.tmp");
+    fs::write(&tmp, text)?;
+    fs::rename(&tmp, path)
+}
+
+impl Config {
+    pub fn profile(&self, name: &str) -> io::Result<&Profile> {
+        self.profiles.get(name).ok_or_else(|| {
+            let known: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "No profile named '{}' (known profiles: {})",
+                    name,
+                    if known.is_empty() {
+                        "none".to_string()
+                    } else {
+                        known.join(", ")
+                    }
+                ),
+            )
+        })
+    }
+}
+
+/// Reads a passphrase file, without its trailing newline.
+fn read_key_file(path: &str) -> io::Result<String> {
+    let path = expand_user_path(path);
+    let key = fs::read_to_string(&path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Cannot read key file {}: {}", path.display(), e),
+        )
+    })?;
+    let key = key.trim_end_matches(['\n', '\r']);
+    if key.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Key file {} is empty", path.display()),
+        ));
+    }
+    Ok(key.to_string())
+}
+
+impl Profile {
+    /// Applies the profile under the options of `args`. Component flags and
+    /// excludes add to the profile; other options replace its values when
+    /// they are given.
+    pub fn apply(&self, args: &BackupArgs) -> io::Result<BackupArgs> {
+        let mut merged = args.clone();
+        merged.profile = None;
+
+        for item in &self.components {
+            match item {
+                BackupItem::Apps => merged.apps = true,
+                BackupItem::Home => merged.home = true,
+                BackupItem::Flatpak => merged.flatpak = true,
+                BackupItem::Keys => merged.keys = true,
+            }
+        }
+        merged.exclude_dir = self
+            .exclude
+            .iter()
+            .chain(&args.exclude_dir)
+            .cloned()
+            .collect();
+
+        merged.archive_path = args
+            .archive_path
+            .clone()
+            .or_else(|| self.destination.clone());
+        merged.compression = args.compression.or(self.compression);
+        merged.cipher = args.cipher.or(self.cipher);
+        if !args.encrypt {
+            if let Some(file) = &self.encrypt_key_file {
+                merged.encrypt = true;
+                merged.encrypt_key = Some(read_key_file(file)?);
+            }
+        }
+        merged.sign_key = args.sign_key.clone().or_else(|| self.sign_key.clone());
+        merged.incremental_from = args
+            .incremental_from
+            .clone()
+            .or_else(|| self.incremental_from.clone());
+        merged.repo = args.repo.clone().or_else(|| self.repo.clone());
+        if !args.retention.is_set() {
+            merged.retention = self.retention.clone();
+        }
+
+        if merged.repo.is_some() && merged.incremental_from.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A backup cannot use both a repository and --incremental-from",
+            ));
+        }
+        Ok(merged)
+    }
+}
+
+/// Resolves `args.profile` against the default config file. Returns `args`
+/// unchanged when no profile is named.
+pub fn resolve_backup_args(args: &BackupArgs) -> io::Result<BackupArgs> {
+    match args.profile.as_deref() {
+        Some(name) => load_config(&config_path()?)?.profile(name)?.apply(args),
+        None => Ok(args.clone()),
+    }
+}
@@ -0,0 +1,383 @@
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+
+use crate::backup::consolidate::{expand_user_path, BackupComponentMeta, ComponentSource};
+use crate::backup::incremental::{self, Base};
+use crate::cli::DiffArgs;
+use crate::flatpak::flatpak;
+use crate::list::list::{walk_archive, ArchiveEntry};
+use crate::pbar::component::Component;
+use crate::pbar::manifest::FileRecord;
+use crate::pbar::PbarArchive;
+use crate::pm::packages::{self, Package};
+use crate::pm::paru;
+use crate::utils::filter::PathFilter;
+
+/// What a backup captured, or what one would capture now.
+pub struct Snapshot {
+    /// Archive file name, or "the live system".
+    pub label: String,
+    pub components: HashSet<Component>,
+    /// Files of the home and key components by payload path.
+    pub files: BTreeMap<String, FileRecord>,
+    /// Explicitly installed packages and their versions, empty for lists
+    /// of names.
+    pub packages: BTreeMap<String, String>,
+    pub flatpaks: BTreeSet<String>,
+    /// Directories under home the backup left out, or the live scan skips.
+    pub exclude_dir: Vec<String>,
+}
+
+/// Changes of one component, as home relative paths or package names.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ComponentDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Files whose contents or permissions changed, or packages whose
+    /// version changed.
+    pub modified: Vec<String>,
+}
+
+impl ComponentDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+pub struct SnapshotDiff {
+    pub changes: Vec<(Component, ComponentDiff)>,
+    /// Components only one side captured, which cannot be compared.
+    pub skipped: Vec<Component>,
+}
+
+/// Components the manifest of `archive` says it captured.
+fn captured_components(archive: &PbarArchive) -> HashSet<Component> {
+    let contents = &archive.manifest.archive_contents;
+    [
+        (Component::Apps, contents.apps.included),
+        (Component::Flatpak, contents.flatpak.included),
+        (Component::Home, contents.home_dotfiles.included),
+        (Component::Gpg, contents.keys.gpg_keys),
+        (Component::Ssh, contents.keys.ssh_keys),
+    ]
+    .into_iter()
+    .filter_map(|(component, included)| included.then_some(component))
+    .collect()
+}
+
+fn read_names(data: &mut dyn Read) -> io::Result<BTreeSet<String>> {
+    let mut text = String::new();
+    data.read_to_string(&mut text)?;
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Reads the file index stored after the payload and the package lists from
+/// the payload. Archives written before the index existed are hashed instead.
+pub fn read_archive(path: &Path, passphrase: Option<&str>) -> io::Result<Snapshot> {
+    let mut archive = PbarArchive::open(path, passphrase)?;
+    let index = archive.read_index()?.unwrap_or_default();
+    let mut snapshot = Snapshot {
+        label: path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| path.display().to_string()),
+        components: captured_components(&archive),
+        files: BTreeMap::new(),
+        packages: BTreeMap::new(),
+        flatpaks: BTreeSet::new(),
+        exclude_dir: archive
+            .manifest
+            .archive_contents
+            .home_dotfiles
+            .exclude_dir
+            .clone(),
+    };
+    for record in &index.files {
+        let is_file = ArchiveEntry::from_record(record)
+            .is_some_and(|entry| entry.component.home_prefix().is_some());
+        if is_file {
+            snapshot.files.insert(record.path.clone(), record.clone());
+        }
+    }
+
+    let hash_files = index.files.is_empty();
+    let has_lists = snapshot.components.contains(&Component::Apps)
+        || snapshot.components.contains(&Component::Flatpak);
+    if !hash_files && !has_lists {
+        return Ok(snapshot);
+    }
+    walk_archive(archive, |entry, data| {
+        match entry.component {
+            Component::Apps
+                if entry.path == Path::new(paru::APPS_LIST_FILE)
+                    || entry.path == Path::new(packages::PACKAGES_FILE) =>
+            {
+                let mut content = String::new();
+                data.read_to_string(&mut content)?;
+                let list = packages::read_package_list(&entry.path, &content)?;
+                snapshot.packages = package_versions(list);
+            }
+            Component::Flatpak if entry.path == Path::new(flatpak::FLATPAK_APPS_LIST_FILE) => {
+                snapshot.flatpaks = read_names(data)?;
+            }
+            Component::Apps | Component::Flatpak => {}
+            _ if hash_files && entry.entry_type.is_file() => {
+                let mut hasher = Sha256::new();
+                let size = io::copy(data, &mut hasher)?;
+                let path = entry.payload_path.to_string_lossy().to_string();
+                let record = FileRecord {
+                    path: path.clone(),
+                    size,
+                    mode: entry.mode & 0o7777,
+                    mtime: entry.mtime,
+                    sha256: hex::encode(hasher.finalize()),
+                    stored_in: None,
+                };
+                snapshot.files.insert(path, record);
+            }
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(snapshot)
+}
+
+/// Scans the live system for the components `base` captured, the way a
+/// backup would, leaving out the directories `base` excluded and
+/// `exclude_dir`. Files whose size and mtime match `base` are not read again.
+/// Package lists that cannot be read are left out of the comparison.
+pub fn read_live(
+    home: &Path,
+    base: &Snapshot,
+    exclude_dir: &[String],
+    skip: &Path,
+) -> io::Result<Snapshot> {
+    let mut snapshot = Snapshot {
+        label: "the live system".to_string(),
+        components: base.components.clone(),
+        files: BTreeMap::new(),
+        packages: BTreeMap::new(),
+        flatpaks: BTreeSet::new(),
+        exclude_dir: base.exclude_dir.iter().chain(exclude_dir).cloned().collect(),
+    };
+    if base.components.contains(&Component::Apps) {
+        match packages::list_packages() {
+            Ok(list) => snapshot.packages = package_versions(list),
+            Err(e) => {
+                eprintln!("Failed to list installed apps: {}", e);
+                snapshot.components.remove(&Component::Apps);
+            }
+        }
+    }
+    if base.components.contains(&Component::Flatpak) {
+        match flatpak::installed_flatpak_app_ids() {
+            Ok(apps) => snapshot.flatpaks = apps.into_iter().collect(),
+            Err(e) => {
+                eprintln!("Failed to list Flatpak apps: {}", e);
+                snapshot.components.remove(&Component::Flatpak);
+            }
+        }
+    }
+
+    let mut components = Vec::new();
+    for component in Component::value_variants() {
+        let prefix = match component.home_prefix() {
+            Some(prefix) if base.components.contains(component) => prefix,
+            _ => continue,
+        };
+        let exclude_paths = if *component == Component::Home {
+            snapshot.exclude_dir.iter().map(|d| home.join(d)).collect()
+        } else {
+            Vec::new()
+        };
+        components.push(BackupComponentMeta {
+            category: component.dir(),
+            source: ComponentSource::Directory {
+                root: home.join(prefix),
+                exclude_paths,
+            },
+            count: 0,
+            size_bytes: 0,
+            extra_info: None,
+        });
+    }
+    let known = Base {
+        name: base.label.clone(),
+        files: base
+            .files
+            .iter()
+            .map(|(path, record)| (path.clone(), record.clone()))
+            .collect(),
+    };
+    let index = incremental::index_files(
+        &components,
+        &known,
+        &[skip.to_path_buf()],
+        &AtomicBool::new(false),
+    )?;
+    for mut record in index.records {
+        record.stored_in = None;
+        snapshot.files.insert(record.path.clone(), record);
+    }
+    Ok(snapshot)
+}
+
+fn package_versions(list: Vec<Package>) -> BTreeMap<String, String> {
+    packages::explicit_packages(list)
+        .map(|p| (p.name, p.version))
+        .collect()
+}
+
+/// Packages added and removed, and those whose version changed, as
+/// `name old -> new`.
+fn diff_packages(old: &BTreeMap<String, String>, new: &BTreeMap<String, String>) -> ComponentDiff {
+    let mut diff = ComponentDiff::default();
+    for (name, version) in new {
+        match old.get(name) {
+            None => diff.added.push(name.clone()),
+            Some(previous)
+                if !previous.is_empty() && !version.is_empty() && previous != version =>
+            {
+                diff.modified
+                    .push(format!("{} {} -> {}", name, previous, version));
+            }
+            Some(_) => {}
+        }
+    }
+    diff.removed = old
+        .keys()
+        .filter(|name| !new.contains_key(*name))
+        .cloned()
+        .collect();
+    diff
+}
+
+fn diff_names(old: &BTreeSet<String>, new: &BTreeSet<String>) -> ComponentDiff {
+    ComponentDiff {
+        added: new.difference(old).cloned().collect(),
+        removed: old.difference(new).cloned().collect(),
+        modified: Vec::new(),
+    }
+}
+
+/// Home relative path of a payload path, e.g. `.ssh/config` for `sshb/config`.
+fn display_path(record: &FileRecord) -> Option<(Component, PathBuf)> {
+    ArchiveEntry::from_record(record).map(|entry| (entry.component, entry.path))
+}
+
+/// Compares the components both snapshots captured. Only paths `filter`
+/// matches are compared.
+pub fn diff_snapshots(old: &Snapshot, new: &Snapshot, filter: &PathFilter) -> SnapshotDiff {
+    let mut diff = SnapshotDiff {
+        changes: Vec::new(),
+        skipped: Vec::new(),
+    };
+    let mut files: HashMap<Component, ComponentDiff> = HashMap::new();
+    let paths: BTreeSet<&String> = old.files.keys().chain(new.files.keys()).collect();
+    for path in paths {
+        let (old_record, new_record) = (old.files.get(path), new.files.get(path));
+        let (component, shown) = match old_record.or(new_record).and_then(display_path) {
+            Some(found) => found,
+            None => continue,
+        };
+        if !filter.matches(&shown) {
+            continue;
+        }
+        let changes = files.entry(component).or_default();
+        let shown = shown.to_string_lossy().to_string();
+        match (old_record, new_record) {
+            (None, Some(_)) => changes.added.push(shown),
+            (Some(_), None) => changes.removed.push(shown),
+            (Some(a), Some(b)) => {
+                // Archives written before the index recorded modes have 0
+                let mode_changed = a.mode != 0 && b.mode != 0 && a.mode != b.mode;
+                if a.sha256 != b.sha256 || mode_changed {
+                    changes.modified.push(shown);
+                }
+            }
+            (None, None) => {}
+        }
+    }
+
+    for component in Component::value_variants() {
+        match (
+            old.components.contains(component),
+            new.components.contains(component),
+        ) {
+            (true, true) => {}
+            (false, false) => continue,
+            _ => {
+                diff.skipped.push(*component);
+                continue;
+            }
+        }
+        let changes = match component {
+            Component::Apps => diff_packages(&old.packages, &new.packages),
+            Component::Flatpak => diff_names(&old.flatpaks, &new.flatpaks),
+            _ => files.remove(component).unwrap_or_default(),
+        };
+        diff.changes.push((*component, changes));
+    }
+    diff
+}
+
+pub fn handle_diff(args: &DiffArgs) -> io::Result<()> {
+    let filter = PathFilter::new(&args.patterns, &args.exclude)?;
+    let old_path = expand_user_path(&args.old);
+    let old = read_archive(&old_path, args.decrypt_key.as_deref())?;
+    let new = match &args.new {
+        Some(path) => read_archive(&expand_user_path(path), args.decrypt_key.as_deref())?,
+        None => {
+            let home = std::env::var("HOME")
+                .map(PathBuf::from)
+                .map_err(|_| io::Error::new(io::ErrorKind::NotFound, "HOME is not set"))?;
+            println!("Scanning the live system...");
+            read_live(&home, &old, &args.exclude_dir, &old_path)?
+        }
+    };
+
+    println!("Changes from {} to {}:", old.label, new.label);
+    let diff = diff_snapshots(&old, &new, &filter);
+    let mut total = 0;
+    for (component, changes) in &diff.changes {
+        if changes.is_empty() {
+            continue;
+        }
+        total += changes.added.len() + changes.removed.len() + changes.modified.len();
+        println!(
+            "{}: {} added, {} removed, {} modified",
+            component.name(),
+            changes.added.len(),
+            changes.removed.len(),
+            changes.modified.len()
+        );
+        for (mark, names) in [
+            ('+', &changes.added),
+            ('-', &changes.removed),
+            ('~', &changes.modified),
+        ] {
+            for name in names {
+                println!("  {} {}", mark, name);
+            }
+        }
+    }
+    for component in &diff.skipped {
+        println!(
+            "{}: not compared, captured by only one side",
+            component.name()
+        );
+    }
+    if total == 0 {
+        println!("No differences.");
+    }
+    Ok(())
+}
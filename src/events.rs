@@ -19,6 +19,9 @@ pub enum ProgressEvent {
     FileProgress { file_name: String, bytes_processed: u64 },
     PhaseChanged(BackupPhase),
     StatusMessage(String),
+    /// One line of a dry-run restore plan, e.g. `create` and a file path or
+    /// `install` and a package name.
+    PlannedChange { action: String, target: String },
     Completed,
     Error(String),
 }
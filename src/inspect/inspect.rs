@@ -0,0 +1,226 @@
+use serde::Serialize;
+use std::fs;
+use std::io;
+
+use crate::backup::consolidate::expand_user_path;
+use crate::cli::InspectArgs;
+use crate::pbar::archive::read_header;
+use crate::pbar::manifest::ComponentInfo;
+use crate::pbar::{CipherKind, KdfParams, PbarArchive, PbarHeader, PbarManifest};
+use crate::utils::compression::CompressionCodec;
+
+#[derive(Debug, Serialize)]
+pub struct InspectReport {
+    pub archive: String,
+    pub size_bytes: u64,
+    pub header: HeaderReport,
+    /// `None` when the manifest is encrypted and no key was given.
+    pub manifest: Option<PbarManifest>,
+    /// Totals of the file index, `None` when it is encrypted and no key was
+    /// given or the archive has none. `list --index` prints the files.
+    pub index: Option<IndexSummary>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct IndexSummary {
+    pub files: usize,
+    pub size_bytes: u64,
+    /// Files of the base of an increment that no longer exist.
+    pub deleted: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HeaderReport {
+    pub version: u16,
+    pub feature_flags: u16,
+    pub flags: Vec<&'static str>,
+    pub compression: &'static str,
+    pub cipher: Option<&'static str>,
+    pub kdf: Option<KdfParams>,
+    pub manifest_size: u32,
+    pub signed_by: Option<String>,
+}
+
+impl HeaderReport {
+    fn new(header: &PbarHeader) -> Self {
+        Self {
+            version: header.version,
+            feature_flags: header.feature_flags,
+            flags: header.flag_names(),
+            compression: CompressionCodec::from_flags(header.feature_flags).name(),
+            cipher: header
+                .is_encrypted()
+                .then(|| CipherKind::from_flags(header.feature_flags).name()),
+            kdf: header
+                .is_encrypted()
+                .then(|| header.effective_kdf_params()),
+            manifest_size: header.manifest_size,
+            signed_by: header
+                .signature_block
+                .as_ref()
+                .map(|sig| hex::encode(sig.public_key)),
+        }
+    }
+}
+
+/// Reads the header, manifest and file index totals of an archive without
+/// touching the payload.
+pub fn inspect_archive(args: &InspectArgs) -> io::Result<InspectReport> {
+    let archive_path = expand_user_path(&args.archive_path);
+    let size_bytes = fs::metadata(&archive_path)?.len();
+    let header = read_header(&archive_path)?;
+
+    let (manifest, index) = if header.is_encrypted() && args.decrypt_key.is_none() {
+        (None, None)
+    } else {
+        let mut archive = PbarArchive::open(&archive_path, args.decrypt_key.as_deref())?;
+        let index = archive.read_index()?.map(|index| IndexSummary {
+            files: index.files.len(),
+            size_bytes: index.total_size(),
+            deleted: index.deleted.len(),
+        });
+        (Some(archive.manifest), index)
+    };
+
+    Ok(InspectReport {
+        archive: archive_path.display().to_string(),
+        size_bytes,
+        header: HeaderReport::new(&header),
+        manifest,
+        index,
+    })
+}
+
+pub fn handle_inspect(args: &InspectArgs) -> io::Result<()> {
+    let report = inspect_archive(args)?;
+
+    if args.json {
+        let json = serde_json::to_string_pretty(&report)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        println!("{}", json);
+        return Ok(());
+    }
+
+    print_report(&report);
+    Ok(())
+}
+
+fn print_report(report: &InspectReport) {
+    let header = &report.header;
+    println!("Archive:      {}", report.archive);
+    println!("Size:         {} bytes", report.size_bytes);
+    println!("Version:      {}", header.version);
+    println!(
+        "Flags:        {:#06x} [{}]",
+        header.feature_flags,
+        header.flags.join(", ")
+    );
+    println!("Compression:  {}", header.compression);
+    println!("Cipher:       {}", header.cipher.unwrap_or("None"));
+    match header.kdf {
+        Some(kdf) => println!(
+            "KDF:          Argon2id (m={} KiB, t={}, p={})",
+            kdf.memory_kib, kdf.iterations, kdf.parallelism
+        ),
+        None => println!("KDF:          None"),
+    }
+    match &header.signed_by {
+        Some(key) => println!("Signed by:    {} (not verified, see `verify`)", key),
+        None => println!("Signed by:    None"),
+    }
+
+    let manifest = match &report.manifest {
+        Some(manifest) => manifest,
+        None => {
+            println!();
+            println!("Manifest is encrypted, pass --decrypt-key to show it.");
+            return;
+        }
+    };
+
+    println!();
+    println!("Created:      {}", manifest.created_at);
+    println!("Creator:      {}", manifest.creator);
+    println!("Format:       {}", manifest.format_version);
+
+    let sys = &manifest.system_info;
+    println!();
+    println!("System:");
+    println!("  Distro:     {} {}", sys.distro, sys.release);
+    println!("  Kernel:     {}", sys.kernel);
+    println!("  Arch:       {}", sys.arch);
+    println!("  Hostname:   {}", sys.hostname);
+
+    let contents = &manifest.archive_contents;
+    println!();
+    println!("Contents:");
+    print_component("Apps", &contents.apps);
+    print_component("Flatpak", &contents.flatpak);
+    let home = &contents.home_dotfiles;
+    if home.included {
+        println!(
+            "  Home:       {} bytes uncompressed{}",
+            home.uncompressed_size_bytes,
+            file_suffix(&home.file_path)
+        );
+    } else {
+        println!("  Home:       not included");
+    }
+    let keys = &contents.keys;
+    if keys.included {
+        println!(
+            "  Keys:       gpg: {}, ssh: {}",
+            yes_no(keys.gpg_keys),
+            yes_no(keys.ssh_keys)
+        );
+    } else {
+        println!("  Keys:       not included");
+    }
+    print_component("Systemd", &contents.systemd_services);
+
+    if let Some(index) = &report.index {
+        println!();
+        println!(
+            "Files:        {} indexed, {} bytes, see `list --index`",
+            index.files, index.size_bytes
+        );
+        if manifest.increment.is_some() {
+            println!("Deleted:      {} since the base", index.deleted);
+        }
+    }
+}
+
+fn print_component(label: &str, info: &ComponentInfo) {
+    let label = format!("{}:", label);
+    if !info.included {
+        println!("  {:<12}not included", label);
+        return;
+    }
+    let manager = info
+        .package_manager
+        .as_ref()
+        .map(|pm| format!(" via {}", pm))
+        .unwrap_or_default();
+    println!(
+        "  {:<12}{} entries{}{}",
+        label,
+        info.count,
+        manager,
+        file_suffix(&info.file_path)
+    );
+}
+
+fn file_suffix(file_path: &Option<String>) -> String {
+    file_path
+        .as_ref()
+        .map(|p| format!(" ({})", p))
+        .unwrap_or_default()
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
@@ -1,9 +1,16 @@
 pub mod backup;
 pub mod cli;
+pub mod config;
+pub mod diff;
 pub mod events;
 pub mod flatpak;
+pub mod inspect;
+pub mod list;
 pub mod pbar;
 pub mod pm;
+pub mod prune;
+pub mod repo;
 pub mod restore;
 pub mod system;
 pub mod utils;
+pub mod verify;
@@ -0,0 +1,240 @@
+use chrono::{Local, TimeZone};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::backup::consolidate::expand_user_path;
+use crate::cli::ListArgs;
+use crate::pbar::component::Component;
+use crate::pbar::manifest::FileRecord;
+use crate::pbar::PbarArchive;
+use crate::repo::snapshot::{parse_tree, TreeTar, TREE_ENTRY};
+use crate::repo::store::Repository;
+use crate::utils::compression::CompressionCodec;
+use crate::utils::filter::PathFilter;
+
+/// A file or directory captured in an archive.
+#[derive(Debug, Clone)]
+pub struct ArchiveEntry {
+    pub component: Component,
+    /// Path relative to the home directory, or to the component directory
+    /// for the package lists.
+    pub path: PathBuf,
+    /// Path inside the payload as the file index records it,
+    /// e.g. `homeb/.bashrc`.
+    pub payload_path: PathBuf,
+    pub size: u64,
+    pub mode: u32,
+    pub mtime: u64,
+    pub entry_type: tar::EntryType,
+}
+
+impl ArchiveEntry {
+    fn new(
+        component: Component,
+        path: PathBuf,
+        payload_path: PathBuf,
+        header: &tar::Header,
+    ) -> Self {
+        Self {
+            component,
+            path,
+            payload_path,
+            size: header.size().unwrap_or(0),
+            mode: header.mode().unwrap_or(0),
+            mtime: header.mtime().unwrap_or(0),
+            entry_type: header.entry_type(),
+        }
+    }
+
+    /// Entry for a record of the archive file index, `None` for a path
+    /// outside the known components.
+    pub fn from_record(record: &FileRecord) -> Option<Self> {
+        let payload_path = PathBuf::from(&record.path);
+        let component = Component::of_entry(&payload_path)?;
+        let inner_path = payload_path.iter().skip(1).collect::<PathBuf>();
+        let path = match component.home_prefix() {
+            Some(prefix) => prefix.join(inner_path),
+            None => inner_path,
+        };
+        Some(Self {
+            component,
+            path,
+            payload_path,
+            size: record.size,
+            mode: record.mode,
+            mtime: record.mtime,
+            entry_type: tar::EntryType::Regular,
+        })
+    }
+}
+
+/// Streams through the archive payload and the nested component tarballs,
+/// calling `visit` with every entry and its data without writing anything to disk.
+/// The files of a snapshot are read from its repository only when `visit` reads them.
+pub fn walk_archive<F>(archive: PbarArchive, visit: F) -> io::Result<()>
+where
+    F: FnMut(&ArchiveEntry, &mut dyn Read) -> io::Result<()>,
+{
+    let repository = archive.repository()?;
+    let mut payload = archive.payload()?;
+    walk_payload(&mut payload, repository.as_ref(), visit)
+}
+
+/// Like `walk_archive`, for a payload tarball that is already open. The
+/// files of a snapshot tree are read from `repository`.
+pub fn walk_payload<R, F>(
+    payload: &mut tar::Archive<R>,
+    repository: Option<&Repository>,
+    mut visit: F,
+) -> io::Result<()>
+where
+    R: Read,
+    F: FnMut(&ArchiveEntry, &mut dyn Read) -> io::Result<()>,
+{
+    for entry in payload.entries()? {
+        let mut entry = entry?;
+        if entry.path()? == Path::new(TREE_ENTRY) {
+            let repository = repository.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Snapshot tree in a plain archive",
+                )
+            })?;
+            let mut tree = tar::Archive::new(TreeTar::new(repository, parse_tree(&mut entry)?));
+            for tree_entry in tree.entries_with_seek()? {
+                visit_entry(&mut tree_entry?, &mut visit)?;
+            }
+            continue;
+        }
+        visit_entry(&mut entry, &mut visit)?;
+    }
+
+    Ok(())
+}
+
+fn visit_entry<R, F>(entry: &mut tar::Entry<R>, visit: &mut F) -> io::Result<()>
+where
+    R: Read,
+    F: FnMut(&ArchiveEntry, &mut dyn Read) -> io::Result<()>,
+{
+    let entry_path = entry.path()?.to_path_buf();
+    let component = match Component::of_entry(&entry_path) {
+        Some(component) => component,
+        None => return Ok(()), // manifest.json
+    };
+    let inner_path = entry_path.iter().skip(1).collect::<PathBuf>();
+
+    match (
+        component.home_prefix(),
+        CompressionCodec::from_path(&entry_path),
+    ) {
+        (Some(prefix), Some(codec)) => {
+            let mut nested = tar::Archive::new(codec.decoder(entry)?);
+            for nested_entry in nested.entries()? {
+                let mut nested_entry = nested_entry?;
+                let nested_path = nested_entry.path()?.to_path_buf();
+                let listed = ArchiveEntry::new(
+                    component,
+                    prefix.join(&nested_path),
+                    Path::new(component.dir()).join(nested_path),
+                    nested_entry.header(),
+                );
+                visit(&listed, &mut nested_entry)?;
+            }
+            // Read up to the end of the compressed stream so that its checksum is checked
+            io::copy(&mut nested.into_inner(), &mut io::sink())?;
+        }
+        (Some(prefix), None) => {
+            let listed = ArchiveEntry::new(
+                component,
+                prefix.join(inner_path),
+                entry_path,
+                entry.header(),
+            );
+            visit(&listed, entry)?;
+        }
+        (None, _) => {
+            let listed = ArchiveEntry::new(component, inner_path, entry_path, entry.header());
+            visit(&listed, entry)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn handle_list(args: &ListArgs) -> io::Result<()> {
+    let archive_path = expand_user_path(&args.archive_path);
+    let filter = PathFilter::new(&args.patterns, &args.exclude)?;
+    let mut archive = PbarArchive::open(&archive_path, args.decrypt_key.as_deref())?;
+
+    let mut count = 0usize;
+    let mut total_bytes = 0u64;
+    let mut print = |entry: &ArchiveEntry, stored_in: Option<&str>| {
+        if !filter.matches(&entry.path) {
+            return;
+        }
+        count += 1;
+        total_bytes += entry.size;
+        println!(
+            "{} {:>12} {} {:<8}{}{}",
+            format_mode(entry.entry_type, entry.mode),
+            entry.size,
+            format_mtime(entry.mtime),
+            entry.component.name(),
+            entry.path.display(),
+            stored_in
+                .map(|name| format!(" (in {})", name))
+                .unwrap_or_default()
+        );
+    };
+
+    if args.index {
+        let index = archive.read_index()?.unwrap_or_default();
+        if index.files.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} has no file index, list it without --index",
+                    archive_path.display()
+                ),
+            ));
+        }
+        for record in &index.files {
+            if let Some(entry) = ArchiveEntry::from_record(record) {
+                print(&entry, record.stored_in.as_deref());
+            }
+        }
+    } else {
+        walk_archive(archive, |entry, _| {
+            print(entry, None);
+            Ok(())
+        })?;
+    }
+
+    println!("{} entries, {} bytes", count, total_bytes);
+    Ok(())
+}
+
+fn format_mode(entry_type: tar::EntryType, mode: u32) -> String {
+    let kind = match entry_type {
+        tar::EntryType::Directory => 'd',
+        tar::EntryType::Symlink => 'l',
+        tar::EntryType::Link => 'h',
+        _ => '-',
+    };
+    let mut out = String::with_capacity(10);
+    out.push(kind);
+    for shift in [6, 3, 0] {
+        let bits = (mode >> shift) & 0o7;
+        out.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        out.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        out.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+    }
+    out
+}
+
+fn format_mtime(mtime: u64) -> String {
+    match Local.timestamp_opt(mtime as i64, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M").to_string(),
+        None => "-".repeat(16),
+    }
+}
@@ -1,14 +1,29 @@
 pub mod backup;
 pub mod cli;
+pub mod config;
+pub mod diff;
 pub mod events;
 pub mod flatpak;
+pub mod inspect;
+pub mod list;
 pub mod pbar;
 pub mod pm;
+pub mod prune;
+pub mod repo;
 pub mod restore;
 pub mod system;
 pub mod utils;
+pub mod verify;
 use crate::backup::backup::handle_backup;
+use crate::diff::diff::handle_diff;
+use crate::inspect::inspect::handle_inspect;
+use crate::list::list::handle_list;
+use crate::prune::prune::handle_prune;
+use crate::repo::repo::handle_repo;
 use crate::restore::restore::handle_restore;
+use crate::system::schedule::handle_schedule;
+use crate::verify::keygen::handle_keygen;
+use crate::verify::verify::handle_verify;
 use clap::Parser;
 use cli::{Cli, Commands};
 
@@ -17,7 +32,11 @@ fn main() {
 
     match cli.command {
         Commands::Backup(args) => {
-            handle_backup(&args);
+            let result = handle_backup(&args);
+            if let Err(e) = result {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
         }
         Commands::Restore(args) => {
             let result = handle_restore(&args);
@@ -26,12 +45,61 @@ fn main() {
                 std::process::exit(1);
             }
         }
-        // Commands::Schedule(args) => {
-        //     let result = system::schedule::schedule_backup(&args);
-        //     if let Err(e) = result {
-        //         eprintln!("Error: {}", e);
-        //         std::process::exit(1);
-        //     }
-        // }
+        Commands::Verify(args) => {
+            let result = handle_verify(&args);
+            if let Err(e) = result {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Inspect(args) => {
+            let result = handle_inspect(&args);
+            if let Err(e) = result {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::List(args) => {
+            let result = handle_list(&args);
+            if let Err(e) = result {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Diff(args) => {
+            let result = handle_diff(&args);
+            if let Err(e) = result {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Keygen(args) => {
+            let result = handle_keygen(&args);
+            if let Err(e) = result {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Prune(args) => {
+            let result = handle_prune(&args);
+            if let Err(e) = result {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Repo(args) => {
+            let result = handle_repo(&args);
+            if let Err(e) = result {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Schedule(args) => {
+            let result = handle_schedule(&args);
+            if let Err(e) = result {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
     }
 }
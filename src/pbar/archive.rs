@@ -0,0 +1,152 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Take};
+use std::path::{Path, PathBuf};
+
+use crate::pbar::header::PbarHeader;
+use crate::pbar::index::ArchiveIndex;
+use crate::pbar::manifest::PbarManifest;
+use crate::pbar::stream::{CipherKind, PbarChunkReader};
+use crate::repo::store::Repository;
+use crate::utils::compression::CompressionCodec;
+
+/// Reads and validates the header at the start of a `.pbar` file.
+pub fn read_header(path: &Path) -> io::Result<PbarHeader> {
+    let file = File::open(path)?;
+    let header = PbarHeader::read_from(file)?;
+    check_supported(&header)?;
+    Ok(header)
+}
+
+fn check_supported(header: &PbarHeader) -> io::Result<()> {
+    if header.has_supported_stream() {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "Encrypted archives of PBAR format version {} are not supported",
+            header.version
+        ),
+    ))
+}
+
+/// An opened `.pbar` archive, positioned at the start of its payload stream.
+pub struct PbarArchive {
+    pub header: PbarHeader,
+    pub manifest: PbarManifest,
+    path: PathBuf,
+    passphrase: Option<String>,
+    key: Option<[u8; 32]>,
+    file: File,
+    payload_start: u64,
+}
+
+impl PbarArchive {
+    /// Opens `path` and reads its manifest, deriving the archive key from
+    /// `passphrase` when the archive is encrypted.
+    pub fn open(path: &Path, passphrase: Option<&str>) -> io::Result<Self> {
+        Self::from_file(File::open(path)?, path, passphrase)
+    }
+
+    /// Like [`PbarArchive::open`], reading from the start of an already open
+    /// `file`, such as one whose signature was just checked. `path` is where
+    /// it was opened from, used to find the repository of a snapshot.
+    pub fn from_file(mut file: File, path: &Path, passphrase: Option<&str>) -> io::Result<Self> {
+        file.rewind()?;
+        let header = PbarHeader::read_from(&mut file)?;
+        check_supported(&header)?;
+
+        let key = match (header.is_encrypted(), passphrase) {
+            (false, _) => None,
+            (true, Some(pass)) => Some(header.derive_key(pass.as_bytes())?),
+            (true, None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Archive is encrypted but no decryption key was given",
+                ))
+            }
+        };
+
+        let manifest_block = header.read_manifest_block(&mut file)?;
+        let manifest = PbarManifest::from_block(&manifest_block, &header, key.as_ref())?;
+        let payload_start = file.stream_position()?;
+        if header.index_offset.is_some_and(|offset| offset < payload_start) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Index offset points before the payload",
+            ));
+        }
+
+        Ok(Self {
+            header,
+            manifest,
+            path: path.to_path_buf(),
+            passphrase: passphrase.map(str::to_string),
+            key,
+            file,
+            payload_start,
+        })
+    }
+
+    /// Opens the repository holding the files of a snapshot archive, `None`
+    /// for a self-contained archive. Snapshots sit in the `snapshots`
+    /// directory of their repository and are opened with the same passphrase.
+    pub fn repository(&self) -> io::Result<Option<Repository>> {
+        let info = match &self.manifest.repository {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+        let path = std::fs::canonicalize(&self.path)?;
+        let root = path.parent().and_then(Path::parent).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not inside a repository", self.path.display()),
+            )
+        })?;
+        let repo = Repository::open(root, self.passphrase.as_deref())?;
+        if repo.id != info.id {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} belongs to repository {}, but {} holds repository {}",
+                    self.path.display(),
+                    info.id,
+                    root.display(),
+                    repo.id
+                ),
+            ));
+        }
+        Ok(Some(repo))
+    }
+
+    /// Reads the file index stored after the payload, `None` for archives
+    /// without one. Leaves the archive positioned at the start of its payload.
+    pub fn read_index(&mut self) -> io::Result<Option<ArchiveIndex>> {
+        let offset = match self.header.index_offset {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        self.file.seek(SeekFrom::Start(offset))?;
+        let index = ArchiveIndex::read_from(&mut self.file, &self.header, self.key);
+        self.file.seek(SeekFrom::Start(self.payload_start))?;
+        index.map(Some)
+    }
+
+    /// Decrypted payload stream, still compressed with the codec of the
+    /// header. It ends where the file index starts.
+    pub fn payload_stream(self) -> PbarChunkReader<Take<File>> {
+        let cipher = CipherKind::from_flags(self.header.feature_flags);
+        let len = match self.header.index_offset {
+            Some(offset) => offset - self.payload_start,
+            None => u64::MAX,
+        };
+        PbarChunkReader::with_cipher(self.file.take(len), self.key, cipher, self.header.base_nonce)
+    }
+
+    /// Decrypted and decompressed payload tarball.
+    pub fn payload(self) -> io::Result<tar::Archive<Box<dyn Read>>> {
+        let codec = CompressionCodec::from_flags(self.header.feature_flags);
+        let decoder = codec.decoder(self.payload_stream())?;
+        Ok(tar::Archive::new(decoder))
+    }
+}
@@ -0,0 +1,65 @@
+use std::path::{Path, PathBuf};
+
+/// Top-level component directories of the PBAR payload tarball.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum Component {
+    Apps,
+    Flatpak,
+    Home,
+    Gpg,
+    Ssh,
+}
+
+impl Component {
+    /// Maps a payload directory name (`homeb`, `sshb`, ...) to its component.
+    pub fn from_dir(dir: &str) -> Option<Self> {
+        match dir {
+            "appsb" => Some(Component::Apps),
+            "flatpakb" => Some(Component::Flatpak),
+            "homeb" => Some(Component::Home),
+            "gnupgb" => Some(Component::Gpg),
+            "sshb" => Some(Component::Ssh),
+            _ => None,
+        }
+    }
+
+    /// Component of a payload entry, taken from its first path element.
+    pub fn of_entry(entry_path: &Path) -> Option<Self> {
+        entry_path
+            .iter()
+            .next()
+            .and_then(|s| s.to_str())
+            .and_then(Self::from_dir)
+    }
+
+    pub fn dir(self) -> &'static str {
+        match self {
+            Component::Apps => "appsb",
+            Component::Flatpak => "flatpakb",
+            Component::Home => "homeb",
+            Component::Gpg => "gnupgb",
+            Component::Ssh => "sshb",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Component::Apps => "apps",
+            Component::Flatpak => "flatpak",
+            Component::Home => "home",
+            Component::Gpg => "gpg",
+            Component::Ssh => "ssh",
+        }
+    }
+
+    /// Where this component's files live relative to the home directory,
+    /// `None` for the package lists that are not restored as files.
+    pub fn home_prefix(self) -> Option<PathBuf> {
+        match self {
+            Component::Home => Some(PathBuf::new()),
+            Component::Gpg => Some(PathBuf::from(".gnupg")),
+            Component::Ssh => Some(PathBuf::from(".ssh")),
+            Component::Apps | Component::Flatpak => None,
+        }
+    }
+}
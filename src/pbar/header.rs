@@ -1,7 +1,11 @@
 use std::io::{self, Read, Write};
 
+use crate::pbar::stream::{derive_argon2_key_with, KdfParams};
+
 pub const PBAR_MAGIC: &[u8; 4] = b"PBAR";
-pub const PBAR_VERSION: u16 = 0x0001; // v1.2 specification
+pub const PBAR_VERSION: u16 = 0x0002; // v1.3 specification
+/// First format version whose encrypted chunk stream carries a final-chunk marker.
+pub const PBAR_VERSION_FINAL_CHUNK: u16 = 0x0002;
 
 // Feature Flags Bitfield
 pub const FLAG_IS_ENCRYPTED: u16 = 1 << 0;
@@ -10,6 +14,15 @@ pub const FLAG_COMPRESS_ZSTD: u16 = 1 << 2; // 00 = Gzip, 01 = Zstd
 pub const FLAG_ENCRYPT_CHACHA: u16 = 1 << 4; // 00 = AES-256-GCM, 01 = ChaCha20
 pub const FLAG_ENCRYPTED_MANIFEST: u16 = 1 << 6;
 pub const FLAG_IS_SIGNED: u16 = 1 << 8;
+pub const FLAG_HAS_KDF_PARAMS: u16 = 1 << 9;
+pub const FLAG_HAS_INDEX: u16 = 1 << 10;
+
+/// Largest manifest block written or accepted from a header, 64 MiB. The
+/// manifest holds counts and sizes; per-file records go to the file index.
+pub const MAX_MANIFEST_SIZE: u32 = 64 * 1024 * 1024;
+
+/// KDF block algorithm identifier for Argon2id (version 0x13).
+pub const KDF_ARGON2ID: u8 = 1;
 
 #[derive(Debug, Clone)]
 pub struct PbarHeader {
@@ -18,6 +31,9 @@ pub struct PbarHeader {
     pub salt: [u8; 16],
     pub base_nonce: [u8; 12],
     pub manifest_size: u32,
+    pub kdf_params: Option<KdfParams>,
+    /// Offset of the file index stream that follows the payload.
+    pub index_offset: Option<u64>,
     pub signature_block: Option<PbarSignatureBlock>,
 }
 
@@ -39,8 +55,10 @@ impl PbarHeader {
         rng.fill_bytes(&mut base_nonce);
 
         let mut feature_flags = 0u16;
+        let mut kdf_params = None;
         if is_encrypted {
-            feature_flags |= FLAG_IS_ENCRYPTED | FLAG_ENCRYPTED_MANIFEST;
+            feature_flags |= FLAG_IS_ENCRYPTED | FLAG_ENCRYPTED_MANIFEST | FLAG_HAS_KDF_PARAMS;
+            kdf_params = Some(KdfParams::default());
         }
         if is_compressed {
             feature_flags |= FLAG_IS_COMPRESSED;
@@ -52,10 +70,53 @@ impl PbarHeader {
             salt,
             base_nonce,
             manifest_size,
+            kdf_params,
+            index_offset: None,
             signature_block: None,
         }
     }
 
+    /// Reads the manifest block that follows the header. The size comes from
+    /// the unauthenticated header, so it is checked before allocating.
+    pub fn read_manifest_block<R: Read>(&self, mut reader: R) -> io::Result<Vec<u8>> {
+        if self.manifest_size > MAX_MANIFEST_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Manifest size {} exceeds the limit of {} bytes",
+                    self.manifest_size, MAX_MANIFEST_SIZE
+                ),
+            ));
+        }
+        let mut block = vec![0u8; self.manifest_size as usize];
+        reader.read_exact(&mut block)?;
+        Ok(block)
+    }
+
+    /// Records the KDF cost parameters used for this archive's key.
+    pub fn set_kdf_params(&mut self, params: KdfParams) {
+        self.feature_flags |= FLAG_HAS_KDF_PARAMS;
+        self.kdf_params = Some(params);
+    }
+
+    /// Records where the file index starts. The field has a fixed size, so
+    /// the header can be rewritten in place once the payload is written.
+    pub fn set_index_offset(&mut self, offset: u64) {
+        self.feature_flags |= FLAG_HAS_INDEX;
+        self.index_offset = Some(offset);
+    }
+
+    /// KDF cost parameters for this archive. Archives written before the KDF
+    /// block existed used the Argon2 crate defaults.
+    pub fn effective_kdf_params(&self) -> KdfParams {
+        self.kdf_params.unwrap_or_default()
+    }
+
+    /// Derives the archive key from `passphrase` with this header's salt and KDF parameters.
+    pub fn derive_key(&self, passphrase: &[u8]) -> io::Result<[u8; 32]> {
+        derive_argon2_key_with(passphrase, &self.salt, &self.effective_kdf_params())
+    }
+
     pub fn is_encrypted(&self) -> bool {
         (self.feature_flags & FLAG_IS_ENCRYPTED) != 0
     }
@@ -64,10 +125,50 @@ impl PbarHeader {
         (self.feature_flags & FLAG_IS_COMPRESSED) != 0
     }
 
+    pub fn is_zstd(&self) -> bool {
+        (self.feature_flags & FLAG_COMPRESS_ZSTD) != 0
+    }
+
+    /// Encrypted streams written before the final-chunk marker cannot be
+    /// checked for truncation and are no longer read.
+    pub fn has_supported_stream(&self) -> bool {
+        !self.is_encrypted() || self.version >= PBAR_VERSION_FINAL_CHUNK
+    }
+
     pub fn is_signed(&self) -> bool {
         (self.feature_flags & FLAG_IS_SIGNED) != 0
     }
 
+    /// Names of the feature flags set in this header, for display.
+    pub fn flag_names(&self) -> Vec<&'static str> {
+        [
+            (FLAG_IS_ENCRYPTED, "encrypted"),
+            (FLAG_IS_COMPRESSED, "compressed"),
+            (FLAG_COMPRESS_ZSTD, "zstd"),
+            (FLAG_ENCRYPT_CHACHA, "chacha20"),
+            (FLAG_ENCRYPTED_MANIFEST, "encrypted-manifest"),
+            (FLAG_IS_SIGNED, "signed"),
+            (FLAG_HAS_KDF_PARAMS, "kdf-params"),
+            (FLAG_HAS_INDEX, "index"),
+        ]
+        .into_iter()
+        .filter(|(flag, _)| (self.feature_flags & flag) != 0)
+        .map(|(_, name)| name)
+        .collect()
+    }
+
+    /// Header bytes covered by the archive signature: the full header with the
+    /// signature field itself zeroed.
+    pub fn signable_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut unsigned = self.clone();
+        if let Some(sig) = unsigned.signature_block.as_mut() {
+            sig.signature = [0u8; 64];
+        }
+        let mut bytes = Vec::new();
+        unsigned.write_to(&mut bytes)?;
+        Ok(bytes)
+    }
+
     pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
         writer.write_all(PBAR_MAGIC)?;
         writer.write_all(&self.version.to_be_bytes())?;
@@ -76,6 +177,18 @@ impl PbarHeader {
         writer.write_all(&self.base_nonce)?;
         writer.write_all(&self.manifest_size.to_be_bytes())?;
 
+        if (self.feature_flags & FLAG_HAS_KDF_PARAMS) != 0 {
+            let params = self.effective_kdf_params();
+            writer.write_all(&[KDF_ARGON2ID])?;
+            writer.write_all(&params.memory_kib.to_be_bytes())?;
+            writer.write_all(&params.iterations.to_be_bytes())?;
+            writer.write_all(&params.parallelism.to_be_bytes())?;
+        }
+
+        if (self.feature_flags & FLAG_HAS_INDEX) != 0 {
+            writer.write_all(&self.index_offset.unwrap_or(0).to_be_bytes())?;
+        }
+
         if let Some(sig) = &self.signature_block {
             writer.write_all(&[sig.sig_type])?;
             writer.write_all(&sig.public_key)?;
@@ -98,6 +211,12 @@ impl PbarHeader {
         let mut version_bytes = [0u8; 2];
         reader.read_exact(&mut version_bytes)?;
         let version = u16::from_be_bytes(version_bytes);
+        if version == 0 || version > PBAR_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported PBAR format version {}", version),
+            ));
+        }
 
         let mut flags_bytes = [0u8; 2];
         reader.read_exact(&mut flags_bytes)?;
@@ -113,6 +232,38 @@ impl PbarHeader {
         reader.read_exact(&mut size_bytes)?;
         let manifest_size = u32::from_be_bytes(size_bytes);
 
+        let kdf_params = if (feature_flags & FLAG_HAS_KDF_PARAMS) != 0 {
+            let mut algorithm = [0u8; 1];
+            reader.read_exact(&mut algorithm)?;
+            if algorithm[0] != KDF_ARGON2ID {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unsupported KDF algorithm: {}", algorithm[0]),
+                ));
+            }
+            let mut fields = [[0u8; 4]; 3];
+            for field in fields.iter_mut() {
+                reader.read_exact(field)?;
+            }
+            let params = KdfParams {
+                memory_kib: u32::from_be_bytes(fields[0]),
+                iterations: u32::from_be_bytes(fields[1]),
+                parallelism: u32::from_be_bytes(fields[2]),
+            };
+            params.check_limits()?;
+            Some(params)
+        } else {
+            None
+        };
+
+        let index_offset = if (feature_flags & FLAG_HAS_INDEX) != 0 {
+            let mut offset = [0u8; 8];
+            reader.read_exact(&mut offset)?;
+            Some(u64::from_be_bytes(offset))
+        } else {
+            None
+        };
+
         let signature_block = if (feature_flags & FLAG_IS_SIGNED) != 0 {
             let mut sig_type = [0u8; 1];
             reader.read_exact(&mut sig_type)?;
@@ -135,6 +286,8 @@ impl PbarHeader {
             salt,
             base_nonce,
             manifest_size,
+            kdf_params,
+            index_offset,
             signature_block,
         })
     }
@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+use crate::pbar::header::PbarHeader;
+use crate::pbar::manifest::FileRecord;
+use crate::pbar::stream::{derive_index_nonce, CipherKind, PbarChunkReader, PbarChunkWriter};
+use crate::utils::compression::CompressionCodec;
+
+/// Longest line accepted from an index, 64 KiB. Paths are far shorter.
+pub const MAX_INDEX_LINE: u64 = 64 * 1024;
+
+/// File index stored after the payload. Kept out of the manifest so that
+/// its size does not grow with the number of files.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArchiveIndex {
+    /// Every file captured by the backup, including the ones an increment
+    /// leaves in earlier archives.
+    pub files: Vec<FileRecord>,
+    /// Payload paths present in the base of an increment that no longer exist.
+    pub deleted: Vec<String>,
+}
+
+/// One line of the index stream.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum IndexLine {
+    File(FileRecord),
+    Deleted(String),
+}
+
+impl ArchiveIndex {
+    /// Sum of the sizes of the indexed files.
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|record| record.size).sum()
+    }
+
+    /// Writes the index as JSON lines, compressed with the payload codec and
+    /// sealed in chunks like the payload, with the index nonce of `header`.
+    pub fn write_to<W: Write>(
+        &self,
+        writer: W,
+        header: &PbarHeader,
+        key: Option<[u8; 32]>,
+    ) -> io::Result<W> {
+        let cipher = CipherKind::from_flags(header.feature_flags);
+        let nonce = derive_index_nonce(&header.base_nonce);
+        let mut stream = PbarChunkWriter::with_cipher(writer, key, cipher, nonce);
+        {
+            let codec = CompressionCodec::from_flags(header.feature_flags);
+            let mut enc = io::BufWriter::new(codec.encoder(&mut stream)?);
+            let files = self.files.iter().cloned().map(IndexLine::File);
+            let deleted = self.deleted.iter().cloned().map(IndexLine::Deleted);
+            for line in files.chain(deleted) {
+                serde_json::to_writer(&mut enc, &line).map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("Index error: {}", e))
+                })?;
+                enc.write_all(b"\n")?;
+            }
+            enc.into_inner().map_err(|e| e.into_error())?.finish()?;
+        }
+        stream.finish()
+    }
+
+    /// Reads an index written by [`ArchiveIndex::write_to`] from `reader`,
+    /// positioned at its start.
+    pub fn read_from<R: Read>(
+        reader: R,
+        header: &PbarHeader,
+        key: Option<[u8; 32]>,
+    ) -> io::Result<Self> {
+        let cipher = CipherKind::from_flags(header.feature_flags);
+        let nonce = derive_index_nonce(&header.base_nonce);
+        let stream = PbarChunkReader::with_cipher(reader, key, cipher, nonce);
+        let codec = CompressionCodec::from_flags(header.feature_flags);
+        let mut lines = BufReader::new(codec.decoder(stream)?);
+
+        let mut index = ArchiveIndex::default();
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let n = lines
+                .by_ref()
+                .take(MAX_INDEX_LINE)
+                .read_until(b'\n', &mut line)?;
+            if n == 0 {
+                break;
+            }
+            if line.last() != Some(&b'\n') {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Index line {} is unterminated or longer than {} bytes",
+                        index.files.len() + index.deleted.len() + 1,
+                        MAX_INDEX_LINE
+                    ),
+                ));
+            }
+            match serde_json::from_slice(&line) {
+                Ok(IndexLine::File(record)) => index.files.push(record),
+                Ok(IndexLine::Deleted(path)) => index.deleted.push(path),
+                Err(e) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Index error: {}", e),
+                    ))
+                }
+            }
+        }
+        Ok(index)
+    }
+}
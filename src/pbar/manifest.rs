@@ -1,4 +1,8 @@
 use serde::{Deserialize, Serialize};
+use std::io;
+
+use crate::pbar::header::{PbarHeader, FLAG_ENCRYPTED_MANIFEST, MAX_MANIFEST_SIZE};
+use crate::pbar::stream::{open_manifest, seal_manifest, CipherKind};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PbarManifest {
@@ -8,6 +12,12 @@ pub struct PbarManifest {
     pub security: SecurityInfo,
     pub system_info: SystemInfo,
     pub archive_contents: ArchiveContents,
+    /// Set when this archive only stores changes on top of an earlier one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub increment: Option<IncrementInfo>,
+    /// Set when this archive is a snapshot whose files live in a repository.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repository: Option<RepositoryInfo>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -54,6 +64,9 @@ pub struct HomeInfo {
     pub uncompressed_size_bytes: u64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub file_path: Option<String>,
+    /// Directories relative to the home directory the backup left out.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_dir: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -63,6 +76,35 @@ pub struct KeysInfo {
     pub ssh_keys: bool,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileRecord {
+    /// Path inside the payload, e.g. `homeb/.bashrc`.
+    pub path: String,
+    pub size: u64,
+    /// Permission bits, 0 in archives written before they were indexed.
+    #[serde(default)]
+    pub mode: u32,
+    pub mtime: u64,
+    /// Hex SHA-256 of the file contents.
+    pub sha256: String,
+    /// File name of the earlier archive in the chain that holds the
+    /// contents, `None` when this archive stores them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored_in: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IncrementInfo {
+    /// File name of the archive this one was built on, expected in the same directory.
+    pub base: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RepositoryInfo {
+    /// Id of the repository holding the file chunks, two levels above the snapshot.
+    pub id: String,
+}
+
 impl PbarManifest {
     pub fn to_json_bytes(&self) -> Result<Vec<u8>, serde_json::Error> {
         serde_json::to_vec_pretty(self)
@@ -71,4 +113,51 @@ impl PbarManifest {
     pub fn from_json_slice(slice: &[u8]) -> Result<Self, serde_json::Error> {
         serde_json::from_slice(slice)
     }
+
+    /// Encodes the manifest block stored right after the header, sealed with
+    /// the archive key when the header has `FLAG_ENCRYPTED_MANIFEST` set.
+    pub fn to_block(&self, header: &PbarHeader, key: Option<&[u8; 32]>) -> io::Result<Vec<u8>> {
+        let json = self
+            .to_json_bytes()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Manifest error: {}", e)))?;
+        let block = if (header.feature_flags & FLAG_ENCRYPTED_MANIFEST) == 0 {
+            json
+        } else {
+            let key = key.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Encrypted manifest requires a key")
+            })?;
+            let cipher = CipherKind::from_flags(header.feature_flags);
+            seal_manifest(&json, key, cipher, &header.base_nonce)?
+        };
+        // Readers refuse larger blocks
+        if block.len() > MAX_MANIFEST_SIZE as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Manifest of {} bytes exceeds the limit of {} bytes",
+                    block.len(),
+                    MAX_MANIFEST_SIZE
+                ),
+            ));
+        }
+        Ok(block)
+    }
+
+    /// Decodes a manifest block read from after the header.
+    pub fn from_block(block: &[u8], header: &PbarHeader, key: Option<&[u8; 32]>) -> io::Result<Self> {
+        let json = if (header.feature_flags & FLAG_ENCRYPTED_MANIFEST) != 0 {
+            let key = key.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Manifest is encrypted, a decryption key is required",
+                )
+            })?;
+            let cipher = CipherKind::from_flags(header.feature_flags);
+            open_manifest(block, key, cipher, &header.base_nonce)?
+        } else {
+            block.to_vec()
+        };
+        Self::from_json_slice(&json)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Manifest error: {}", e)))
+    }
 }
@@ -1,7 +1,14 @@
+pub mod archive;
+pub mod component;
 pub mod header;
+pub mod index;
 pub mod manifest;
+pub mod signature;
 pub mod stream;
 
-pub use header::{PbarHeader, FLAG_IS_COMPRESSED, FLAG_IS_ENCRYPTED, PBAR_MAGIC, PBAR_VERSION};
+pub use archive::PbarArchive;
+pub use header::{
+    PbarHeader, FLAG_COMPRESS_ZSTD, FLAG_IS_COMPRESSED, FLAG_IS_ENCRYPTED, PBAR_MAGIC, PBAR_VERSION,
+};
 pub use manifest::PbarManifest;
-pub use stream::{derive_argon2_key, PbarChunkReader, PbarChunkWriter};
+pub use stream::{derive_argon2_key, CipherKind, KdfParams, PbarChunkReader, PbarChunkWriter};
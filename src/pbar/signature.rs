@@ -0,0 +1,175 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use crate::pbar::header::{PbarHeader, PbarSignatureBlock, FLAG_IS_SIGNED};
+
+pub const SIG_TYPE_ED25519: u8 = 1;
+
+/// Domain separation prefix for signed PBAR messages.
+const SIGNATURE_DOMAIN: &[u8] = b"PBAR-SIG-v1";
+
+/// Writer wrapper that SHA-256 hashes every byte passed through to the underlying stream.
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// The wrapped writer, for queries such as its position. Bytes written
+    /// through it directly are not hashed.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn finalize(self) -> (W, [u8; 32]) {
+        (self.inner, self.hasher.finalize().into())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Outcome of checking an archive's signature block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureStatus {
+    Unsigned,
+    Valid { public_key: [u8; 32] },
+}
+
+/// Message signed for an archive: domain tag, header (signature zeroed) and the
+/// SHA-256 digest of everything stored after the header (manifest and payload).
+pub fn signed_message(header: &PbarHeader, content_digest: &[u8; 32]) -> io::Result<Vec<u8>> {
+    let mut message = Vec::with_capacity(SIGNATURE_DOMAIN.len() + 160);
+    message.extend_from_slice(SIGNATURE_DOMAIN);
+    message.extend_from_slice(&header.signable_bytes()?);
+    message.extend_from_slice(content_digest);
+    Ok(message)
+}
+
+/// Marks the header as signed by `key` and reserves a zeroed signature block.
+pub fn prepare_signed_header(header: &mut PbarHeader, key: &SigningKey) {
+    header.feature_flags |= FLAG_IS_SIGNED;
+    header.signature_block = Some(PbarSignatureBlock {
+        sig_type: SIG_TYPE_ED25519,
+        public_key: key.verifying_key().to_bytes(),
+        signature: [0u8; 64],
+    });
+}
+
+/// Fills in the signature block of a header prepared with `prepare_signed_header`.
+pub fn sign_header(
+    header: &mut PbarHeader,
+    key: &SigningKey,
+    content_digest: &[u8; 32],
+) -> io::Result<()> {
+    let message = signed_message(header, content_digest)?;
+    let signature = key.sign(&message);
+    match header.signature_block.as_mut() {
+        Some(block) => {
+            block.signature = signature.to_bytes();
+            Ok(())
+        }
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Header has no reserved signature block",
+        )),
+    }
+}
+
+/// Reads a whole archive from `reader` and checks its signature block, if any.
+pub fn verify_archive_signature<R: Read>(mut reader: R) -> io::Result<SignatureStatus> {
+    let header = PbarHeader::read_from(&mut reader)?;
+    let block = match (&header.signature_block, header.is_signed()) {
+        (Some(block), true) => block.clone(),
+        _ => return Ok(SignatureStatus::Unsigned),
+    };
+
+    if block.sig_type != SIG_TYPE_ED25519 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unsupported signature type: {}", block.sig_type),
+        ));
+    }
+
+    let mut hasher = HashingWriter::new(io::sink());
+    io::copy(&mut reader, &mut hasher)?;
+    let (_, content_digest) = hasher.finalize();
+
+    let verifying_key = VerifyingKey::from_bytes(&block.public_key).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, "Invalid Ed25519 public key in archive")
+    })?;
+    let message = signed_message(&header, &content_digest)?;
+    verifying_key
+        .verify(&message, &Signature::from_bytes(&block.signature))
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Archive signature is invalid: contents were modified after signing",
+            )
+        })?;
+
+    Ok(SignatureStatus::Valid {
+        public_key: block.public_key,
+    })
+}
+
+/// Loads an Ed25519 signing key stored as 64 hex characters or 32 raw bytes.
+pub fn load_signing_key(path: &Path) -> io::Result<SigningKey> {
+    let data = fs::read(path)?;
+    let seed = decode_key_file(&data).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a valid Ed25519 signing key", path.display()),
+        )
+    })?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+/// Parses a trusted public key given as hex or as a path to a file holding it.
+pub fn parse_verifying_key(spec: &str) -> io::Result<VerifyingKey> {
+    let bytes = match decode_hex_key(spec) {
+        Some(bytes) => bytes,
+        None => {
+            let data = fs::read(spec)?;
+            decode_key_file(&data).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not a valid Ed25519 public key", spec),
+                )
+            })?
+        }
+    };
+    VerifyingKey::from_bytes(&bytes)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid Ed25519 public key"))
+}
+
+fn decode_hex_key(text: &str) -> Option<[u8; 32]> {
+    hex::decode(text.trim()).ok()?.try_into().ok()
+}
+
+/// Key files hold either 64 hex characters or the 32 raw key bytes.
+fn decode_key_file(data: &[u8]) -> Option<[u8; 32]> {
+    std::str::from_utf8(data)
+        .ok()
+        .and_then(decode_hex_key)
+        .or_else(|| data.try_into().ok())
+}
@@ -2,15 +2,176 @@ use aes_gcm::aead::generic_array::GenericArray;
 use aes_gcm::aead::{Aead, KeyInit, Payload};
 use aes_gcm::Aes256Gcm;
 use argon2::Argon2;
+use chacha20poly1305::ChaCha20Poly1305;
+use serde::{Deserialize, Serialize};
 use std::io::{self, Read, Write};
 
+use crate::pbar::header::FLAG_ENCRYPT_CHACHA;
+
 pub const CHUNK_SIZE: usize = 64 * 1024; // 64 KB
 
+/// AEAD cipher used for the chunked payload stream.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum, Serialize, Deserialize,
+)]
+pub enum CipherKind {
+    #[default]
+    #[value(name = "aes256-gcm")]
+    #[serde(rename = "aes256-gcm")]
+    Aes256Gcm,
+    #[value(name = "chacha20")]
+    #[serde(rename = "chacha20")]
+    ChaCha20Poly1305,
+}
+
+impl CipherKind {
+    /// Reads the cipher out of the PBAR header feature flags.
+    pub fn from_flags(feature_flags: u16) -> Self {
+        if (feature_flags & FLAG_ENCRYPT_CHACHA) != 0 {
+            CipherKind::ChaCha20Poly1305
+        } else {
+            CipherKind::Aes256Gcm
+        }
+    }
+
+    /// Feature flag bits to OR into the PBAR header.
+    pub fn header_flag(self) -> u16 {
+        match self {
+            CipherKind::Aes256Gcm => 0,
+            CipherKind::ChaCha20Poly1305 => FLAG_ENCRYPT_CHACHA,
+        }
+    }
+
+    /// Name recorded in `SecurityInfo.cipher`.
+    pub fn name(self) -> &'static str {
+        match self {
+            CipherKind::Aes256Gcm => "AES-256-GCM",
+            CipherKind::ChaCha20Poly1305 => "ChaCha20-Poly1305",
+        }
+    }
+}
+
+/// Keyed AEAD instance for one of the supported ciphers.
+enum ChunkCipher {
+    Aes(Box<Aes256Gcm>),
+    ChaCha(ChaCha20Poly1305),
+}
+
+impl ChunkCipher {
+    fn new(kind: CipherKind, key: &[u8; 32]) -> Self {
+        let key = GenericArray::from_slice(key);
+        match kind {
+            CipherKind::Aes256Gcm => ChunkCipher::Aes(Box::new(Aes256Gcm::new(key))),
+            CipherKind::ChaCha20Poly1305 => ChunkCipher::ChaCha(ChaCha20Poly1305::new(key)),
+        }
+    }
+
+    fn kind(&self) -> CipherKind {
+        match self {
+            ChunkCipher::Aes(_) => CipherKind::Aes256Gcm,
+            ChunkCipher::ChaCha(_) => CipherKind::ChaCha20Poly1305,
+        }
+    }
+
+    fn encrypt(
+        &self,
+        nonce: &GenericArray<u8, aes_gcm::aead::consts::U12>,
+        payload: Payload,
+    ) -> Result<Vec<u8>, aes_gcm::aead::Error> {
+        match self {
+            ChunkCipher::Aes(c) => c.encrypt(nonce, payload),
+            ChunkCipher::ChaCha(c) => c.encrypt(nonce, payload),
+        }
+    }
+
+    fn decrypt(
+        &self,
+        nonce: &GenericArray<u8, aes_gcm::aead::consts::U12>,
+        payload: Payload,
+    ) -> Result<Vec<u8>, aes_gcm::aead::Error> {
+        match self {
+            ChunkCipher::Aes(c) => c.decrypt(nonce, payload),
+            ChunkCipher::ChaCha(c) => c.decrypt(nonce, payload),
+        }
+    }
+}
+
+/// Argon2id cost parameters stored in the PBAR header KDF block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct KdfParams {
+    /// Memory cost in KiB.
+    pub memory_kib: u32,
+    /// Number of passes.
+    pub iterations: u32,
+    /// Degree of parallelism (lanes).
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            memory_kib: argon2::Params::DEFAULT_M_COST,
+            iterations: argon2::Params::DEFAULT_T_COST,
+            parallelism: argon2::Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Largest memory cost accepted from a header, 4 GiB.
+    pub const MAX_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+    pub const MAX_ITERATIONS: u32 = 64;
+    pub const MAX_PARALLELISM: u32 = 64;
+
+    /// Rejects costs above the limits. The KDF block is read before anything
+    /// is authenticated, so a crafted header could otherwise make key
+    /// derivation allocate or spin without bound.
+    pub fn check_limits(&self) -> io::Result<()> {
+        for (name, value, max) in [
+            ("memory cost", self.memory_kib, Self::MAX_MEMORY_KIB),
+            ("iterations", self.iterations, Self::MAX_ITERATIONS),
+            ("parallelism", self.parallelism, Self::MAX_PARALLELISM),
+        ] {
+            if value > max {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Argon2 {} {} exceeds the limit of {}", name, value, max),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn to_argon2(self) -> io::Result<argon2::Params> {
+        self.check_limits()?;
+        argon2::Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32)).map_err(
+            |e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid Argon2 parameters: {}", e),
+                )
+            },
+        )
+    }
+}
+
 pub fn derive_argon2_key(passphrase: &[u8], salt: &[u8; 16]) -> io::Result<[u8; 32]> {
+    derive_argon2_key_with(passphrase, salt, &KdfParams::default())
+}
+
+pub fn derive_argon2_key_with(
+    passphrase: &[u8],
+    salt: &[u8; 16],
+    params: &KdfParams,
+) -> io::Result<[u8; 32]> {
     let mut key = [0u8; 32];
-    Argon2::default()
-        .hash_password_into(passphrase, salt, &mut key)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Argon2 KDF failed: {}", e)))?;
+    Argon2::new(
+        argon2::Algorithm::Argon2id,
+        argon2::Version::V0x13,
+        params.to_argon2()?,
+    )
+    .hash_password_into(passphrase, salt, &mut key)
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Argon2 KDF failed: {}", e)))?;
     Ok(key)
 }
 
@@ -23,17 +184,92 @@ pub fn derive_chunk_nonce(base_nonce: &[u8; 12], chunk_index: u64) -> GenericArr
     GenericArray::clone_from_slice(&nonce)
 }
 
-pub fn construct_ad(chunk_index: u64) -> Vec<u8> {
-    let mut ad = Vec::with_capacity(12);
+/// Nonce for the sealed manifest block. Chunk nonces only vary the last eight
+/// bytes, so flipping the first four keeps the manifest nonce out of their range.
+pub fn derive_manifest_nonce(base_nonce: &[u8; 12]) -> GenericArray<u8, aes_gcm::aead::consts::U12> {
+    let mut nonce = *base_nonce;
+    for (byte, mask) in nonce.iter_mut().zip(b"MNFT") {
+        *byte ^= mask;
+    }
+    GenericArray::clone_from_slice(&nonce)
+}
+
+/// Base nonce of the chunk stream holding the file index. Its first four
+/// bytes differ from those of the payload and manifest nonces, so the chunk
+/// nonces of the two streams never meet.
+pub fn derive_index_nonce(base_nonce: &[u8; 12]) -> [u8; 12] {
+    let mut nonce = *base_nonce;
+    for (byte, mask) in nonce.iter_mut().zip(b"INDX") {
+        *byte ^= mask;
+    }
+    nonce
+}
+
+const MANIFEST_AD: &[u8] = b"PBAR-MANIFEST";
+
+/// Seals the JSON manifest as a single AEAD block stored after the header.
+pub fn seal_manifest(
+    plaintext: &[u8],
+    key: &[u8; 32],
+    cipher: CipherKind,
+    base_nonce: &[u8; 12],
+) -> io::Result<Vec<u8>> {
+    let aead = ChunkCipher::new(cipher, key);
+    aead.encrypt(
+        &derive_manifest_nonce(base_nonce),
+        Payload {
+            msg: plaintext,
+            aad: MANIFEST_AD,
+        },
+    )
+    .map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} manifest encryption failed", cipher.name()),
+        )
+    })
+}
+
+/// Opens a manifest block sealed with `seal_manifest`.
+pub fn open_manifest(
+    ciphertext: &[u8],
+    key: &[u8; 32],
+    cipher: CipherKind,
+    base_nonce: &[u8; 12],
+) -> io::Result<Vec<u8>> {
+    let aead = ChunkCipher::new(cipher, key);
+    aead.decrypt(
+        &derive_manifest_nonce(base_nonce),
+        Payload {
+            msg: ciphertext,
+            aad: MANIFEST_AD,
+        },
+    )
+    .map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Manifest decryption failed: wrong password or corrupted archive",
+        )
+    })
+}
+
+/// Associated data for chunk `chunk_index`. The trailing flag byte marks the last
+/// chunk of the stream, so dropping trailing chunks fails authentication.
+pub fn construct_ad(chunk_index: u64, is_final: bool) -> Vec<u8> {
+    let mut ad = Vec::with_capacity(13);
     ad.extend_from_slice(&chunk_index.to_be_bytes());
     ad.extend_from_slice(b"PBAR");
+    ad.push(is_final as u8);
     ad
 }
 
+/// AEAD tag length shared by AES-256-GCM and ChaCha20-Poly1305.
+const TAG_SIZE: usize = 16;
+
 /// Writer wrapper that streams plaintext, encrypts in 64KB AEAD chunks, and writes to underlying stream.
 pub struct PbarChunkWriter<W: Write> {
     writer: W,
-    cipher: Option<Aes256Gcm>,
+    cipher: Option<ChunkCipher>,
     base_nonce: [u8; 12],
     chunk_index: u64,
     buffer: Vec<u8>,
@@ -42,7 +278,16 @@ pub struct PbarChunkWriter<W: Write> {
 
 impl<W: Write> PbarChunkWriter<W> {
     pub fn new(writer: W, key: Option<[u8; 32]>, base_nonce: [u8; 12]) -> Self {
-        let cipher = key.map(|k| Aes256Gcm::new(GenericArray::from_slice(&k)));
+        Self::with_cipher(writer, key, CipherKind::Aes256Gcm, base_nonce)
+    }
+
+    pub fn with_cipher(
+        writer: W,
+        key: Option<[u8; 32]>,
+        cipher: CipherKind,
+        base_nonce: [u8; 12],
+    ) -> Self {
+        let cipher = key.map(|k| ChunkCipher::new(cipher, &k));
         Self {
             writer,
             cipher,
@@ -57,17 +302,24 @@ impl<W: Write> PbarChunkWriter<W> {
         self.bytes_written
     }
 
-    fn flush_chunk(&mut self) -> io::Result<()> {
-        if self.buffer.is_empty() {
+    fn flush_chunk(&mut self, is_final: bool) -> io::Result<()> {
+        // The final chunk is always emitted, even when empty, so readers can tell
+        // a complete stream from a truncated one.
+        if self.buffer.is_empty() && !(is_final && self.cipher.is_some()) {
             return Ok(());
         }
 
         if let Some(ref cipher) = self.cipher {
             let nonce = derive_chunk_nonce(&self.base_nonce, self.chunk_index);
-            let ad = construct_ad(self.chunk_index);
+            let ad = construct_ad(self.chunk_index, is_final);
             let ciphertext = cipher
                 .encrypt(&nonce, Payload { msg: &self.buffer, aad: &ad })
-                .map_err(|_| io::Error::new(io::ErrorKind::Other, "AES-GCM chunk encryption failed"))?;
+                .map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("{} chunk encryption failed", cipher.kind().name()),
+                    )
+                })?;
 
             let chunk_len = ciphertext.len() as u32;
             self.writer.write_all(&chunk_len.to_be_bytes())?;
@@ -82,8 +334,10 @@ impl<W: Write> PbarChunkWriter<W> {
         Ok(())
     }
 
+    /// Seals the buffered data as the final chunk. Dropping the writer without
+    /// calling this leaves a stream that readers reject as truncated.
     pub fn finish(mut self) -> io::Result<W> {
-        self.flush_chunk()?;
+        self.flush_chunk(true)?;
         self.writer.flush()?;
         Ok(self.writer)
     }
@@ -93,20 +347,22 @@ impl<W: Write> Write for PbarChunkWriter<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let mut written = 0;
         while written < buf.len() {
+            // A full chunk is only sealed once more data arrives, since until
+            // then it might still turn out to be the final one.
+            if self.buffer.len() == CHUNK_SIZE {
+                self.flush_chunk(false)?;
+            }
+
             let space = CHUNK_SIZE - self.buffer.len();
             let to_copy = (buf.len() - written).min(space);
             self.buffer.extend_from_slice(&buf[written..written + to_copy]);
             written += to_copy;
-
-            if self.buffer.len() == CHUNK_SIZE {
-                self.flush_chunk()?;
-            }
         }
         Ok(written)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.flush_chunk()?;
+        self.flush_chunk(false)?;
         self.writer.flush()
     }
 }
@@ -114,7 +370,7 @@ impl<W: Write> Write for PbarChunkWriter<W> {
 /// Reader wrapper that reads 64KB AEAD chunks from underlying stream and decrypts into plaintext stream.
 pub struct PbarChunkReader<R: Read> {
     reader: R,
-    cipher: Option<Aes256Gcm>,
+    cipher: Option<ChunkCipher>,
     base_nonce: [u8; 12],
     chunk_index: u64,
     buffer: Vec<u8>,
@@ -124,7 +380,16 @@ pub struct PbarChunkReader<R: Read> {
 
 impl<R: Read> PbarChunkReader<R> {
     pub fn new(reader: R, key: Option<[u8; 32]>, base_nonce: [u8; 12]) -> Self {
-        let cipher = key.map(|k| Aes256Gcm::new(GenericArray::from_slice(&k)));
+        Self::with_cipher(reader, key, CipherKind::Aes256Gcm, base_nonce)
+    }
+
+    pub fn with_cipher(
+        reader: R,
+        key: Option<[u8; 32]>,
+        cipher: CipherKind,
+        base_nonce: [u8; 12],
+    ) -> Self {
+        let cipher = key.map(|k| ChunkCipher::new(cipher, &k));
         Self {
             reader,
             cipher,
@@ -136,6 +401,11 @@ impl<R: Read> PbarChunkReader<R> {
         }
     }
 
+    /// Number of AEAD chunks authenticated so far, 0 for plaintext streams.
+    pub fn chunks_read(&self) -> u64 {
+        self.chunk_index
+    }
+
     fn read_next_chunk(&mut self) -> io::Result<bool> {
         if self.eof {
             return Ok(false);
@@ -143,29 +413,58 @@ impl<R: Read> PbarChunkReader<R> {
 
         if let Some(ref cipher) = self.cipher {
             let mut len_bytes = [0u8; 4];
-            match self.reader.read_exact(&mut len_bytes) {
-                Ok(_) => {},
-                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
-                    self.eof = true;
-                    return Ok(false);
-                }
-                Err(e) => return Err(e),
-            }
+            self.reader
+                .read_exact(&mut len_bytes)
+                .map_err(|e| truncation_error(e, self.chunk_index))?;
 
             let chunk_len = u32::from_be_bytes(len_bytes) as usize;
+            if !(TAG_SIZE..=CHUNK_SIZE + TAG_SIZE).contains(&chunk_len) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid length {} for chunk {}", chunk_len, self.chunk_index),
+                ));
+            }
             let mut ciphertext = vec![0u8; chunk_len];
-            self.reader.read_exact(&mut ciphertext)?;
+            self.reader
+                .read_exact(&mut ciphertext)
+                .map_err(|e| truncation_error(e, self.chunk_index))?;
 
             let nonce = derive_chunk_nonce(&self.base_nonce, self.chunk_index);
-            let ad = construct_ad(self.chunk_index);
 
-            let plaintext = cipher
-                .decrypt(&nonce, Payload { msg: &ciphertext, aad: &ad })
-                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "AES-GCM decryption failed or bad password/tag"))?;
+            // Only a short chunk is normally final, so try that case first for it.
+            let likely_final = chunk_len < CHUNK_SIZE + TAG_SIZE;
+            let mut opened = None;
+            for is_final in [likely_final, !likely_final] {
+                let ad = construct_ad(self.chunk_index, is_final);
+                if let Ok(plaintext) = cipher.decrypt(&nonce, Payload { msg: &ciphertext, aad: &ad }) {
+                    opened = Some((plaintext, is_final));
+                    break;
+                }
+            }
+
+            let (plaintext, is_final) = opened.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{} decryption of chunk {} failed: bad password, corrupted or reordered data",
+                        cipher.kind().name(),
+                        self.chunk_index
+                    ),
+                )
+            })?;
+
+            // Nothing may follow the final chunk, or appended data would go unnoticed
+            if is_final && self.reader.read(&mut [0u8; 1])? > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("PBAR stream has data after its final chunk {}", self.chunk_index),
+                ));
+            }
 
             self.buffer = plaintext;
             self.buffer_offset = 0;
             self.chunk_index += 1;
+            self.eof = is_final;
             Ok(true)
         } else {
             let mut raw_buf = vec![0u8; CHUNK_SIZE];
@@ -182,9 +481,23 @@ impl<R: Read> PbarChunkReader<R> {
     }
 }
 
+fn truncation_error(e: io::Error, chunk_index: u64) -> io::Error {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!(
+                "PBAR stream truncated: final chunk missing after {} chunks",
+                chunk_index
+            ),
+        )
+    } else {
+        e
+    }
+}
+
 impl<R: Read> Read for PbarChunkReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        if self.buffer_offset >= self.buffer.len() {
+        while self.buffer_offset >= self.buffer.len() {
             if !self.read_next_chunk()? {
                 return Ok(0);
             }
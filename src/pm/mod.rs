@@ -1 +1,2 @@
+pub mod packages;
 pub mod paru;
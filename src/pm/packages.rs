@@ -0,0 +1,440 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::pm::paru::detect_package_manager;
+
+/// Name of the structured package list inside the `appsb` component.
+pub const PACKAGES_FILE: &str = "packages.json";
+
+/// `repo` of packages that are in no sync repository, e.g. from the AUR.
+pub const FOREIGN_REPO: &str = "foreign";
+
+/// Where pacman keeps downloaded packages unless `pacman.conf` says otherwise.
+const DEFAULT_CACHE_DIR: &str = "/var/cache/pacman/pkg";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InstallReason {
+    Explicit,
+    Dependency,
+}
+
+/// An installed package as recorded in `packages.json`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Package {
+    pub name: String,
+    /// Full version with epoch and release, e.g. `1:2.3-1`. Empty when
+    /// read from a list of names.
+    #[serde(default)]
+    pub version: String,
+    /// Sync repository the package came from, [`FOREIGN_REPO`], or empty
+    /// when unknown.
+    #[serde(default)]
+    pub repo: String,
+    pub reason: InstallReason,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+}
+
+impl Package {
+    /// An explicitly installed package known only by name.
+    pub fn from_name(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            version: String::new(),
+            repo: String::new(),
+            reason: InstallReason::Explicit,
+            groups: Vec::new(),
+        }
+    }
+
+    pub fn is_foreign(&self) -> bool {
+        self.repo == FOREIGN_REPO
+    }
+}
+
+/// The packages a restore installs; their dependencies come with them.
+pub fn explicit_packages(packages: Vec<Package>) -> impl Iterator<Item = Package> {
+    packages
+        .into_iter()
+        .filter(|p| p.reason == InstallReason::Explicit)
+}
+
+/// Parses `pacman -Qi` output, leaving `repo` empty.
+pub fn parse_query_info(text: &str) -> Vec<Package> {
+    let mut packages = Vec::new();
+    let mut current: Option<Package> = None;
+    for line in text.lines() {
+        // Continuation lines of multi-line values start with whitespace
+        if line.starts_with(char::is_whitespace) {
+            continue;
+        }
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => continue,
+        };
+        match key {
+            "Name" => {
+                packages.extend(current.take());
+                current = Some(Package::from_name(value));
+            }
+            "Version" => {
+                if let Some(package) = current.as_mut() {
+                    package.version = value.to_string();
+                }
+            }
+            "Groups" => {
+                if let Some(package) = current.as_mut() {
+                    package.groups = value
+                        .split_whitespace()
+                        .filter(|group| *group != "None")
+                        .map(str::to_string)
+                        .collect();
+                }
+            }
+            "Install Reason" => {
+                if let Some(package) = current.as_mut() {
+                    package.reason = if value.starts_with("Explicitly") {
+                        InstallReason::Explicit
+                    } else {
+                        InstallReason::Dependency
+                    };
+                }
+            }
+            _ => {}
+        }
+    }
+    packages.extend(current);
+    packages
+}
+
+/// Parses `pacman -Sl` output into the first repository of every package.
+pub fn parse_sync_list(text: &str) -> HashMap<String, String> {
+    let mut repos = HashMap::new();
+    for line in text.lines() {
+        let mut fields = line.split_whitespace();
+        if let (Some(repo), Some(name)) = (fields.next(), fields.next()) {
+            repos
+                .entry(name.to_string())
+                .or_insert_with(|| repo.to_string());
+        }
+    }
+    repos
+}
+
+/// Runs pacman with `args` under the C locale and returns its output.
+fn pacman_output(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("pacman")
+        .args(args)
+        .env("LC_ALL", "C")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()?;
+    if !output.status.success() {
+        let error_message = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(io::Error::new(io::ErrorKind::Other, error_message));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Every installed package with its version, repository, install reason and groups.
+pub fn list_packages() -> io::Result<Vec<Package>> {
+    let mut packages = parse_query_info(&pacman_output(&["-Qi"])?);
+    let repos = parse_sync_list(&pacman_output(&["-Sl"])?);
+    // -Qm exits non-zero when there are no foreign packages
+    let foreign: HashSet<String> = pacman_output(&["-Qmq"])
+        .unwrap_or_default()
+        .lines()
+        .map(|line| line.trim().to_string())
+        .collect();
+    for package in &mut packages {
+        package.repo = if foreign.contains(&package.name) {
+            FOREIGN_REPO.to_string()
+        } else {
+            repos.get(&package.name).cloned().unwrap_or_default()
+        };
+    }
+    Ok(packages)
+}
+
+/// Reads an `appsb` package list: `packages.json`, or the plain list of
+/// names older archives store in `apps.txt`.
+pub fn read_package_list(file_name: &Path, content: &str) -> io::Result<Vec<Package>> {
+    if file_name.file_name() == Some(PACKAGES_FILE.as_ref()) {
+        return serde_json::from_str(content).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid package list {}: {}", file_name.display(), e),
+            )
+        });
+    }
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(Package::from_name)
+        .collect())
+}
+
+/// `CacheDir` from `pacman-conf`, or pacman's default.
+pub fn cache_dir() -> PathBuf {
+    Command::new("pacman-conf")
+        .arg("CacheDir")
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .next()
+                .map(|line| PathBuf::from(line.trim()))
+        })
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CACHE_DIR))
+}
+
+/// Package file of exactly `package.version` in `cache_dir`, e.g.
+/// `bash-5.2.026-2-x86_64.pkg.tar.zst`.
+pub fn find_cached_package(cache_dir: &Path, package: &Package) -> Option<PathBuf> {
+    if package.version.is_empty() {
+        return None;
+    }
+    let prefix = format!("{}-{}-", package.name, package.version);
+    let mut found: Vec<PathBuf> = fs::read_dir(cache_dir)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .filter(|e| {
+            let name = e.file_name().to_string_lossy().to_string();
+            match name.strip_prefix(&prefix) {
+                // What follows the version is the architecture, then the extension
+                Some(rest) => match rest.split_once(".pkg.tar") {
+                    Some((arch, ext)) => !arch.contains('-') && !ext.ends_with(".sig"),
+                    None => false,
+                },
+                None => false,
+            }
+        })
+        .map(|e| e.path())
+        .collect();
+    found.sort();
+    found.pop()
+}
+
+/// AUR helper to build foreign packages with, if one is installed.
+fn aur_helper() -> Option<&'static str> {
+    Some(detect_package_manager()).filter(|pm| *pm != "pacman")
+}
+
+/// Runs pacman as root, through sudo unless this process already is root.
+fn pacman_command() -> Command {
+    let is_root = fs::metadata("/proc/self").is_ok_and(|m| m.uid() == 0);
+    if is_root {
+        Command::new("pacman")
+    } else {
+        let mut command = Command::new("sudo");
+        command.arg("pacman");
+        command
+    }
+}
+
+/// Runs `command` with its output on the terminal.
+fn run(mut command: Command) -> bool {
+    command
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Runs `command` for a single package, returning the last line of its
+/// error output when it fails.
+fn run_single(mut command: Command) -> Result<(), String> {
+    let output = command
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|e| e.to_string())?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Err(stderr
+        .lines()
+        .map(str::trim)
+        .rfind(|line| !line.is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("failed with {}", output.status)))
+}
+
+/// Where each package of a restore is installed from.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct InstallPlan {
+    /// Native package names and their file in the pacman cache.
+    pub cached_native: Vec<(String, PathBuf)>,
+    /// Packages of the sync repositories, installed with pacman.
+    pub native: Vec<String>,
+    /// Foreign package names and their file in the pacman cache.
+    pub cached_foreign: Vec<(String, PathBuf)>,
+    /// AUR and other foreign packages, built with the AUR helper.
+    pub foreign: Vec<String>,
+}
+
+/// Splits `packages` into native and foreign ones. Packages recorded as
+/// foreign stay foreign; the others are native if a sync repository in
+/// `sync_repos` still has them, which also sorts out lists of names. With
+/// a `cache_dir`, packages whose archived version is there are pinned.
+pub fn plan_install(
+    packages: &[Package],
+    sync_repos: &HashMap<String, String>,
+    cache_dir: Option<&Path>,
+) -> InstallPlan {
+    let mut plan = InstallPlan::default();
+    for package in packages {
+        let name = package.name.clone();
+        let native = !package.is_foreign() && sync_repos.contains_key(&package.name);
+        let cached = cache_dir.and_then(|dir| find_cached_package(dir, package));
+        match (cached, native) {
+            (Some(file), true) => plan.cached_native.push((name, file)),
+            (Some(file), false) => plan.cached_foreign.push((name, file)),
+            (None, true) => plan.native.push(name),
+            (None, false) => plan.foreign.push(name),
+        }
+    }
+    plan
+}
+
+/// Packages a restore installed and the ones it could not, with the reason.
+#[derive(Debug, Default)]
+pub struct InstallReport {
+    pub installed: Vec<String>,
+    pub failed: Vec<(String, String)>,
+}
+
+/// Installs `targets`, pairs of a package name and the argument naming it,
+/// in one transaction. If that fails, retries them one by one to find out
+/// which packages failed, since one bad package fails the whole transaction.
+fn install_batch(
+    new_command: &dyn Fn() -> Command,
+    targets: &[(String, OsString)],
+    report: &mut InstallReport,
+) {
+    if targets.is_empty() {
+        return;
+    }
+    let mut command = new_command();
+    command.args(targets.iter().map(|(_, arg)| arg));
+    if run(command) {
+        report
+            .installed
+            .extend(targets.iter().map(|(name, _)| name.clone()));
+        return;
+    }
+
+    println!("Installing the packages one by one to find the failing ones...");
+    for (name, arg) in targets {
+        let mut command = new_command();
+        command.arg(arg);
+        match run_single(command) {
+            Ok(()) => report.installed.push(name.clone()),
+            Err(reason) => report.failed.push((name.clone(), reason)),
+        }
+    }
+}
+
+/// Installs `packages`: repository packages with pacman first, then foreign
+/// ones with the AUR helper. With `pin_versions`, packages whose archived
+/// version is in the pacman cache are installed from there instead of in
+/// their current version, each at the start of its group.
+pub fn install_packages(packages: &[Package], pin_versions: bool) -> InstallReport {
+    let sync_repos = match pacman_output(&["-Sl"]) {
+        Ok(text) => parse_sync_list(&text),
+        Err(e) => {
+            eprintln!(
+                "Warning: could not read the sync repositories: {}",
+                e.to_string().trim()
+            );
+            HashMap::new()
+        }
+    };
+    let cache = if pin_versions {
+        Some(cache_dir())
+    } else {
+        None
+    };
+    let plan = plan_install(packages, &sync_repos, cache.as_deref());
+    if let Some(dir) = &cache {
+        let unpinned = plan.native.len() + plan.foreign.len();
+        if unpinned > 0 {
+            println!(
+                "{} packages are not in {} and get their current version.",
+                unpinned,
+                dir.display()
+            );
+        }
+    }
+
+    let mut report = InstallReport::default();
+    let pacman = |operation: &str| {
+        let mut command = pacman_command();
+        command.arg(operation).arg("--needed").arg("--noconfirm");
+        command
+    };
+    let install_cached = |kind: &str, cached: &[(String, PathBuf)], report: &mut InstallReport| {
+        if cached.is_empty() {
+            return;
+        }
+        println!(
+            "Installing {} {} packages from the package cache...",
+            cached.len(),
+            kind
+        );
+        let targets: Vec<(String, OsString)> = cached
+            .iter()
+            .map(|(name, file)| (name.clone(), file.clone().into_os_string()))
+            .collect();
+        install_batch(&|| pacman("-U"), &targets, report);
+    };
+    install_cached("repository", &plan.cached_native, &mut report);
+    let by_name = |names: &[String]| -> Vec<(String, OsString)> {
+        names
+            .iter()
+            .map(|name| (name.clone(), OsString::from(name)))
+            .collect()
+    };
+    if !plan.native.is_empty() {
+        println!("Installing {} repository packages...", plan.native.len());
+        install_batch(&|| pacman("-S"), &by_name(&plan.native), &mut report);
+    }
+    install_cached("foreign", &plan.cached_foreign, &mut report);
+    if !plan.foreign.is_empty() {
+        match aur_helper() {
+            Some(helper) => {
+                println!(
+                    "Installing {} foreign packages with {}...",
+                    plan.foreign.len(),
+                    helper
+                );
+                let new_command = || {
+                    let mut command = Command::new(helper);
+                    command.arg("-S").arg("--needed").arg("--noconfirm");
+                    command
+                };
+                install_batch(&new_command, &by_name(&plan.foreign), &mut report);
+            }
+            None => report.failed.extend(plan.foreign.iter().map(|name| {
+                (
+                    name.clone(),
+                    "not in the sync repositories and no AUR helper (paru or yay) is installed"
+                        .to_string(),
+                )
+            })),
+        }
+    }
+    report
+}
@@ -0,0 +1,208 @@
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, TimeZone, Utc};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::backup::consolidate::{expand_user_path, PBAR_EXT};
+use crate::cli::{PruneArgs, RetentionArgs};
+use crate::pbar::PbarArchive;
+use crate::repo::store::SNAPSHOTS_DIR;
+
+/// An archive of the pruned directory and why it is kept, if it is.
+#[derive(Debug, Clone)]
+pub struct PruneItem {
+    pub path: PathBuf,
+    pub name: String,
+    pub created: DateTime<Local>,
+    /// Archive name flags, e.g. `i` for an incremental backup.
+    pub flags: String,
+    pub reasons: Vec<String>,
+}
+
+impl PruneItem {
+    pub fn keep(&self) -> bool {
+        !self.reasons.is_empty()
+    }
+}
+
+/// Creation time and flags of an archive named by `consolidate_backups`,
+/// `backup-<%Y-%m-%d-%H-%M-%S>-<flags>.pbar` with the time in UTC.
+pub fn parse_archive_name(name: &str) -> Option<(DateTime<Local>, String)> {
+    let stem = name
+        .strip_prefix("backup-")?
+        .strip_suffix(PBAR_EXT)?
+        .strip_suffix('.')?;
+    let timestamp = stem.get(..19)?;
+    let flags = stem[19..].strip_prefix('-').unwrap_or("");
+    let naive = NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d-%H-%M-%S").ok()?;
+    Some((Utc.from_utc_datetime(&naive).with_timezone(&Local), flags.to_string()))
+}
+
+/// Archives in `dir`, newest first. Files with other names are ignored.
+pub fn find_archives(dir: &Path) -> io::Result<Vec<PruneItem>> {
+    let mut items = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some((created, flags)) = parse_archive_name(&name) {
+            items.push(PruneItem {
+                path: entry.path(),
+                name,
+                created,
+                flags,
+                reasons: Vec::new(),
+            });
+        }
+    }
+    items.sort_by(|a, b| b.created.cmp(&a.created).then_with(|| b.name.cmp(&a.name)));
+    Ok(items)
+}
+
+/// Marks the archives `policy` keeps. `items` must be sorted newest first;
+/// each rule keeps the newest archive of as many distinct periods as it allows.
+pub fn apply_policy(items: &mut [PruneItem], policy: &RetentionArgs) {
+    type Bucket = fn(&DateTime<Local>) -> (i32, u32, u32);
+    let rules: [(&str, usize, Bucket); 4] = [
+        ("last", policy.keep_last, |_| (0, 0, 0)),
+        ("daily", policy.keep_daily, |t| (t.year(), t.month(), t.day())),
+        ("weekly", policy.keep_weekly, |t| {
+            let week = t.iso_week();
+            (week.year(), week.week(), 0)
+        }),
+        ("monthly", policy.keep_monthly, |t| (t.year(), t.month(), 0)),
+    ];
+
+    for (label, limit, bucket) in rules {
+        let mut kept = 0;
+        let mut last = None;
+        for item in items.iter_mut() {
+            if kept == limit {
+                break;
+            }
+            // Every archive is its own period for --keep-last
+            let period = bucket(&item.created);
+            if label == "last" || last != Some(period) {
+                item.reasons.push(label.to_string());
+                kept += 1;
+                last = Some(period);
+            }
+        }
+    }
+}
+
+/// Also keeps every archive that a kept incremental archive takes files from,
+/// so that all kept archives stay restorable.
+pub fn keep_bases(items: &mut [PruneItem], passphrase: Option<&str>) -> io::Result<()> {
+    let index: HashMap<String, usize> = items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| (item.name.clone(), i))
+        .collect();
+    let mut pending: Vec<usize> = (0..items.len()).filter(|&i| items[i].keep()).collect();
+
+    while let Some(i) = pending.pop() {
+        if !items[i].flags.contains('i') {
+            continue;
+        }
+        let read_links = || -> io::Result<Vec<String>> {
+            let mut archive = PbarArchive::open(&items[i].path, passphrase)?;
+            let mut bases: Vec<String> = archive
+                .read_index()?
+                .unwrap_or_default()
+                .files
+                .into_iter()
+                .filter_map(|record| record.stored_in)
+                .collect();
+            bases.extend(archive.manifest.increment.map(|increment| increment.base));
+            Ok(bases)
+        };
+        let mut bases = read_links().map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "Cannot read {} to find the archives it builds on: {}",
+                    items[i].name, e
+                ),
+            )
+        })?;
+        bases.sort();
+        bases.dedup();
+
+        for base in bases {
+            if let Some(&j) = index.get(&base) {
+                if !items[j].keep() {
+                    pending.push(j);
+                }
+                let reason = format!("base of {}", items[i].name);
+                if !items[j].reasons.contains(&reason) {
+                    items[j].reasons.push(reason);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the archives in `dir` that `policy` does not keep, or only
+/// prints the plan for `dry_run`. Returns the deleted paths.
+pub fn prune_archives(
+    dir: &Path,
+    policy: &RetentionArgs,
+    passphrase: Option<&str>,
+    dry_run: bool,
+) -> io::Result<Vec<PathBuf>> {
+    if !policy.is_set() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Refusing to prune without a --keep-last, --keep-daily, --keep-weekly or --keep-monthly policy",
+        ));
+    }
+
+    let mut items = find_archives(dir)?;
+    apply_policy(&mut items, policy);
+    keep_bases(&mut items, passphrase)?;
+
+    if dry_run {
+        println!("Dry run, nothing will be deleted.");
+    }
+    let mut deleted = Vec::new();
+    let mut freed = 0u64;
+    for item in &items {
+        if item.keep() {
+            println!("keep     {} ({})", item.name, item.reasons.join(", "));
+            continue;
+        }
+        println!("delete   {}", item.name);
+        freed += fs::metadata(&item.path).map(|m| m.len()).unwrap_or(0);
+        if !dry_run {
+            fs::remove_file(&item.path)?;
+        }
+        deleted.push(item.path.clone());
+    }
+
+    println!(
+        "{} {} of {} archives ({} bytes), keeping {}.",
+        if dry_run { "Would delete" } else { "Deleted" },
+        deleted.len(),
+        items.len(),
+        freed,
+        items.len() - deleted.len()
+    );
+    Ok(deleted)
+}
+
+pub fn handle_prune(args: &PruneArgs) -> io::Result<()> {
+    let dir = match args.repo.as_deref() {
+        Some(repo) => expand_user_path(repo).join(SNAPSHOTS_DIR),
+        None => expand_user_path(&args.archive_path),
+    };
+    let deleted = prune_archives(&dir, &args.retention, args.decrypt_key.as_deref(), args.dry_run)?;
+    if args.repo.is_some() && !deleted.is_empty() && !args.dry_run {
+        println!("Run `repo gc` to free the chunks of the deleted snapshots.");
+    }
+    Ok(())
+}
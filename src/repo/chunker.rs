@@ -0,0 +1,136 @@
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read};
+
+/// Content-defined chunk size limits. A cut is made where the rolling gear
+/// hash has its top `log2(avg_size)` bits clear, so boundaries follow the
+/// data and an insertion only changes the chunks around it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkerParams {
+    pub min_size: usize,
+    /// Expected chunk size, a power of two.
+    pub avg_size: usize,
+    pub max_size: usize,
+}
+
+impl Default for ChunkerParams {
+    fn default() -> Self {
+        Self {
+            min_size: 512 * 1024,
+            avg_size: 1024 * 1024,
+            max_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+impl ChunkerParams {
+    pub fn validate(&self) -> io::Result<()> {
+        if !self.avg_size.is_power_of_two()
+            || self.min_size == 0
+            || self.min_size > self.avg_size
+            || self.avg_size > self.max_size
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid chunker parameters: {:?}", self),
+            ));
+        }
+        Ok(())
+    }
+
+    fn mask(&self) -> u64 {
+        !0u64 << (64 - self.avg_size.trailing_zeros())
+    }
+
+    /// Length of the first chunk of `data`, which must hold at least
+    /// `max_size` bytes unless it is the end of the stream.
+    pub fn cut_point(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min_size {
+            return data.len();
+        }
+        let end = data.len().min(self.max_size);
+        let mask = self.mask();
+        let mut hash = 0u64;
+        for (i, &byte) in data[..end].iter().enumerate().skip(self.min_size) {
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+/// Splits a stream into content-defined chunks.
+pub struct Chunker<R: Read> {
+    reader: R,
+    params: ChunkerParams,
+    buffer: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Chunker<R> {
+    pub fn new(reader: R, params: ChunkerParams) -> Self {
+        Self {
+            reader,
+            params,
+            buffer: Vec::with_capacity(params.max_size),
+            eof: false,
+        }
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        while !self.eof && self.buffer.len() < self.params.max_size {
+            let start = self.buffer.len();
+            self.buffer.resize(self.params.max_size, 0);
+            match self.reader.read(&mut self.buffer[start..]) {
+                Ok(n) => {
+                    self.buffer.truncate(start + n);
+                    self.eof = n == 0;
+                }
+                Err(e) => {
+                    self.buffer.truncate(start);
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for Chunker<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(e) = self.fill() {
+            return Some(Err(e));
+        }
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let cut = self.params.cut_point(&self.buffer);
+        let rest = self.buffer.split_off(cut);
+        Some(Ok(std::mem::replace(&mut self.buffer, rest)))
+    }
+}
+
+/// Random values for the gear hash, fixed so that chunk boundaries stay the
+/// same across versions.
+static GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state = 0x5041_5243_4842_4b50u64;
+    let mut i = 0;
+    while i < 256 {
+        // splitmix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
@@ -0,0 +1,4 @@
+pub mod chunker;
+pub mod repo;
+pub mod snapshot;
+pub mod store;
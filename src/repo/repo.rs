@@ -0,0 +1,83 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::backup::consolidate::expand_user_path;
+use crate::cli::{RepoArgs, RepoCommand};
+use crate::pbar::PbarArchive;
+use crate::repo::snapshot::load_tree;
+use crate::repo::store::{find_snapshot, list_snapshots, Repository};
+
+pub fn handle_repo(args: &RepoArgs) -> io::Result<()> {
+    match &args.command {
+        RepoCommand::Snapshots { repo } => print_snapshots(&expand_user_path(repo)),
+        RepoCommand::Forget { repo, snapshot } => {
+            let path = find_snapshot(&expand_user_path(repo), Some(snapshot))?;
+            fs::remove_file(&path)?;
+            println!("Removed snapshot {}, run `repo gc` to free its chunks.", snapshot);
+            Ok(())
+        }
+        RepoCommand::Gc { repo, decrypt_key } => {
+            collect_garbage(&expand_user_path(repo), decrypt_key.as_deref())
+        }
+    }
+}
+
+fn print_snapshots(root: &Path) -> io::Result<()> {
+    let snapshots = list_snapshots(root)?;
+    for path in &snapshots {
+        println!(
+            "{:>12} {}",
+            fs::metadata(path)?.len(),
+            path.file_name().unwrap_or_default().to_string_lossy()
+        );
+    }
+    println!("{} snapshots", snapshots.len());
+    Ok(())
+}
+
+/// Deletes the chunks that none of the snapshots refers to. Every snapshot
+/// has to be readable, otherwise nothing is deleted.
+pub fn collect_garbage(root: &Path, passphrase: Option<&str>) -> io::Result<()> {
+    let repo = Repository::open(root, passphrase)?;
+    let _lock = repo.lock()?;
+
+    let mut referenced = HashSet::new();
+    let snapshots = list_snapshots(root)?;
+    for path in &snapshots {
+        let archive = PbarArchive::open(path, passphrase)?;
+        if archive.manifest.repository.as_ref().map(|info| &info.id) != Some(&repo.id) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a snapshot of this repository", path.display()),
+            ));
+        }
+        for entry in load_tree(archive)? {
+            referenced.extend(entry.chunks);
+        }
+    }
+
+    let (chunks, leftovers) = repo.stored_chunks()?;
+    let mut removed = 0usize;
+    let mut freed = 0u64;
+    for chunk in &chunks {
+        if !referenced.contains(&chunk.id) {
+            repo.remove_chunk(&chunk.id)?;
+            removed += 1;
+            freed += chunk.size;
+        }
+    }
+    for path in &leftovers {
+        fs::remove_file(path)?;
+    }
+
+    println!(
+        "Removed {} unreferenced chunks ({} bytes), kept {} chunks of {} snapshots.",
+        removed,
+        freed,
+        chunks.len() - removed,
+        snapshots.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,409 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::time::UNIX_EPOCH;
+
+use crate::backup::consolidate::{BackupComponentMeta, ComponentSource};
+use crate::pbar::manifest::FileRecord;
+use crate::pbar::PbarArchive;
+use crate::repo::chunker::Chunker;
+use crate::repo::store::Repository;
+use crate::utils::compression;
+
+/// Payload entry of a snapshot listing its directory trees.
+pub const TREE_ENTRY: &str = "tree.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    Dir,
+    File,
+}
+
+/// A file or directory of a snapshot. File contents are the concatenation
+/// of `chunks`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TreeEntry {
+    /// Path inside the payload, e.g. `homeb/.bashrc`.
+    pub path: String,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub mtime: u64,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+}
+
+/// Directory trees of a backup stored in a repository.
+pub struct SnapshotTree {
+    pub entries: Vec<TreeEntry>,
+    pub records: Vec<FileRecord>,
+    pub new_chunks: usize,
+    pub reused_chunks: usize,
+    /// Bytes written to the repository for the new chunks.
+    pub new_bytes: u64,
+}
+
+/// Splits the files of the directory components into chunks and stores the
+/// ones the repository does not have yet. The repository itself is left out
+/// of the trees.
+pub fn store_tree(
+    repo: &Repository,
+    components: &[BackupComponentMeta],
+    should_stop: &AtomicBool,
+) -> io::Result<SnapshotTree> {
+    let mut tree = SnapshotTree {
+        entries: Vec::new(),
+        records: Vec::new(),
+        new_chunks: 0,
+        reused_chunks: 0,
+        new_bytes: 0,
+    };
+
+    // Walked trees are absolute, `--repo` may be given relative
+    let repo_root = fs::canonicalize(repo.root())?;
+
+    for meta in components {
+        let (root, exclude_paths) = match &meta.source {
+            ComponentSource::Directory {
+                root,
+                exclude_paths,
+            } => (root, exclude_paths),
+            _ => continue,
+        };
+        let mut exclude_paths = exclude_paths.clone();
+        exclude_paths.push(repo_root.clone());
+
+        for entry in compression::walk_tree(root, &exclude_paths) {
+            compression::check_stop(should_stop)?;
+            let relative = match entry.path().strip_prefix(root) {
+                Ok(p) => p,
+                Err(_) => continue,
+            };
+            let path = Path::new(meta.category)
+                .join(relative)
+                .to_string_lossy()
+                .to_string();
+
+            // Like the archive writer, follow symlinks and skip files that vanished
+            let metadata = match fs::metadata(entry.path()) {
+                Ok(m) => m,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let mode = metadata.permissions().mode() & 0o7777;
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+
+            if metadata.is_dir() {
+                tree.entries.push(TreeEntry {
+                    path,
+                    kind: EntryKind::Dir,
+                    mode,
+                    mtime,
+                    size: 0,
+                    chunks: Vec::new(),
+                });
+                continue;
+            }
+
+            let file = match File::open(entry.path()) {
+                Ok(f) => f,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let mut hasher = Sha256::new();
+            let mut size = 0u64;
+            let mut chunks = Vec::new();
+            for chunk in Chunker::new(file, repo.chunker) {
+                compression::check_stop(should_stop)?;
+                let chunk = chunk?;
+                hasher.update(&chunk);
+                size += chunk.len() as u64;
+                let (id, written) = repo.write_chunk(&chunk)?;
+                if written > 0 {
+                    tree.new_chunks += 1;
+                    tree.new_bytes += written;
+                } else {
+                    tree.reused_chunks += 1;
+                }
+                chunks.push(id);
+            }
+
+            tree.records.push(FileRecord {
+                path: path.clone(),
+                size,
+                mode,
+                mtime,
+                sha256: hex::encode(hasher.finalize()),
+                stored_in: None,
+            });
+            tree.entries.push(TreeEntry {
+                path,
+                kind: EntryKind::File,
+                mode,
+                mtime,
+                size,
+                chunks,
+            });
+        }
+    }
+
+    Ok(tree)
+}
+
+pub fn parse_tree(reader: impl Read) -> io::Result<Vec<TreeEntry>> {
+    serde_json::from_reader(reader).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("Snapshot tree error: {}", e))
+    })
+}
+
+/// Reads the tree of a snapshot archive, empty for a plain archive.
+pub fn load_tree(archive: PbarArchive) -> io::Result<Vec<TreeEntry>> {
+    let mut payload = archive.payload()?;
+    for entry in payload.entries()? {
+        let entry = entry?;
+        if entry.path()? == Path::new(TREE_ENTRY) {
+            return parse_tree(entry);
+        }
+    }
+    Ok(Vec::new())
+}
+
+/// Contents of a snapshot file, read chunk by chunk on demand.
+pub struct FileReader<'a> {
+    repo: &'a Repository,
+    chunks: std::vec::IntoIter<String>,
+    buffer: Vec<u8>,
+    offset: usize,
+}
+
+impl<'a> FileReader<'a> {
+    pub fn new(repo: &'a Repository, chunks: Vec<String>) -> Self {
+        Self {
+            repo,
+            chunks: chunks.into_iter(),
+            buffer: Vec::new(),
+            offset: 0,
+        }
+    }
+}
+
+impl Read for FileReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.offset >= self.buffer.len() {
+            match self.chunks.next() {
+                Some(id) => {
+                    self.buffer = self.repo.read_chunk(&id)?;
+                    self.offset = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.buffer.len() - self.offset);
+        buf[..n].copy_from_slice(&self.buffer[self.offset..self.offset + n]);
+        self.offset += n;
+        Ok(n)
+    }
+}
+
+const BLOCK_SIZE: u64 = 512;
+
+/// A snapshot tree presented as a tar stream, so that it can be listed and
+/// restored like the payload of a plain archive. Seeking forward over file
+/// data skips its chunks without reading them.
+pub struct TreeTar<'a> {
+    repo: &'a Repository,
+    entries: std::vec::IntoIter<TreeEntry>,
+    position: u64,
+    header: Vec<u8>,
+    header_offset: usize,
+    data: Option<FileReader<'a>>,
+    data_left: u64,
+    path: String,
+    padding: u64,
+    finished: bool,
+}
+
+impl<'a> TreeTar<'a> {
+    pub fn new(repo: &'a Repository, entries: Vec<TreeEntry>) -> Self {
+        Self {
+            repo,
+            entries: entries.into_iter(),
+            position: 0,
+            header: Vec::new(),
+            header_offset: 0,
+            data: None,
+            data_left: 0,
+            path: String::new(),
+            padding: 0,
+            finished: false,
+        }
+    }
+
+    /// Queues the headers, data and padding of the next entry, or the end
+    /// of archive marker. Returns false once that has been queued too.
+    fn advance(&mut self) -> bool {
+        if self.finished {
+            return false;
+        }
+        let entry = match self.entries.next() {
+            Some(entry) => entry,
+            None => {
+                self.finished = true;
+                self.padding = 2 * BLOCK_SIZE;
+                return true;
+            }
+        };
+
+        let size = match entry.kind {
+            EntryKind::Dir => 0,
+            EntryKind::File => entry.size,
+        };
+        self.header = entry_header(&entry);
+        self.header_offset = 0;
+        self.data_left = size;
+        self.padding = (BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE;
+        self.data = Some(FileReader::new(self.repo, entry.chunks));
+        self.path = entry.path;
+        true
+    }
+}
+
+fn short_file(path: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Snapshot data of {} is shorter than recorded", path),
+    )
+}
+
+impl Read for TreeTar<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let n = if self.header_offset < self.header.len() {
+                let n = buf.len().min(self.header.len() - self.header_offset);
+                buf[..n].copy_from_slice(&self.header[self.header_offset..self.header_offset + n]);
+                self.header_offset += n;
+                n
+            } else if self.data_left > 0 {
+                let limit = buf.len().min(self.data_left.min(usize::MAX as u64) as usize);
+                let reader = self.data.as_mut().ok_or_else(|| short_file(&self.path))?;
+                let n = reader.read(&mut buf[..limit])?;
+                if n == 0 {
+                    return Err(short_file(&self.path));
+                }
+                self.data_left -= n as u64;
+                n
+            } else if self.padding > 0 {
+                let n = buf.len().min(self.padding.min(usize::MAX as u64) as usize);
+                buf[..n].fill(0);
+                self.padding -= n as u64;
+                n
+            } else if self.advance() {
+                continue;
+            } else {
+                return Ok(0);
+            };
+            self.position += n as u64;
+            return Ok(n);
+        }
+    }
+}
+
+impl Seek for TreeTar<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let skip = match pos {
+            SeekFrom::Current(n) if n >= 0 => n as u64,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Snapshot trees can only be skipped forward",
+                ))
+            }
+        };
+        let mut amount = skip;
+
+        let n = amount.min((self.header.len() - self.header_offset) as u64);
+        self.header_offset += n as usize;
+        amount -= n;
+
+        if amount >= self.data_left {
+            self.data = None;
+            amount -= self.data_left;
+            self.data_left = 0;
+        } else if amount > 0 {
+            let reader = self.data.as_mut().ok_or_else(|| short_file(&self.path))?;
+            let skipped = io::copy(&mut reader.take(amount), &mut io::sink())?;
+            if skipped < amount {
+                return Err(short_file(&self.path));
+            }
+            self.data_left -= amount;
+            amount = 0;
+        }
+
+        let n = amount.min(self.padding);
+        self.padding -= n;
+        amount -= n;
+        if amount > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek past the end of a snapshot entry",
+            ));
+        }
+
+        self.position += skip;
+        Ok(self.position)
+    }
+}
+
+/// Tar header blocks for `entry`, preceded by a GNU long name entry when the
+/// path does not fit the header. Paths are copied verbatim; restore checks them.
+fn entry_header(entry: &TreeEntry) -> Vec<u8> {
+    let name = entry.path.as_bytes();
+    let mut out = Vec::new();
+
+    if name.len() > 100 {
+        let mut long = tar::Header::new_gnu();
+        long.as_gnu_mut().unwrap().name[..13].copy_from_slice(b"././@LongLink");
+        long.set_entry_type(tar::EntryType::GNULongName);
+        long.set_mode(0o644);
+        long.set_size(name.len() as u64 + 1);
+        long.set_cksum();
+        out.extend_from_slice(long.as_bytes());
+        out.extend_from_slice(name);
+        out.push(0);
+        let padding = (BLOCK_SIZE - (name.len() as u64 + 1) % BLOCK_SIZE) % BLOCK_SIZE;
+        out.resize(out.len() + padding as usize, 0);
+    }
+
+    let mut header = tar::Header::new_gnu();
+    let len = name.len().min(100);
+    header.as_gnu_mut().unwrap().name[..len].copy_from_slice(&name[..len]);
+    match entry.kind {
+        EntryKind::Dir => {
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_size(0);
+        }
+        EntryKind::File => {
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_size(entry.size);
+        }
+    }
+    header.set_mode(entry.mode);
+    header.set_mtime(entry.mtime);
+    header.set_cksum();
+    out.extend_from_slice(header.as_bytes());
+    out
+}
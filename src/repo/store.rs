@@ -0,0 +1,397 @@
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::backup::consolidate::PBAR_EXT;
+use crate::pbar::header::PbarHeader;
+use crate::pbar::stream::{open_manifest, seal_manifest, CipherKind, KdfParams};
+use crate::pbar::{PbarChunkReader, PbarChunkWriter};
+use crate::repo::chunker::ChunkerParams;
+
+pub const CONFIG_FILE: &str = "config.pbar";
+pub const CHUNKS_DIR: &str = "chunks";
+pub const SNAPSHOTS_DIR: &str = "snapshots";
+pub const LOCK_FILE: &str = "lock";
+
+const REPO_VERSION: u32 = 1;
+const CHUNK_ZSTD_LEVEL: i32 = 3;
+const TMP_EXT: &str = "tmp";
+
+/// Settings stored in `config.pbar`, sealed like an archive manifest when
+/// the repository is encrypted.
+#[derive(Debug, Serialize, Deserialize)]
+struct RepoConfig {
+    version: u32,
+    id: String,
+    chunker: ChunkerParams,
+}
+
+/// Keys derived from the repository passphrase.
+#[derive(Clone)]
+struct RepoKeys {
+    /// Encrypts chunk files.
+    chunk: [u8; 32],
+    /// Keys the chunk ids, so they do not reveal the hash of the contents.
+    id: [u8; 32],
+}
+
+/// A directory of content-addressed chunks shared by the snapshots in it:
+///
+/// ```text
+/// config.pbar              PBAR header + repository settings
+/// chunks/<ab>/<id>         12-byte nonce + PBAR chunk stream of zstd data
+/// snapshots/<name>.pbar    PBAR archives whose tree refers to chunks by id
+/// ```
+#[derive(Clone)]
+pub struct Repository {
+    root: PathBuf,
+    pub id: String,
+    pub chunker: ChunkerParams,
+    cipher: CipherKind,
+    keys: Option<RepoKeys>,
+}
+
+/// A chunk file found in the repository.
+pub struct StoredChunk {
+    pub id: String,
+    /// Size on disk.
+    pub size: u64,
+}
+
+/// Held while a backup or garbage collection changes the repository.
+pub struct RepoLock {
+    path: PathBuf,
+}
+
+impl Drop for RepoLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Repository {
+    pub fn exists(root: &Path) -> bool {
+        root.join(CONFIG_FILE).is_file()
+    }
+
+    /// Creates an empty repository in `root`, encrypted when `passphrase` is given.
+    pub fn init(
+        root: &Path,
+        passphrase: Option<&str>,
+        cipher: CipherKind,
+        kdf_params: KdfParams,
+        chunker: ChunkerParams,
+    ) -> io::Result<Self> {
+        if Self::exists(root) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already holds a repository", root.display()),
+            ));
+        }
+        chunker.validate()?;
+        fs::create_dir_all(root.join(CHUNKS_DIR))?;
+        fs::create_dir_all(root.join(SNAPSHOTS_DIR))?;
+
+        let mut id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut id);
+        let config = RepoConfig {
+            version: REPO_VERSION,
+            id: hex::encode(id),
+            chunker,
+        };
+        let json = serde_json::to_vec_pretty(&config).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Repository config error: {}", e))
+        })?;
+
+        let mut header = PbarHeader::new(passphrase.is_some(), false, 0);
+        let (block, master_key) = match passphrase {
+            Some(pass) => {
+                header.feature_flags |= cipher.header_flag();
+                header.set_kdf_params(kdf_params);
+                let key = header.derive_key(pass.as_bytes())?;
+                (seal_manifest(&json, &key, cipher, &header.base_nonce)?, Some(key))
+            }
+            None => (json, None),
+        };
+        header.manifest_size = block.len() as u32;
+
+        let mut file = File::create(root.join(CONFIG_FILE))?;
+        header.write_to(&mut file)?;
+        file.write_all(&block)?;
+        file.sync_all()?;
+
+        println!("Created repository {} in {}", config.id, root.display());
+        Ok(Self::from_config(root, config, cipher, master_key))
+    }
+
+    /// Opens the repository in `root`, checking `passphrase` against its config.
+    pub fn open(root: &Path, passphrase: Option<&str>) -> io::Result<Self> {
+        let mut file = File::open(root.join(CONFIG_FILE)).map_err(|e| {
+            if e.kind() == io::ErrorKind::NotFound {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No repository found in {}", root.display()),
+                )
+            } else {
+                e
+            }
+        })?;
+        let header = PbarHeader::read_from(&mut file)?;
+        let block = header.read_manifest_block(&mut file)?;
+
+        let cipher = CipherKind::from_flags(header.feature_flags);
+        let master_key = match (header.is_encrypted(), passphrase) {
+            (false, _) => None,
+            (true, Some(pass)) => Some(header.derive_key(pass.as_bytes())?),
+            (true, None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Repository is encrypted but no key was given",
+                ))
+            }
+        };
+        let json = match &master_key {
+            Some(key) => open_manifest(&block, key, cipher, &header.base_nonce)?,
+            None => block,
+        };
+        let config: RepoConfig = serde_json::from_slice(&json).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Repository config error: {}", e))
+        })?;
+        if config.version != REPO_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported repository version {}", config.version),
+            ));
+        }
+        config.chunker.validate()?;
+
+        Ok(Self::from_config(root, config, cipher, master_key))
+    }
+
+    fn from_config(
+        root: &Path,
+        config: RepoConfig,
+        cipher: CipherKind,
+        master_key: Option<[u8; 32]>,
+    ) -> Self {
+        let keys = master_key.map(|key| RepoKeys {
+            chunk: expand_key(&key, b"parch-backup repo chunk key"),
+            id: expand_key(&key, b"parch-backup repo id key"),
+        });
+        Self {
+            root: root.to_path_buf(),
+            id: config.id,
+            chunker: config.chunker,
+            cipher,
+            keys,
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.keys.is_some()
+    }
+
+    pub fn snapshots_dir(&self) -> PathBuf {
+        self.root.join(SNAPSHOTS_DIR)
+    }
+
+    /// Takes the repository lock, failing when another process holds it.
+    pub fn lock(&self) -> io::Result<RepoLock> {
+        let path = self.root.join(LOCK_FILE);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                writeln!(file, "{}", std::process::id())?;
+                Ok(RepoLock { path })
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!(
+                    "Repository is in use by another backup (remove {} if it is stale)",
+                    path.display()
+                ),
+            )),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Hex id of a chunk: HMAC-SHA256 under the id key when encrypted, plain SHA-256 otherwise.
+    pub fn chunk_id(&self, data: &[u8]) -> String {
+        match &self.keys {
+            Some(keys) => {
+                let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&keys.id)
+                    .expect("HMAC accepts keys of any length");
+                mac.update(data);
+                hex::encode(mac.finalize().into_bytes())
+            }
+            None => hex::encode(Sha256::digest(data)),
+        }
+    }
+
+    fn chunk_path(&self, id: &str) -> io::Result<PathBuf> {
+        if id.len() != 64 || !id.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid chunk id: {:?}", id),
+            ));
+        }
+        Ok(self.root.join(CHUNKS_DIR).join(&id[..2]).join(id))
+    }
+
+    /// Stores `data` unless a chunk with the same id exists. Returns the id
+    /// and the number of bytes written, zero for a reused chunk.
+    pub fn write_chunk(&self, data: &[u8]) -> io::Result<(String, u64)> {
+        let id = self.chunk_id(data);
+        let path = self.chunk_path(&id)?;
+        if path.exists() {
+            return Ok((id, 0));
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let compressed = zstd::encode_all(data, CHUNK_ZSTD_LEVEL)?;
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let tmp_path = path.with_extension(TMP_EXT);
+        let write = || -> io::Result<u64> {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&nonce)?;
+            let key = self.keys.as_ref().map(|k| k.chunk);
+            let mut writer = PbarChunkWriter::with_cipher(file, key, self.cipher, nonce);
+            writer.write_all(&compressed)?;
+            let file = writer.finish()?;
+            file.sync_all()?;
+            let written = file.metadata()?.len();
+            fs::rename(&tmp_path, &path)?;
+            Ok(written)
+        };
+        match write() {
+            Ok(written) => Ok((id, written)),
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_path);
+                Err(e)
+            }
+        }
+    }
+
+    /// Reads chunk `id` back and checks that its contents still match the id.
+    pub fn read_chunk(&self, id: &str) -> io::Result<Vec<u8>> {
+        let path = self.chunk_path(id)?;
+        let mut file = File::open(&path).map_err(|e| {
+            if e.kind() == io::ErrorKind::NotFound {
+                io::Error::new(io::ErrorKind::NotFound, format!("Chunk {} is missing", id))
+            } else {
+                e
+            }
+        })?;
+        let mut nonce = [0u8; 12];
+        file.read_exact(&mut nonce)?;
+        let key = self.keys.as_ref().map(|k| k.chunk);
+        let reader = PbarChunkReader::with_cipher(file, key, self.cipher, nonce);
+
+        let data = zstd::decode_all(reader)?;
+        if self.chunk_id(&data) != id {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Chunk {} is corrupted", id),
+            ));
+        }
+        Ok(data)
+    }
+
+    /// All stored chunks. Leftover temporary files of interrupted writes are
+    /// returned separately.
+    pub fn stored_chunks(&self) -> io::Result<(Vec<StoredChunk>, Vec<PathBuf>)> {
+        let mut chunks = Vec::new();
+        let mut leftovers = Vec::new();
+        for dir in fs::read_dir(self.root.join(CHUNKS_DIR))? {
+            let dir = dir?;
+            if !dir.file_type()?.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(dir.path())? {
+                let file = file?;
+                let path = file.path();
+                if path.extension().is_some_and(|ext| ext == TMP_EXT) {
+                    leftovers.push(path);
+                } else {
+                    chunks.push(StoredChunk {
+                        id: file.file_name().to_string_lossy().to_string(),
+                        size: file.metadata()?.len(),
+                    });
+                }
+            }
+        }
+        Ok((chunks, leftovers))
+    }
+
+    pub fn remove_chunk(&self, id: &str) -> io::Result<()> {
+        fs::remove_file(self.chunk_path(id)?)
+    }
+}
+
+fn expand_key(master_key: &[u8; 32], label: &[u8]) -> [u8; 32] {
+    let hk = Hkdf::<Sha256>::new(None, master_key);
+    let mut okm = [0u8; 32];
+    hk.expand(label, &mut okm)
+        .expect("32 bytes is a valid length for HKDF output");
+    okm
+}
+
+/// Snapshot archives in the repository at `root`, oldest first.
+pub fn list_snapshots(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let dir = root.join(SNAPSHOTS_DIR);
+    if !Repository::exists(root) || !dir.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No repository found in {}", root.display()),
+        ));
+    }
+    let mut snapshots: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file() && p.extension().is_some_and(|ext| ext == PBAR_EXT))
+        .collect();
+    snapshots.sort();
+    Ok(snapshots)
+}
+
+/// Path of snapshot `name` in the repository at `root`, or of the newest
+/// snapshot when no name is given.
+pub fn find_snapshot(root: &Path, name: Option<&str>) -> io::Result<PathBuf> {
+    let name = match name {
+        Some(name) => name,
+        None => {
+            return list_snapshots(root)?.pop().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Repository {} has no snapshots", root.display()),
+                )
+            })
+        }
+    };
+    if Path::new(name).file_name() != Some(name.as_ref()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid snapshot name: {}", name),
+        ));
+    }
+    let path = root.join(SNAPSHOTS_DIR).join(name);
+    if !path.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Snapshot {} not found in {}", name, root.display()),
+        ));
+    }
+    Ok(path)
+}
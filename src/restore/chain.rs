@@ -0,0 +1,156 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::pbar::component::Component;
+use crate::pbar::index::ArchiveIndex;
+use crate::pbar::manifest::PbarManifest;
+use crate::pbar::PbarArchive;
+use crate::restore::selection::Selection;
+
+/// Payload entries to take from one archive of a restore.
+pub enum PayloadPaths {
+    All,
+    Only(HashSet<String>),
+}
+
+impl PayloadPaths {
+    /// Whether the payload entry at `entry_path` is taken.
+    pub fn wants(&self, entry_path: &Path) -> bool {
+        match self {
+            PayloadPaths::All => true,
+            PayloadPaths::Only(paths) => entry_path.to_str().is_some_and(|p| paths.contains(p)),
+        }
+    }
+
+    /// Like [`PayloadPaths::wants`], for a file listed by `walk_archive` at
+    /// `home_path` relative to the home directory.
+    pub fn wants_file(&self, component: Component, home_path: &Path) -> bool {
+        if let PayloadPaths::All = self {
+            return true;
+        }
+        let inner = match component.home_prefix() {
+            Some(prefix) => match home_path.strip_prefix(prefix) {
+                Ok(inner) => inner,
+                Err(_) => return false,
+            },
+            None => return false,
+        };
+        self.wants(&Path::new(component.dir()).join(inner))
+    }
+}
+
+/// An archive taking part in a restore.
+pub struct RestoreSource {
+    pub archive: PbarArchive,
+    pub paths: PayloadPaths,
+}
+
+/// Archives needed to restore `archive`, oldest first. An incremental backup
+/// pulls its unchanged files from the earlier archives of its chain, which
+/// must sit next to it; each of them is checked with `authenticate` and then
+/// read through the same handle, so its path cannot be pointed elsewhere.
+pub fn resolve_chain(
+    archive_path: &Path,
+    mut archive: PbarArchive,
+    passphrase: Option<&str>,
+    authenticate: &dyn Fn(&mut File) -> io::Result<()>,
+) -> io::Result<Vec<RestoreSource>> {
+    let index = increment_index(&mut archive)?;
+    let mut earlier: BTreeMap<String, HashSet<String>> = BTreeMap::new();
+    for record in &index.files {
+        if let Some(name) = &record.stored_in {
+            earlier
+                .entry(name.clone())
+                .or_default()
+                .insert(record.path.clone());
+        }
+    }
+
+    if let Some(increment) = &archive.manifest.increment {
+        println!(
+            "Incremental backup on top of {}, {} files deleted since.",
+            increment.base,
+            index.deleted.len()
+        );
+    }
+
+    let dir = match archive_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let mut sources = Vec::new();
+    for (name, paths) in earlier {
+        if Path::new(&name).file_name() != Some(name.as_ref()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid archive name in incremental chain: {}", name),
+            ));
+        }
+        let path = dir.join(&name);
+        if !path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "Archive {} holding files of this incremental backup was not found in {}",
+                    name,
+                    dir.display()
+                ),
+            ));
+        }
+        let mut file = File::open(&path)?;
+        authenticate(&mut file)?;
+        println!("Taking {} unchanged files from {}", paths.len(), name);
+        sources.push(RestoreSource {
+            archive: PbarArchive::from_file(file, &path, passphrase)?,
+            paths: PayloadPaths::Only(paths),
+        });
+    }
+
+    sources.push(RestoreSource {
+        archive,
+        paths: PayloadPaths::All,
+    });
+    Ok(sources)
+}
+
+/// File index of an incremental backup, empty for a full one. Only an
+/// increment leaves files in other archives or records deleted ones.
+fn increment_index(archive: &mut PbarArchive) -> io::Result<ArchiveIndex> {
+    if archive.manifest.increment.is_none() {
+        return Ok(ArchiveIndex::default());
+    }
+    archive.read_index()?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Incremental backup has no file index",
+        )
+    })
+}
+
+/// Files the incremental backup `archive` records as deleted since its
+/// base, as home relative paths, limited to what `selection` restores.
+pub fn deleted_files(archive: &mut PbarArchive, selection: &Selection) -> io::Result<Vec<PathBuf>> {
+    let deleted = increment_index(archive)?.deleted;
+    Ok(deleted
+        .iter()
+        .filter_map(|payload_path| {
+            let payload_path = Path::new(payload_path);
+            let component = Component::of_entry(payload_path)?;
+            let prefix = component.home_prefix()?;
+            let path = prefix.join(payload_path.iter().skip(1).collect::<PathBuf>());
+            (selection.wants_component(component) && selection.filter.matches(&path))
+                .then_some(path)
+        })
+        .collect())
+}
+
+/// Creation time of the backup of `manifest` in seconds since the epoch, 0
+/// when it cannot be read.
+pub fn backup_time(manifest: &PbarManifest) -> u64 {
+    chrono::DateTime::parse_from_rfc3339(&manifest.created_at)
+        .map(|t| t.timestamp().max(0) as u64)
+        .unwrap_or(0)
+}
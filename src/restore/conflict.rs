@@ -0,0 +1,245 @@
+use dialoguer::Select;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Suffix appended to restored copies under `rename-with-suffix`.
+const RESTORED_SUFFIX: &str = "pbar-restored";
+
+/// What to do when a restored file already exists on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ConflictPolicy {
+    /// Replace the existing file
+    #[default]
+    Overwrite,
+    /// Leave the existing file alone
+    Skip,
+    /// Replace the existing file only if the archived copy is newer
+    KeepNewer,
+    /// Write the archived copy next to the existing file as `<name>.pbar-restored`
+    RenameWithSuffix,
+    /// Prompt for each conflict
+    Ask,
+}
+
+/// Per-outcome file counts for a restore.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConflictStats {
+    pub created: usize,
+    pub overwritten: usize,
+    pub skipped: usize,
+    pub kept_newer: usize,
+    pub renamed: usize,
+    /// Files deleted before an incremental backup and removed from disk.
+    pub removed: usize,
+}
+
+impl fmt::Display for ConflictStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} created, {} overwritten, {} skipped, {} kept (newer on disk), {} renamed, {} removed",
+            self.created,
+            self.overwritten,
+            self.skipped,
+            self.kept_newer,
+            self.renamed,
+            self.removed
+        )
+    }
+}
+
+/// Applies a `ConflictPolicy` to each file a restore is about to write.
+pub struct ConflictResolver {
+    policy: ConflictPolicy,
+    stats: ConflictStats,
+}
+
+impl ConflictResolver {
+    pub fn new(policy: ConflictPolicy) -> Self {
+        Self {
+            policy,
+            stats: ConflictStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &ConflictStats {
+        &self.stats
+    }
+
+    /// Decides where an archive entry bound for `dest` is written, or `None`
+    /// to leave what is on disk untouched. Directories always pass through.
+    pub fn resolve(&mut self, dest: &Path, header: &tar::Header) -> io::Result<Option<PathBuf>> {
+        if header.entry_type().is_dir() {
+            return Ok(Some(dest.to_path_buf()));
+        }
+
+        let mtime = header.mtime().unwrap_or(0);
+        let mut outcome = plan(self.policy, dest, mtime)?;
+        if outcome == Outcome::Ask {
+            let policy = self.ask(format!("{} already exists", dest.display()))?;
+            outcome = plan(policy, dest, mtime)?;
+        }
+
+        Ok(match outcome {
+            Outcome::Create => {
+                self.stats.created += 1;
+                Some(dest.to_path_buf())
+            }
+            Outcome::Overwrite => {
+                self.stats.overwritten += 1;
+                Some(dest.to_path_buf())
+            }
+            Outcome::Skip => {
+                self.stats.skipped += 1;
+                None
+            }
+            Outcome::KeepNewer => {
+                self.stats.kept_newer += 1;
+                None
+            }
+            Outcome::Rename(path) => {
+                self.stats.renamed += 1;
+                Some(path)
+            }
+            Outcome::Ask => unreachable!("ask resolves to a concrete policy"),
+        })
+    }
+
+    /// Decides whether `path`, a file deleted before an incremental backup
+    /// taken at `mtime`, is removed from disk. `overwrite` removes it,
+    /// `keep-newer` only when it was not changed after the backup, and the
+    /// other policies leave it alone.
+    pub fn resolve_removal(&mut self, path: &Path, mtime: u64) -> io::Result<bool> {
+        let mut outcome = plan(self.policy, path, mtime)?;
+        if outcome == Outcome::Ask {
+            let policy = self.ask(format!(
+                "{} was deleted before the backup, overwrite removes it",
+                path.display()
+            ))?;
+            outcome = plan(policy, path, mtime)?;
+        }
+
+        Ok(match outcome {
+            Outcome::Create => false,
+            Outcome::Overwrite => {
+                self.stats.removed += 1;
+                true
+            }
+            Outcome::KeepNewer => {
+                self.stats.kept_newer += 1;
+                false
+            }
+            Outcome::Skip | Outcome::Rename(_) => {
+                self.stats.skipped += 1;
+                false
+            }
+            Outcome::Ask => unreachable!("ask resolves to a concrete policy"),
+        })
+    }
+
+    /// Prompts for one conflict. The "all" answers replace the policy for the
+    /// rest of the restore.
+    fn ask(&mut self, prompt: String) -> io::Result<ConflictPolicy> {
+        let choices = [
+            "Overwrite",
+            "Skip",
+            "Keep newer",
+            "Rename with suffix",
+            "Overwrite all",
+            "Skip all",
+        ];
+        let choice = Select::new()
+            .with_prompt(prompt)
+            .items(&choices)
+            .default(1)
+            .interact()
+            .map_err(|dialoguer::Error::IO(e)| {
+                io::Error::new(e.kind(), format!("Cannot ask about restore conflicts: {}", e))
+            })?;
+
+        Ok(match choice {
+            0 => ConflictPolicy::Overwrite,
+            2 => ConflictPolicy::KeepNewer,
+            3 => ConflictPolicy::RenameWithSuffix,
+            4 => {
+                self.policy = ConflictPolicy::Overwrite;
+                ConflictPolicy::Overwrite
+            }
+            5 => {
+                self.policy = ConflictPolicy::Skip;
+                ConflictPolicy::Skip
+            }
+            _ => ConflictPolicy::Skip,
+        })
+    }
+}
+
+/// What restoring a file with a given mtime to `dest` would do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Create,
+    Overwrite,
+    Skip,
+    KeepNewer,
+    Rename(PathBuf),
+    /// The policy is `ask` and the file exists.
+    Ask,
+}
+
+impl Outcome {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Outcome::Create => "create",
+            Outcome::Overwrite => "overwrite",
+            Outcome::Skip => "skip",
+            Outcome::KeepNewer => "keep",
+            Outcome::Rename(_) => "rename",
+            Outcome::Ask => "ask",
+        }
+    }
+}
+
+/// Works out the outcome of restoring a file to `dest` under `policy`
+/// without touching the filesystem or prompting.
+pub fn plan(policy: ConflictPolicy, dest: &Path, mtime: u64) -> io::Result<Outcome> {
+    let existing = match fs::symlink_metadata(dest) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Outcome::Create),
+        Err(e) => return Err(e),
+    };
+
+    Ok(match policy {
+        ConflictPolicy::Overwrite => Outcome::Overwrite,
+        ConflictPolicy::Skip => Outcome::Skip,
+        ConflictPolicy::KeepNewer => {
+            let on_disk = existing
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            if on_disk > mtime {
+                Outcome::KeepNewer
+            } else {
+                Outcome::Overwrite
+            }
+        }
+        ConflictPolicy::RenameWithSuffix => Outcome::Rename(free_suffixed_path(dest)),
+        ConflictPolicy::Ask => Outcome::Ask,
+    })
+}
+
+/// First of `<dest>.pbar-restored`, `<dest>.pbar-restored.1`, ... that does not exist yet.
+fn free_suffixed_path(dest: &Path) -> PathBuf {
+    let base = dest.as_os_str().to_string_lossy();
+    let mut candidate = PathBuf::from(format!("{}.{}", base, RESTORED_SUFFIX));
+    let mut n = 1;
+    while fs::symlink_metadata(&candidate).is_ok() {
+        candidate = PathBuf::from(format!("{}.{}.{}", base, RESTORED_SUFFIX, n));
+        n += 1;
+    }
+    candidate
+}
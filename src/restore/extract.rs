@@ -0,0 +1,134 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+fn unsafe_entry(path: &Path, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Refusing archive entry {}: {}", path.display(), reason),
+    )
+}
+
+/// Joins archive member path `member` onto `base`, refusing absolute paths
+/// and `..` components.
+pub fn safe_join(base: &Path, member: &Path) -> io::Result<PathBuf> {
+    let mut joined = base.to_path_buf();
+    for component in member.components() {
+        match component {
+            Component::Normal(part) => joined.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => return Err(unsafe_entry(member, "path contains '..'")),
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(unsafe_entry(member, "path is absolute"))
+            }
+        }
+    }
+    Ok(joined)
+}
+
+/// Refuses `path` when one of its existing parent directories below `root`
+/// is a symlink, which an archive could have planted to redirect writes.
+pub fn ensure_no_symlink_parents(root: &Path, path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) => parent,
+        None => return Ok(()),
+    };
+    let relative = parent
+        .strip_prefix(root)
+        .map_err(|_| unsafe_entry(path, "destination is outside the restore root"))?;
+
+    let mut current = root.to_path_buf();
+    for component in relative.components() {
+        current.push(component);
+        match fs::symlink_metadata(&current) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                return Err(unsafe_entry(
+                    path,
+                    &format!("parent {} is a symlink", current.display()),
+                ))
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Writes `entry` to `dest`. `root` is the restore root that nothing may be
+/// written outside of, and `base` the directory that hard link targets in
+/// this tarball are relative to.
+///
+/// Unlike `tar::Entry::unpack`, this never writes through symlinks, resolves
+/// hard links inside `base`, and refuses device nodes and FIFOs.
+pub fn unpack_entry<R: Read>(
+    entry: &mut tar::Entry<R>,
+    root: &Path,
+    base: &Path,
+    dest: &Path,
+) -> io::Result<()> {
+    ensure_no_symlink_parents(root, dest)?;
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let kind = entry.header().entry_type();
+    match kind {
+        tar::EntryType::Directory => {
+            // tar would chmod through an existing symlink here
+            if let Ok(meta) = fs::symlink_metadata(dest) {
+                if meta.file_type().is_symlink() {
+                    return Err(unsafe_entry(dest, "directory would replace a symlink"));
+                }
+            }
+            entry.unpack(dest)?;
+        }
+        tar::EntryType::Link => {
+            let link_name = entry
+                .link_name()?
+                .ok_or_else(|| unsafe_entry(dest, "hard link without a target"))?
+                .into_owned();
+            let source = safe_join(base, &link_name)?;
+            ensure_no_symlink_parents(root, &source)?;
+            if fs::symlink_metadata(&source)?.file_type().is_symlink() {
+                return Err(unsafe_entry(dest, "hard link target is a symlink"));
+            }
+            remove_existing_file(dest)?;
+            fs::hard_link(&source, dest)?;
+        }
+        tar::EntryType::Regular
+        | tar::EntryType::Continuous
+        | tar::EntryType::GNUSparse
+        | tar::EntryType::Symlink => {
+            entry.unpack(dest)?;
+        }
+        other => {
+            return Err(unsafe_entry(
+                dest,
+                &format!("unsupported entry type {:?}", other),
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// Writes `contents` to a fresh file at `dest` under `root`, replacing any
+/// existing file or symlink instead of writing through it.
+pub fn write_new_file(root: &Path, dest: &Path, contents: &[u8]) -> io::Result<()> {
+    ensure_no_symlink_parents(root, dest)?;
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    remove_existing_file(dest)?;
+    let mut file = OpenOptions::new().write(true).create_new(true).open(dest)?;
+    file.write_all(contents)
+}
+
+fn remove_existing_file(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => Err(unsafe_entry(path, "a directory is in the way")),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
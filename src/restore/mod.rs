@@ -1 +1,6 @@
+pub mod chain;
+pub mod conflict;
+pub mod extract;
+pub mod plan;
 pub mod restore;
+pub mod selection;
@@ -0,0 +1,183 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crossbeam_channel::Sender;
+
+use crate::events::ProgressEvent;
+use crate::flatpak::flatpak;
+use crate::list::list::walk_archive;
+use crate::pbar::component::Component;
+use crate::pm::packages;
+use crate::pm::paru;
+use crate::restore::chain::{backup_time, deleted_files, RestoreSource};
+use crate::restore::conflict::{plan, ConflictPolicy, Outcome};
+use crate::restore::extract::safe_join;
+use crate::restore::selection::Selection;
+
+/// Options of a dry-run restore that affect the plan.
+pub struct PlanOptions<'a> {
+    pub selection: &'a Selection,
+    pub policy: ConflictPolicy,
+    /// Directory standing in for `$HOME`.
+    pub restore_root: &'a Path,
+    pub install_packages: bool,
+    /// Whether files deleted since the base of an increment are removed.
+    pub delete: bool,
+}
+
+/// Walks the archives and reports what a restore with `options` would do,
+/// without writing anything.
+pub fn plan_restore(
+    mut sources: Vec<RestoreSource>,
+    options: &PlanOptions,
+    tx: Option<&Sender<ProgressEvent>>,
+) -> io::Result<()> {
+    let report = |action: &str, target: String| {
+        println!("{:<10} {}", action, target);
+        if let Some(sender) = tx {
+            let _ = sender.send(ProgressEvent::PlannedChange {
+                action: action.to_string(),
+                target,
+            });
+        }
+    };
+
+    println!("Dry run, nothing will be written.");
+
+    let selection = options.selection;
+    let mut counts: BTreeMap<&'static str, usize> = BTreeMap::new();
+    let mut package_names = Vec::new();
+    let mut flatpaks = Vec::new();
+    let (deleted, deleted_before) = match sources.last_mut() {
+        Some(top) => (
+            deleted_files(&mut top.archive, selection)?,
+            backup_time(&top.archive.manifest),
+        ),
+        None => (Vec::new(), 0),
+    };
+
+    for RestoreSource { archive, paths } in sources {
+        walk_archive(archive, |entry, data| {
+            if !selection.wants_component(entry.component) {
+                return Ok(());
+            }
+            if !paths.wants_file(entry.component, &entry.path) {
+                return Ok(());
+            }
+
+            if entry.component.home_prefix().is_none() {
+                if selection.wants_package_list(entry.component) {
+                    let mut content = String::new();
+                    data.read_to_string(&mut content)?;
+                    match entry.component {
+                        Component::Apps => {
+                            let list = packages::read_package_list(&entry.path, &content)?;
+                            package_names.extend(packages::explicit_packages(list).map(|p| p.name));
+                        }
+                        _ => flatpaks.extend(
+                            content
+                                .lines()
+                                .map(|line| line.trim().to_string())
+                                .filter(|l| !l.is_empty()),
+                        ),
+                    }
+                }
+                return Ok(());
+            }
+
+            if entry.entry_type.is_dir() || !selection.filter.matches(&entry.path) {
+                return Ok(());
+            }
+
+            let dest = safe_join(options.restore_root, &entry.path)?;
+            let outcome = plan(options.policy, &dest, entry.mtime)?;
+            *counts.entry(outcome.label()).or_default() += 1;
+            report(outcome.label(), dest.display().to_string());
+            Ok(())
+        })?;
+    }
+
+    let mut kept_deleted = 0;
+    for path in &deleted {
+        let dest = safe_join(options.restore_root, path)?;
+        match fs::symlink_metadata(&dest) {
+            Ok(meta) if !meta.is_dir() => {}
+            _ => continue,
+        }
+        if !options.delete {
+            kept_deleted += 1;
+            continue;
+        }
+        let label = match plan(options.policy, &dest, deleted_before)? {
+            Outcome::Overwrite => "remove",
+            outcome => outcome.label(),
+        };
+        *counts.entry(label).or_default() += 1;
+        report(label, dest.display().to_string());
+    }
+
+    let mut to_install = (0, 0);
+    if options.install_packages {
+        to_install.0 = plan_installs(&package_names, paru::installed_package_names, "package", &report);
+        to_install.1 = plan_installs(&flatpaks, flatpak::installed_flatpak_app_ids, "flatpak", &report);
+    } else if !package_names.is_empty() || !flatpaks.is_empty() {
+        println!("Package and Flatpak reinstall would be skipped.");
+    }
+
+    let files = counts
+        .iter()
+        .map(|(label, n)| format!("{} {}", n, label))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let summary = format!(
+        "Plan: files: {}; {} packages and {} Flatpaks to install",
+        if files.is_empty() { "none".to_string() } else { files },
+        to_install.0,
+        to_install.1
+    );
+    println!("{}", summary);
+    if let Some(sender) = tx {
+        let _ = sender.send(ProgressEvent::StatusMessage(summary));
+    }
+    if kept_deleted > 0 {
+        println!(
+            "{} files deleted since the base archive would be kept, pass --delete to remove them.",
+            kept_deleted
+        );
+    }
+
+    Ok(())
+}
+
+/// Reports each of `wanted` that is not installed yet and returns how many there are.
+fn plan_installs(
+    wanted: &[String],
+    installed: impl FnOnce() -> io::Result<Vec<String>>,
+    kind: &str,
+    report: &impl Fn(&str, String),
+) -> usize {
+    if wanted.is_empty() {
+        return 0;
+    }
+
+    let installed: HashSet<String> = match installed() {
+        Ok(names) => names.into_iter().collect(),
+        Err(e) => {
+            eprintln!("Warning: could not query installed {}s: {}", kind, e.to_string().trim());
+            HashSet::new()
+        }
+    };
+
+    let mut missing = 0;
+    for name in wanted {
+        if installed.contains(name) {
+            report("present", format!("{} {}", kind, name));
+        } else {
+            report("install", format!("{} {}", kind, name));
+            missing += 1;
+        }
+    }
+    missing
+}
@@ -1,22 +1,51 @@
-use flate2::read::GzDecoder;
-use std::fs;
+use std::fs::{self, File};
 use std::io::{self, Read};
 use std::path::{Path, PathBuf};
+
+use crossbeam_channel::Sender;
 use tar::Archive;
 
 use crate::backup::consolidate::expand_user_path;
 use crate::cli::RestoreArgs;
 use crate::events::{BackupPhase, ProgressEvent};
 use crate::flatpak::flatpak;
-use crate::pbar::{derive_argon2_key, PbarChunkReader, PbarHeader, PbarManifest};
-use crate::pm::paru;
-use crossbeam_channel::Sender;
+use crate::pbar::component::Component;
+use crate::pbar::header::PbarHeader;
+use crate::pbar::signature::SignatureStatus;
+use crate::pbar::PbarArchive;
+use crate::pm::packages::{self, Package};
+use crate::repo::snapshot::{parse_tree, TreeTar, TREE_ENTRY};
+use crate::repo::store::find_snapshot;
+use crate::restore::chain::{backup_time, deleted_files, resolve_chain, RestoreSource};
+use crate::restore::conflict::ConflictResolver;
+use crate::restore::extract::{safe_join, unpack_entry, write_new_file};
+use crate::restore::plan::{plan_restore, PlanOptions};
+use crate::restore::selection::Selection;
+use crate::utils::compression::CompressionCodec;
+use crate::utils::filter::PathFilter;
+use crate::verify::verify::{check_signature, print_signer_note};
 
 pub fn handle_restore_with_tx(
     args: &RestoreArgs,
     tx: Option<&Sender<ProgressEvent>>,
 ) -> io::Result<()> {
-    let archive_path = expand_user_path(&args.archive_path);
+    let archive_path = match (args.repo.as_deref(), &args.archive_path) {
+        (Some(repo), _) => find_snapshot(&expand_user_path(repo), args.snapshot.as_deref()),
+        (None, Some(path)) => Ok(expand_user_path(&path.to_string_lossy())),
+        (None, None) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No archive path or --repo given",
+        )),
+    };
+    let archive_path = match archive_path {
+        Ok(path) => path,
+        Err(e) => {
+            if let Some(sender) = tx {
+                let _ = sender.send(ProgressEvent::Error(e.to_string()));
+            }
+            return Err(e);
+        }
+    };
     if !archive_path.exists() {
         let err = format!("Archive file not found: {}", archive_path.display());
         if let Some(sender) = tx {
@@ -29,13 +58,34 @@ pub fn handle_restore_with_tx(
         let _ = sender.send(ProgressEvent::PhaseChanged(BackupPhase::Scanning));
     }
 
-    let mut file = fs::File::open(&archive_path)?;
+    // Authenticate the whole archive before anything is written to disk, then
+    // keep reading the same handle so the path cannot be pointed at another
+    // file in between. Changes made to the file in place are not caught.
+    let mut archive_file = match File::open(&archive_path)
+        .and_then(|mut file| authenticate(&mut file, args).map(|()| file))
+    {
+        Ok(file) => file,
+        Err(e) => {
+            if let Some(sender) = tx {
+                let _ = sender.send(ProgressEvent::Error(e.to_string()));
+            }
+            return Err(e);
+        }
+    };
 
     // 1. Read PBAR Header
-    let header = PbarHeader::read_from(&mut file)?;
+    let header = match PbarHeader::read_from(&mut archive_file) {
+        Ok(header) => header,
+        Err(e) => {
+            if let Some(sender) = tx {
+                let _ = sender.send(ProgressEvent::Error(e.to_string()));
+            }
+            return Err(e);
+        }
+    };
 
     // 2. Check Encryption Key
-    let derived_key = if header.is_encrypted() {
+    let passphrase = if header.is_encrypted() {
         if !args.decrypt {
             let err = "Archive is encrypted. Please provide --decrypt and --decrypt-key.".to_string();
             if let Some(sender) = tx {
@@ -46,84 +96,178 @@ pub fn handle_restore_with_tx(
 
         let key_str = args
             .decrypt_key
-            .as_ref()
+            .as_deref()
             .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Decryption key not provided"))?;
 
         if let Some(sender) = tx {
             let _ = sender.send(ProgressEvent::PhaseChanged(BackupPhase::Encrypting));
         }
 
-        Some(derive_argon2_key(key_str.as_bytes(), &header.salt)?)
+        Some(key_str)
     } else {
         None
     };
 
-    // 3. Read Manifest bytes
-    let manifest_size = header.manifest_size as usize;
-    let mut manifest_bytes = vec![0u8; manifest_size];
-    file.read_exact(&mut manifest_bytes)?;
+    // 3. Read Manifest block, sealed separately from the payload when encrypted
+    let archive = match PbarArchive::from_file(archive_file, &archive_path, passphrase) {
+        Ok(archive) => archive,
+        Err(e) => {
+            if let Some(sender) = tx {
+                let _ = sender.send(ProgressEvent::Error(e.to_string()));
+            }
+            return Err(e);
+        }
+    };
+    let manifest = &archive.manifest;
+    println!("Restoring PBAR Backup created by: {}", manifest.creator);
+    println!("Distro: {}, Kernel: {}", manifest.system_info.distro, manifest.system_info.kernel);
+
+    // Incremental backups also need the earlier archives holding their unchanged files
+    let mut sources = match resolve_chain(&archive_path, archive, passphrase, &|file| {
+        authenticate(file, args)
+    }) {
+        Ok(sources) => sources,
+        Err(e) => {
+            if let Some(sender) = tx {
+                let _ = sender.send(ProgressEvent::Error(e.to_string()));
+            }
+            return Err(e);
+        }
+    };
 
-    // Parse manifest if valid JSON
-    if let Ok(manifest) = PbarManifest::from_json_slice(&manifest_bytes) {
-        println!("Restoring PBAR Backup created by: {}", manifest.creator);
-        println!("Distro: {}, Kernel: {}", manifest.system_info.distro, manifest.system_info.kernel);
+    let selection = Selection::new(args)?;
+    let target_root = args.target.as_deref().map(expand_user_path);
+    let restore_root = match &target_root {
+        Some(root) => root.clone(),
+        None => std::env::var("HOME").map(PathBuf::from).unwrap_or_default(),
+    };
+
+    if args.dry_run {
+        let options = PlanOptions {
+            selection: &selection,
+            policy: args.conflict,
+            restore_root: &restore_root,
+            install_packages: target_root.is_none() || args.install_packages,
+            delete: args.delete,
+        };
+        plan_restore(sources, &options, tx)?;
+        if let Some(sender) = tx {
+            let _ = sender.send(ProgressEvent::Completed);
+        }
+        return Ok(());
     }
 
     if let Some(sender) = tx {
         let _ = sender.send(ProgressEvent::PhaseChanged(BackupPhase::Restoring));
     }
 
-    // 4. Create PbarChunkReader to stream inner tarball
-    let chunk_reader = PbarChunkReader::new(file, derived_key, header.base_nonce);
-    let gz_decoder = GzDecoder::new(chunk_reader);
-    let mut tar = Archive::new(gz_decoder);
+    let mut extractor = Extractor {
+        selection: &selection,
+        target_root: target_root.as_deref(),
+        restore_root: &restore_root,
+        resolver: ConflictResolver::new(args.conflict),
+        apps_to_install: Vec::new(),
+        flatpak_apps_to_install: Vec::new(),
+        tx,
+    };
 
-    let mut apps_to_install = Vec::new();
-    let mut flatpak_apps_to_install = Vec::new();
+    // Files deleted between the base and an increment are in no payload
+    let (deleted, deleted_before) = match sources.last_mut() {
+        Some(top) => (
+            deleted_files(&mut top.archive, &selection)?,
+            backup_time(&top.archive.manifest),
+        ),
+        None => (Vec::new(), 0),
+    };
 
-    for entry in tar.entries()? {
-        let mut entry = entry?;
-        let entry_path = entry.path()?.to_path_buf();
-        println!("Extracting {:?}", entry_path);
+    // 4. Stream the inner tarball out of each payload
+    for RestoreSource { archive, paths } in sources {
+        let repository = archive.repository()?;
+        let mut tar = archive.payload()?;
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+            let entry_path = entry.path()?.to_path_buf();
 
-        if let Some(sender) = tx {
-            let _ = sender.send(ProgressEvent::FileProgress {
-                file_name: entry_path.display().to_string(),
-                bytes_processed: entry.header().size().unwrap_or(0),
-            });
+            if entry_path == Path::new("manifest.json") {
+                continue; // Already processed
+            }
+            if entry_path == Path::new(TREE_ENTRY) {
+                // Snapshot files are pulled from the repository chunk by chunk
+                let repository = repository.as_ref().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Snapshot tree in a plain archive")
+                })?;
+                let tree = TreeTar::new(repository, parse_tree(&mut entry)?);
+                for tree_entry in tar::Archive::new(tree).entries_with_seek()? {
+                    extractor.extract(&mut tree_entry?)?;
+                }
+                continue;
+            }
+            if !paths.wants(&entry_path) {
+                continue; // Stored again in a later archive of the chain
+            }
+            extractor.extract(&mut entry)?;
         }
+    }
 
-        if entry_path == Path::new("manifest.json") {
-            continue; // Already processed
+    // 5. Remove what is left on disk of the deleted files, when asked to
+    let mut kept_deleted = 0;
+    for path in &deleted {
+        let dest = safe_join(&restore_root, path)?;
+        match fs::symlink_metadata(&dest) {
+            Ok(meta) if !meta.is_dir() => {}
+            _ => continue, // Gone already, or a directory now
         }
-
-        let dest_path = determine_restore_path(&entry_path)?;
-
-        if let Some(parent) = dest_path.parent() {
-            fs::create_dir_all(parent)?;
+        if !args.delete {
+            kept_deleted += 1;
+        } else if extractor.resolver.resolve_removal(&dest, deleted_before)? {
+            println!("Removing {}", dest.display());
+            fs::remove_file(&dest)?;
         }
+    }
+    if kept_deleted > 0 {
+        println!(
+            "Kept {} files deleted since the base archive, pass --delete to remove them.",
+            kept_deleted
+        );
+    }
 
-        if let Some(subdir) = entry_path.iter().next().and_then(|s| s.to_str()) {
-            match subdir {
-                "appsb" => collect_apps_list_from_entry(&mut entry, &mut apps_to_install)?,
-                "flatpakb" => collect_apps_list_from_entry(&mut entry, &mut flatpak_apps_to_install)?,
-                _ => {
-                    if entry_path.extension() == Some(std::ffi::OsStr::new("gz"))
-                        || entry_path.extension() == Some(std::ffi::OsStr::new("zst"))
-                    {
-                        extract_nested_tarball(&dest_path, &mut entry)?;
-                    } else {
-                        entry.unpack(&dest_path)?;
-                    }
-                }
-            }
+    let Extractor {
+        resolver,
+        mut apps_to_install,
+        mut flatpak_apps_to_install,
+        ..
+    } = extractor;
+
+    if target_root.is_some() && !args.install_packages {
+        if !apps_to_install.is_empty() || !flatpak_apps_to_install.is_empty() {
+            println!(
+                "Skipping reinstall of {} packages and {} Flatpaks for --target restore, pass --install-packages to install them.",
+                apps_to_install.len(),
+                flatpak_apps_to_install.len()
+            );
         }
+        apps_to_install.clear();
+        flatpak_apps_to_install.clear();
     }
 
     if !apps_to_install.is_empty() {
         println!("Restoring {} package manager applications...", apps_to_install.len());
-        if let Err(e) = paru::restore_installed_apps(&apps_to_install) {
-            eprintln!("Package manager restore warning: {}", e);
+        let report = packages::install_packages(&apps_to_install, args.pin_versions);
+        println!("Installed {} packages.", report.installed.len());
+        if !report.failed.is_empty() {
+            eprintln!("Failed to install {} packages:", report.failed.len());
+            for (name, reason) in &report.failed {
+                eprintln!("  {}: {}", name, reason);
+            }
+            if let Some(sender) = tx {
+                let names: Vec<&str> =
+                    report.failed.iter().map(|(name, _)| name.as_str()).collect();
+                let _ = sender.send(ProgressEvent::StatusMessage(format!(
+                    "Failed to install {} packages: {}",
+                    names.len(),
+                    names.join(", ")
+                )));
+            }
         }
     }
 
@@ -134,6 +278,12 @@ pub fn handle_restore_with_tx(
         }
     }
 
+    let summary = format!("Restored files: {}", resolver.stats());
+    println!("{}", summary);
+    if let Some(sender) = tx {
+        let _ = sender.send(ProgressEvent::StatusMessage(summary));
+    }
+
     println!("Restore completed successfully.");
     if let Some(sender) = tx {
         let _ = sender.send(ProgressEvent::Completed);
@@ -146,65 +296,152 @@ pub fn handle_restore(args: &RestoreArgs) -> io::Result<()> {
     handle_restore_with_tx(args, None)
 }
 
+/// Writes the payload entries of a restore and collects the package lists.
+struct Extractor<'a> {
+    selection: &'a Selection,
+    target_root: Option<&'a Path>,
+    restore_root: &'a Path,
+    resolver: ConflictResolver,
+    apps_to_install: Vec<Package>,
+    flatpak_apps_to_install: Vec<String>,
+    tx: Option<&'a Sender<ProgressEvent>>,
+}
+
+impl Extractor<'_> {
+    fn extract<R: Read>(&mut self, entry: &mut tar::Entry<R>) -> io::Result<()> {
+        let entry_path = entry.path()?.to_path_buf();
+        let component = Component::of_entry(&entry_path).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown subdirectory in archive: {}", entry_path.display()),
+            )
+        })?;
+        if !self.selection.wants_component(component) {
+            return Ok(());
+        }
+
+        println!("Extracting {:?}", entry_path);
+
+        if let Some(sender) = self.tx {
+            let _ = sender.send(ProgressEvent::FileProgress {
+                file_name: entry_path.display().to_string(),
+                bytes_processed: entry.header().size().unwrap_or(0),
+            });
+        }
+
+        let prefix = match component.home_prefix() {
+            Some(prefix) => prefix,
+            None => {
+                if !self.selection.wants_package_list(component) {
+                    return Ok(());
+                }
+                let mut content = String::new();
+                entry.read_to_string(&mut content)?;
+                // Keep a copy of the list with the relocated files
+                if let Some(root) = self.target_root {
+                    let list_path = safe_join(root, &entry_path)?;
+                    write_new_file(root, &list_path, content.as_bytes())?;
+                }
+                match component {
+                    Component::Apps => {
+                        let list = packages::read_package_list(&entry_path, &content)?;
+                        self.apps_to_install.extend(packages::explicit_packages(list));
+                    }
+                    _ => self.flatpak_apps_to_install.extend(parse_package_list(&content)),
+                }
+                return Ok(());
+            }
+        };
+
+        let component_dir = safe_join(self.restore_root, &prefix)?;
+        let dest_path = determine_restore_path(&component_dir, &entry_path)?;
+
+        match CompressionCodec::from_path(&entry_path) {
+            Some(codec) => extract_nested_tarball(
+                self.restore_root,
+                dest_path.parent().unwrap_or(&component_dir),
+                &prefix,
+                entry,
+                codec,
+                &self.selection.filter,
+                &mut self.resolver,
+            ),
+            None => {
+                let home_relative = prefix.join(entry_path.iter().skip(1).collect::<PathBuf>());
+                if !self.selection.filter.matches(&home_relative) {
+                    return Ok(());
+                }
+                if let Some(dest) = self.resolver.resolve(&dest_path, entry.header())? {
+                    unpack_entry(entry, self.restore_root, &component_dir, &dest)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Checks the signature of the archive in `file` against the trusted keys in `args`.
+fn authenticate(file: &mut File, args: &RestoreArgs) -> io::Result<()> {
+    match check_signature(file, &args.trusted_key) {
+        Ok(SignatureStatus::Valid { public_key }) => {
+            println!("Archive signature OK, signed by {}", hex::encode(public_key));
+            print_signer_note(&args.trusted_key);
+        }
+        Ok(SignatureStatus::Unsigned) => {
+            // A stripped signature looks the same, only --trusted-key refuses it
+            eprintln!("Warning: Archive is not signed, its origin cannot be verified");
+        }
+        Err(e) if args.ignore_signature => {
+            eprintln!("Warning: {}", e);
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
+
+/// Extracts a nested component tarball into `dest_dir`, never writing outside
+/// `root`. `prefix` is the home-relative location of `dest_dir`, used to match
+/// entries against `filter`.
 fn extract_nested_tarball(
-    dest_path: &Path,
+    root: &Path,
+    dest_dir: &Path,
+    prefix: &Path,
     entry: &mut tar::Entry<impl io::Read>,
+    codec: CompressionCodec,
+    filter: &PathFilter,
+    resolver: &mut ConflictResolver,
 ) -> io::Result<()> {
-    let mut tar_gz = Vec::new();
-    entry.read_to_end(&mut tar_gz)?;
-
-    let cursor = std::io::Cursor::new(tar_gz);
-    let tar_gz_decoder = GzDecoder::new(cursor);
-    let mut nested_tar = Archive::new(tar_gz_decoder);
+    // Decode straight from the outer entry so the nested tarball is never held in memory
+    let nested_decoder = codec.decoder(entry)?;
+    let mut nested_tar = Archive::new(nested_decoder);
 
     for nested_entry in nested_tar.entries()? {
         let mut nested_entry = nested_entry?;
-        let nested_entry_path = nested_entry.path()?;
-        let nested_dest_path = dest_path.parent().unwrap().join(nested_entry_path);
-
-        if let Some(parent) = nested_dest_path.parent() {
-            fs::create_dir_all(parent)?;
+        let nested_entry_path = nested_entry.path()?.to_path_buf();
+        if !filter.matches(&prefix.join(&nested_entry_path)) {
+            continue;
         }
+        let nested_dest_path = safe_join(dest_dir, &nested_entry_path)?;
+        let nested_dest_path = match resolver.resolve(&nested_dest_path, nested_entry.header())? {
+            Some(path) => path,
+            None => continue,
+        };
 
-        nested_entry.unpack(nested_dest_path)?;
+        unpack_entry(&mut nested_entry, root, dest_dir, &nested_dest_path)?;
     }
 
     Ok(())
 }
 
-fn determine_restore_path(entry_path: &Path) -> io::Result<PathBuf> {
-    let subdir = entry_path
-        .iter()
-        .next()
-        .and_then(|s| s.to_str())
-        .unwrap_or("");
+/// Destination of a home-based payload entry inside its component directory.
+fn determine_restore_path(component_dir: &Path, entry_path: &Path) -> io::Result<PathBuf> {
     let actual_path = entry_path.iter().skip(1).collect::<PathBuf>();
-
-    let home_dir = std::env::var("HOME").map(PathBuf::from).unwrap_or_default();
-
-    let base_path = match subdir {
-        "appsb" => PathBuf::from("."),
-        "homeb" => home_dir,
-        "flatpakb" => PathBuf::from("."),
-        "gnupgb" => home_dir.join(".gnupg"),
-        "sshb" => home_dir.join(".ssh"),
-        _ => {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Unknown subdirectory in archive: {}", subdir),
-            ))
-        }
-    };
-
-    Ok(base_path.join(actual_path))
+    safe_join(component_dir, &actual_path)
 }
 
-fn collect_apps_list_from_entry<R: Read>(
-    entry: &mut tar::Entry<R>,
-    apps_list: &mut Vec<String>,
-) -> io::Result<()> {
-    let mut content = String::new();
-    entry.read_to_string(&mut content)?;
-    apps_list.extend(content.lines().map(|line| line.trim().to_string()).filter(|l| !l.is_empty()));
-    Ok(())
+fn parse_package_list(content: &str) -> impl Iterator<Item = String> + '_ {
+    content
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|l| !l.is_empty())
 }
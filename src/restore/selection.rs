@@ -0,0 +1,32 @@
+use std::io;
+
+use crate::cli::RestoreArgs;
+use crate::pbar::component::Component;
+use crate::utils::filter::PathFilter;
+
+/// Components and paths of the archive a restore writes back.
+pub struct Selection {
+    pub only: Vec<Component>,
+    pub filter: PathFilter,
+    has_includes: bool,
+}
+
+impl Selection {
+    pub fn new(args: &RestoreArgs) -> io::Result<Self> {
+        Ok(Self {
+            only: args.only.clone(),
+            filter: PathFilter::new(&args.include, &args.exclude)?,
+            has_includes: !args.include.is_empty(),
+        })
+    }
+
+    pub fn wants_component(&self, component: Component) -> bool {
+        self.only.is_empty() || self.only.contains(&component)
+    }
+
+    /// Package lists are not paths, so `--include` skips them unless they
+    /// are named in `--only`.
+    pub fn wants_package_list(&self, component: Component) -> bool {
+        self.only.contains(&component) || (self.only.is_empty() && !self.has_includes)
+    }
+}
@@ -1,31 +1,23 @@
-use crate::utils::compression;
-use std::fs;
+use crate::backup::consolidate::ComponentSource;
+use crate::utils::compression::{self, DirectorySummary};
 use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 
-/// Backs up the user's home directory.
+/// Backs up the user's home directory. The tree is only scanned here and
+/// read when the archive is written.
 pub fn backup_home(
     home_dir: &Path,
     exclude_dir: &[String],
     interrupted: &Arc<AtomicBool>,
-) -> io::Result<PathBuf> {
-    let file_extension = compression::ARCHIVE_EXT;
-    let file_name = format!("home_backup.{}", file_extension);
-    let backup_file = PathBuf::from(file_name);
+) -> io::Result<(ComponentSource, DirectorySummary)> {
+    let exclude_paths: Vec<PathBuf> = exclude_dir.iter().map(|d| home_dir.join(d)).collect();
 
-    // Ensure the parent directory exists
-    if let Some(parent_dir) = backup_file.parent() {
-        fs::create_dir_all(parent_dir)?;
-    }
-
-    // Compress the home directory
-    match compression::compress_directory(home_dir, &backup_file, Some(exclude_dir), interrupted) {
-        Ok(_) => Ok(backup_file),
-        Err(e) => {
-            eprintln!("Failed to backup home directory: {}", e);
-            Err(e)
-        }
-    }
+    let summary = compression::summarize_directory(home_dir, &exclude_paths, interrupted)?;
+    let source = ComponentSource::Directory {
+        root: home_dir.to_path_buf(),
+        exclude_paths,
+    };
+    Ok((source, summary))
 }
@@ -1,7 +1,371 @@
-// use std::io;
+use clap::Parser;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 
-// use crate::cli::ScheduleArgs;
-// pub fn schedule_backup(args: &ScheduleArgs) -> io::Result<()> {
-//     // develop backup schedule
-//     return Ok(());
-// }
+use crate::cli::{Cli, Commands, ScheduleArgs, ScheduleCommand};
+use crate::config::profile::{config_home, resolve_backup_args};
+
+/// Prefix of the generated unit names, `parch-backup-<name>.{service,timer}`.
+pub const UNIT_PREFIX: &str = "parch-backup-";
+
+/// A timer that runs `command` on `on_calendar`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    pub name: String,
+    /// systemd `OnCalendar=` expression.
+    pub on_calendar: String,
+    /// systemd `RandomizedDelaySec=` time span, `None` to start right away.
+    pub randomized_delay: Option<String>,
+    /// Program and arguments of the service's `ExecStart=`.
+    pub command: Vec<String>,
+}
+
+/// A schedule read back from its unit files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstalledSchedule {
+    pub name: String,
+    pub on_calendar: String,
+    /// The service's `ExecStart=` line as written.
+    pub exec_start: String,
+}
+
+impl Schedule {
+    pub fn service_name(&self) -> String {
+        format!("{}{}.service", UNIT_PREFIX, self.name)
+    }
+
+    pub fn timer_name(&self) -> String {
+        format!("{}{}.timer", UNIT_PREFIX, self.name)
+    }
+}
+
+/// Directory of the user's own systemd units,
+/// `$XDG_CONFIG_HOME/systemd/user` or `~/.config/systemd/user`.
+pub fn unit_dir() -> io::Result<PathBuf> {
+    Ok(config_home()?.join("systemd/user"))
+}
+
+/// Schedule names end up in unit names, so only `[A-Za-z0-9_-]` is allowed.
+pub fn check_name(name: &str) -> io::Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid schedule name '{}', use letters, digits, '-' and '_'",
+                name
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Rejects values that would break out of their line in a unit file.
+fn check_unit_value(what: &str, value: &str) -> io::Result<()> {
+    if value.trim().is_empty() || value.chars().any(char::is_control) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid {} '{}'", what, value.escape_debug()),
+        ));
+    }
+    Ok(())
+}
+
+/// Quotes one `ExecStart=` argument. `%` and `$` are doubled so that
+/// systemd does not expand them as specifiers or variables.
+pub fn quote_arg(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%").replace('$', "$$");
+    if !escaped.is_empty()
+        && escaped
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%$~".contains(c))
+    {
+        return escaped;
+    }
+    format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+pub fn render_service(schedule: &Schedule) -> String {
+    let exec_start: Vec<String> = schedule.command.iter().map(|arg| quote_arg(arg)).collect();
+    format!(
+        "# Written by `parch-backup schedule add {name}`, changes are lost when it is run again.
+[Unit]
+Description=ParchBackup backup ({name})
+Documentation=man:parch-backup(1)
+
+[Service]
+Type=oneshot
+ExecStart={exec_start}
+Nice=10
+IOSchedulingClass=idle
+",
+        name = schedule.name,
+        exec_start = exec_start.join(" ")
+    )
+}
+
+pub fn render_timer(schedule: &Schedule) -> String {
+    let delay = schedule
+        .randomized_delay
+        .as_deref()
+        .map(|delay| format!("RandomizedDelaySec={}\n", delay))
+        .unwrap_or_default();
+    format!(
+        "# Written by `parch-backup schedule add {name}`, changes are lost when it is run again.
+[Unit]
+Description=Scheduled ParchBackup backup ({name})
+Documentation=man:parch-backup(1)
+
+[Timer]
+OnCalendar={on_calendar}
+Persistent=true
+{delay}Unit={service}
+
+[Install]
+WantedBy=timers.target
+",
+        name = schedule.name,
+        on_calendar = schedule.on_calendar,
+        delay = delay,
+        service = schedule.service_name()
+    )
+}
+
+/// Writes the service and timer of `schedule` into `dir`, replacing
+/// earlier units of the same name. Returns the written paths.
+pub fn write_units(dir: &Path, schedule: &Schedule) -> io::Result<(PathBuf, PathBuf)> {
+    check_name(&schedule.name)?;
+    check_unit_value("calendar expression", &schedule.on_calendar)?;
+    if let Some(delay) = &schedule.randomized_delay {
+        check_unit_value("randomized delay", delay)?;
+    }
+    if let Some(arg) = schedule
+        .command
+        .iter()
+        .find(|arg| arg.chars().any(char::is_control))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid backup argument '{}'", arg.escape_debug()),
+        ));
+    }
+
+    fs::create_dir_all(dir)?;
+    let service = dir.join(schedule.service_name());
+    let timer = dir.join(schedule.timer_name());
+    fs::write(&service, render_service(schedule))?;
+    fs::write(&timer, render_timer(schedule))?;
+    Ok((service, timer))
+}
+
+/// Value of the first `key=` line of a unit file.
+fn unit_value<'a>(unit: &'a str, key: &str) -> Option<&'a str> {
+    unit.lines()
+        .find_map(|line| line.trim().strip_prefix(key)?.strip_prefix('='))
+        .map(str::trim)
+}
+
+/// Schedules whose timer is in `dir`, sorted by name.
+pub fn read_schedules(dir: &Path) -> io::Result<Vec<InstalledSchedule>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut schedules = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let name = match file_name
+            .strip_prefix(UNIT_PREFIX)
+            .and_then(|rest| rest.strip_suffix(".timer"))
+        {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let timer = fs::read_to_string(entry.path())?;
+        let service = fs::read_to_string(dir.join(format!("{}{}.service", UNIT_PREFIX, name)))
+            .unwrap_or_default();
+        schedules.push(InstalledSchedule {
+            on_calendar: unit_value(&timer, "OnCalendar").unwrap_or("").to_string(),
+            exec_start: unit_value(&service, "ExecStart").unwrap_or("").to_string(),
+            name,
+        });
+    }
+    schedules.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(schedules)
+}
+
+/// Deletes the units of schedule `name` from `dir`. Returns whether
+/// there were any.
+pub fn remove_units(dir: &Path, name: &str) -> io::Result<bool> {
+    check_name(name)?;
+    let mut removed = false;
+    for suffix in ["timer", "service"] {
+        match fs::remove_file(dir.join(format!("{}{}.{}", UNIT_PREFIX, name, suffix))) {
+            Ok(()) => removed = true,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(removed)
+}
+
+/// Checks that `args` are valid `backup` options that can run unattended.
+pub fn check_backup_args(args: &[String]) -> io::Result<()> {
+    let cli = Cli::try_parse_from(
+        ["parch-backup", "backup"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .chain(args.iter().cloned()),
+    )
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let backup = match cli.command {
+        Commands::Backup(backup) => backup,
+        _ => unreachable!("parsed as a backup command"),
+    };
+    if backup.encrypt_key.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Refusing to write --encrypt-key into a unit file in plain text, use a profile with an encrypt_key_file",
+        ));
+    }
+
+    let backup = resolve_backup_args(&backup)?;
+    if !(backup.apps || backup.home || backup.flatpak || backup.keys) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "A scheduled backup needs at least one of --apps, --home, --flatpak or --keys, from its options or its profile",
+        ));
+    }
+    Ok(())
+}
+
+/// Validates a calendar expression with `systemd-analyze calendar` when it
+/// is installed.
+fn check_calendar(on_calendar: &str) -> io::Result<()> {
+    check_unit_value("calendar expression", on_calendar)?;
+    let output = match Command::new("systemd-analyze")
+        .args(["calendar", on_calendar])
+        .stdout(Stdio::null())
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid calendar expression '{}': {}",
+                on_calendar,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Runs `systemctl --user <args>`.
+fn systemctl(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("systemctl")
+        .arg("--user")
+        .args(args)
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("Cannot run systemctl: {}", e)))?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "systemctl --user {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+pub fn handle_schedule(args: &ScheduleArgs) -> io::Result<()> {
+    let dir = unit_dir()?;
+    match &args.command {
+        ScheduleCommand::Add {
+            name,
+            on_calendar,
+            randomized_delay,
+            no_enable,
+            backup_args,
+        } => {
+            check_name(name)?;
+            check_calendar(on_calendar)?;
+            check_backup_args(backup_args)?;
+
+            let exe = std::env::current_exe()?;
+            let mut command = vec![exe.to_string_lossy().to_string(), "backup".to_string()];
+            command.extend(backup_args.iter().cloned());
+            let schedule = Schedule {
+                name: name.clone(),
+                on_calendar: on_calendar.clone(),
+                randomized_delay: Some(randomized_delay.clone()).filter(|delay| delay != "0"),
+                command,
+            };
+
+            let (service, timer) = write_units(&dir, &schedule)?;
+            println!("Wrote {} and {}", service.display(), timer.display());
+            if !no_enable {
+                systemctl(&["daemon-reload"])?;
+                systemctl(&["enable", "--now", &schedule.timer_name()])?;
+                println!("Enabled {} ({})", schedule.timer_name(), on_calendar);
+            }
+            Ok(())
+        }
+        ScheduleCommand::List => {
+            let schedules = read_schedules(&dir)?;
+            for schedule in &schedules {
+                let timer = format!("{}{}.timer", UNIT_PREFIX, schedule.name);
+                // is-enabled exits non-zero for disabled units
+                let state = Command::new("systemctl")
+                    .args(["--user", "is-enabled", &timer])
+                    .stderr(Stdio::null())
+                    .output()
+                    .ok()
+                    .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+                    .filter(|state| !state.is_empty())
+                    .unwrap_or_else(|| "unknown".to_string());
+                println!(
+                    "{:<16} {:<10} {:<24} {}",
+                    schedule.name, state, schedule.on_calendar, schedule.exec_start
+                );
+            }
+            println!("{} schedules in {}", schedules.len(), dir.display());
+            Ok(())
+        }
+        ScheduleCommand::Remove { name } => {
+            check_name(name)?;
+            let timer = format!("{}{}.timer", UNIT_PREFIX, name);
+            if dir.join(&timer).exists() {
+                if let Err(e) = systemctl(&["disable", "--now", &timer]) {
+                    eprintln!("Warning: {}", e);
+                }
+            }
+            if !remove_units(&dir, name)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No schedule named '{}'", name),
+                ));
+            }
+            if let Err(e) = systemctl(&["daemon-reload"]) {
+                eprintln!("Warning: {}", e);
+            }
+            println!("Removed schedule {}", name);
+            Ok(())
+        }
+    }
+}
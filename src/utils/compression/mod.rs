@@ -1,58 +1,250 @@
 use flate2::write::GzEncoder;
 use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::fs::File;
-use std::io;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use walkdir::WalkDir;
 
-pub static ARCHIVE_EXT: &str = "tar.gz";
+use crate::pbar::header::FLAG_COMPRESS_ZSTD;
+use crate::pbar::manifest::FileRecord;
 
-pub fn compress_directory<P: AsRef<Path>>(
-    source_dir: P,
-    target_file: P,
-    exclude_dirs: Option<&[String]>,
-    should_stop: &AtomicBool,
-) -> io::Result<()> {
-    let tar_gz = File::create(target_file)?;
-    let enc = GzEncoder::new(tar_gz, Compression::default());
-    let mut tar = tar::Builder::new(enc);
-
-    let source_dir = source_dir.as_ref();
-    let exclude_paths: Vec<PathBuf> = exclude_dirs
-        .unwrap_or(&[])
-        .iter()
-        .map(|d| source_dir.join(d))
-        .collect();
-
-    for entry in WalkDir::new(source_dir).into_iter().filter_map(|e| e.ok()) {
-        if should_stop.load(Ordering::SeqCst) {
-            return Err(io::Error::new(io::ErrorKind::Interrupted, "Operation canceled"));
+/// Compression level used for zstd streams (zstd's own default).
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compression codec of the PBAR payload tarball, which holds the component
+/// directories as plain entries, and of the file index stream after it.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionCodec {
+    #[default]
+    Gzip,
+    Zstd,
+}
+
+impl CompressionCodec {
+    /// Reads the codec out of the PBAR header feature flags.
+    pub fn from_flags(feature_flags: u16) -> Self {
+        if (feature_flags & FLAG_COMPRESS_ZSTD) != 0 {
+            CompressionCodec::Zstd
+        } else {
+            CompressionCodec::Gzip
         }
+    }
 
-        let entry_path = entry.path();
+    /// Guesses the codec of a nested tarball from its file name.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("gz") => Some(CompressionCodec::Gzip),
+            Some("zst") => Some(CompressionCodec::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Feature flag bits to OR into the PBAR header.
+    pub fn header_flag(self) -> u16 {
+        match self {
+            CompressionCodec::Gzip => 0,
+            CompressionCodec::Zstd => FLAG_COMPRESS_ZSTD,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CompressionCodec::Gzip => "gzip",
+            CompressionCodec::Zstd => "zstd",
+        }
+    }
+
+    /// Wraps `writer` in a compressing encoder. Zstd uses one worker per available CPU.
+    pub fn encoder<W: Write>(self, writer: W) -> io::Result<PayloadEncoder<W>> {
+        match self {
+            CompressionCodec::Gzip => Ok(PayloadEncoder::Gzip(GzEncoder::new(
+                writer,
+                Compression::default(),
+            ))),
+            CompressionCodec::Zstd => {
+                let workers = std::thread::available_parallelism()
+                    .map(|n| n.get() as u32)
+                    .unwrap_or(1);
+                let mut enc = zstd::Encoder::new(writer, ZSTD_LEVEL)?;
+                if workers > 1 {
+                    enc.multithread(workers)?;
+                }
+                Ok(PayloadEncoder::Zstd(enc))
+            }
+        }
+    }
+
+    /// Wraps `reader` in the matching decompressing decoder.
+    pub fn decoder<'a, R: Read + 'a>(self, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+        match self {
+            CompressionCodec::Gzip => Ok(Box::new(flate2::read::GzDecoder::new(reader))),
+            CompressionCodec::Zstd => Ok(Box::new(zstd::Decoder::new(reader)?)),
+        }
+    }
+}
+
+/// Streaming compressor for either supported codec.
+pub enum PayloadEncoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> PayloadEncoder<W> {
+    /// Writes the compressed stream trailer and returns the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            PayloadEncoder::Gzip(enc) => enc.finish(),
+            PayloadEncoder::Zstd(enc) => enc.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for PayloadEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            PayloadEncoder::Gzip(enc) => enc.write(buf),
+            PayloadEncoder::Zstd(enc) => enc.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            PayloadEncoder::Gzip(enc) => enc.flush(),
+            PayloadEncoder::Zstd(enc) => enc.flush(),
+        }
+    }
+}
+
+/// Number of files in a directory tree and their combined size, as
+/// `append_directory` would archive them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DirectorySummary {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// Walks `source_dir` below its root, pruning everything under `exclude_paths`.
+pub fn walk_tree<'a>(
+    source_dir: &Path,
+    exclude_paths: &'a [PathBuf],
+) -> impl Iterator<Item = walkdir::DirEntry> + 'a {
+    WalkDir::new(source_dir)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(move |e| !exclude_paths.iter().any(|d| e.path().starts_with(d)))
+        .filter_map(|e| e.ok())
+}
+
+pub fn check_stop(should_stop: &AtomicBool) -> io::Result<()> {
+    if should_stop.load(Ordering::SeqCst) {
+        return Err(io::Error::new(io::ErrorKind::Interrupted, "Operation canceled"));
+    }
+    Ok(())
+}
 
-        // Skip directories that need to be excluded
-        if exclude_paths.iter().any(|d| entry_path.starts_with(d)) {
-            continue;
+/// Counts the files under `source_dir` without reading them.
+pub fn summarize_directory(
+    source_dir: &Path,
+    exclude_paths: &[PathBuf],
+    should_stop: &AtomicBool,
+) -> io::Result<DirectorySummary> {
+    let mut summary = DirectorySummary::default();
+    for entry in walk_tree(source_dir, exclude_paths) {
+        check_stop(should_stop)?;
+        if !entry.file_type().is_dir() {
+            summary.files += 1;
+            summary.bytes += entry.metadata().map(|m| m.len()).unwrap_or(0);
         }
+    }
+    Ok(summary)
+}
+
+/// Hashes everything read through it.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Appends the open `file` as `path_in_archive` and returns its index record,
+/// made from the bytes that went into `tar` rather than from a second read.
+/// The header takes the size the file has when it is appended: a file that
+/// grows meanwhile is cut there and one that shrinks is padded with zeros,
+/// so the entry always matches its header. `None` for anything but a
+/// regular file, such as a linked directory.
+pub fn append_file_indexed<W: Write>(
+    tar: &mut tar::Builder<W>,
+    path_in_archive: &Path,
+    file: &mut File,
+) -> io::Result<Option<FileRecord>> {
+    let metadata = file.metadata()?;
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata(&metadata);
+    if !metadata.is_file() {
+        header.set_size(0);
+        tar.append_data(&mut header, path_in_archive, io::empty())?;
+        return Ok(None);
+    }
+
+    let size = metadata.len();
+    let mut data = HashingReader {
+        inner: Read::by_ref(file).take(size).chain(io::repeat(0)).take(size),
+        hasher: Sha256::new(),
+    };
+    tar.append_data(&mut header, path_in_archive, &mut data)?;
+    Ok(Some(FileRecord {
+        path: path_in_archive.to_string_lossy().to_string(),
+        size,
+        mode: metadata.permissions().mode() & 0o7777,
+        mtime: header.mtime()?,
+        sha256: hex::encode(data.hasher.finalize()),
+        stored_in: None,
+    }))
+}
+
+/// Appends the tree under `source_dir` to `tar`, with every entry placed
+/// below `prefix`. Paths in `exclude_paths` are absolute and skipped along
+/// with everything under them. Files are only added when `keep` accepts
+/// their path in the archive; directories are always added. Returns the
+/// records of the files added, see [`append_file_indexed`].
+pub fn append_directory<W: Write>(
+    tar: &mut tar::Builder<W>,
+    source_dir: &Path,
+    prefix: &Path,
+    exclude_paths: &[PathBuf],
+    keep: &dyn Fn(&Path) -> bool,
+    should_stop: &AtomicBool,
+) -> io::Result<Vec<FileRecord>> {
+    let mut records = Vec::new();
+    for entry in walk_tree(source_dir, exclude_paths) {
+        check_stop(should_stop)?;
 
+        let entry_path = entry.path();
         let path_in_archive = match entry_path.strip_prefix(source_dir) {
-            Ok(p) => p,
+            Ok(p) => prefix.join(p),
             Err(_) => continue,
         };
 
-        if path_in_archive.components().count() == 0 {
-            // Skip empty paths
-            continue;
-        }
-
         if entry.file_type().is_dir() {
-            tar.append_dir(path_in_archive, entry_path)?;
-        } else {
+            tar.append_dir(&path_in_archive, entry_path)?;
+        } else if keep(&path_in_archive) {
             match File::open(entry_path) {
                 Ok(mut file) => {
-                    tar.append_file(path_in_archive, &mut file)?;
+                    records.extend(append_file_indexed(tar, &path_in_archive, &mut file)?);
                 }
                 Err(e) if e.kind() == io::ErrorKind::NotFound => {
                     eprintln!("Failed to backup file: {} - {}", entry_path.display(), e);
@@ -62,40 +254,5 @@ pub fn compress_directory<P: AsRef<Path>>(
             }
         }
     }
-
-    tar.finish()?;
-    Ok(())
-}
-
-/// Create a compressed tar archive (tar.gz) from specified files.
-pub fn create_tar_gz_archive<P: AsRef<Path>>(
-    archive_name: P,
-    backup_files: &[(String, PathBuf)],
-) -> io::Result<()> {
-    // Create the archive file
-    let archive_file = File::create(&archive_name)?;
-    let enc = GzEncoder::new(archive_file, Compression::default());
-    let mut tar = tar::Builder::new(enc);
-
-    // Add each backup file to the archive and remove original files
-    for (subdir, file) in backup_files {
-        let path_in_archive = Path::new(subdir).join(file.file_name().unwrap());
-        tar.append_path_with_name(file, path_in_archive)?;
-        std::fs::remove_file(file)?;
-    }
-
-    // Finalize the archive
-    tar.finish()?;
-
-    Ok(())
-}
-/// Opens and decodes a tar.gz archive file.
-pub fn open_and_decode_archive<P: AsRef<Path>>(
-    archive_path: P,
-) -> io::Result<tar::Archive<flate2::read::GzDecoder<File>>> {
-    // Open the archive file
-    let archive_file = File::open(archive_path)?;
-    let archive_decoder = flate2::read::GzDecoder::new(archive_file);
-    let tar = tar::Archive::new(archive_decoder);
-    Ok(tar)
+    Ok(records)
 }
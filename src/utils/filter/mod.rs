@@ -0,0 +1,61 @@
+use glob::Pattern;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Include/exclude glob filter over home-relative paths.
+///
+/// A pattern selects a path when it matches the path itself or one of its
+/// parent directories, so `.config/nvim` selects everything below it.
+/// Patterns may be written with a leading `~/`.
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> io::Result<Self> {
+        Ok(Self {
+            include: compile(include)?,
+            exclude: compile(exclude)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Whether `path` passes the filter. An empty include list selects everything.
+    pub fn matches(&self, path: &Path) -> bool {
+        let included = self.include.is_empty() || any_match(&self.include, path);
+        included && !any_match(&self.exclude, path)
+    }
+}
+
+fn compile(patterns: &[String]) -> io::Result<Vec<Pattern>> {
+    patterns
+        .iter()
+        .map(|raw| {
+            let trimmed = normalize(raw);
+            Pattern::new(&trimmed).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid path pattern '{}': {}", raw, e),
+                )
+            })
+        })
+        .collect()
+}
+
+fn normalize(raw: &str) -> String {
+    let trimmed = raw.strip_prefix("~/").unwrap_or(raw);
+    let trimmed = trimmed.trim_start_matches("./").trim_start_matches('/');
+    trimmed.trim_end_matches('/').to_string()
+}
+
+fn any_match(patterns: &[Pattern], path: &Path) -> bool {
+    let path: PathBuf = path.components().collect();
+    path.ancestors()
+        .filter(|p| !p.as_os_str().is_empty())
+        .any(|p| patterns.iter().any(|pattern| pattern.matches_path(p)))
+}
@@ -0,0 +1,36 @@
+use ed25519_dalek::SigningKey;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+
+use crate::backup::consolidate::expand_user_path;
+use crate::cli::KeygenArgs;
+
+/// Generates an Ed25519 signing key pair for `backup --sign-key`.
+pub fn handle_keygen(args: &KeygenArgs) -> io::Result<()> {
+    let secret_path = expand_user_path(&args.output);
+    let public_path = secret_path.with_file_name(format!(
+        "{}.pub",
+        secret_path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default()
+    ));
+
+    let key = SigningKey::generate(&mut rand::rngs::OsRng);
+
+    let mut secret_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&secret_path)?;
+    writeln!(secret_file, "{}", hex::encode(key.to_bytes()))?;
+
+    let public_hex = hex::encode(key.verifying_key().to_bytes());
+    fs::write(&public_path, format!("{}\n", public_hex))?;
+
+    println!("Secret key written to {}", secret_path.display());
+    println!("Public key written to {}", public_path.display());
+    println!("Public key: {}", public_hex);
+    Ok(())
+}
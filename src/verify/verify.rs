@@ -0,0 +1,191 @@
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Seek};
+use std::path::Path;
+
+use crate::backup::consolidate::expand_user_path;
+use crate::cli::VerifyArgs;
+use crate::list::list::walk_payload;
+use crate::pbar::manifest::FileRecord;
+use crate::pbar::signature::{parse_verifying_key, verify_archive_signature, SignatureStatus};
+use crate::pbar::header::PbarHeader;
+use crate::pbar::PbarArchive;
+use crate::utils::compression::CompressionCodec;
+
+/// What `verify_archive` read and checked.
+#[derive(Debug, Default)]
+pub struct IntegrityReport {
+    /// AEAD chunks authenticated, 0 for unencrypted archives.
+    pub chunks: u64,
+    pub entries: usize,
+    pub bytes: u64,
+    /// Files whose contents matched their SHA-256 in the file index.
+    pub files_checked: usize,
+    /// Indexed files that an earlier archive of the chain holds.
+    pub files_elsewhere: usize,
+}
+
+/// Checks the archive signature and enforces the trusted key set, if one is
+/// given. Reads all of `file` and rewinds it. Callers go on reading the same
+/// handle, so the path cannot be pointed at another file after the check;
+/// that does not protect against the file being modified in place.
+pub fn check_signature(
+    file: &mut fs::File,
+    trusted_keys: &[String],
+) -> io::Result<SignatureStatus> {
+    file.rewind()?;
+    let status = verify_archive_signature(io::BufReader::new(&mut *file))?;
+    file.rewind()?;
+
+    if trusted_keys.is_empty() {
+        return Ok(status);
+    }
+
+    let trusted = trusted_keys
+        .iter()
+        .map(|spec| parse_verifying_key(spec).map(|k| k.to_bytes()))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    match status {
+        SignatureStatus::Unsigned => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Archive is not signed but trusted keys were given",
+        )),
+        SignatureStatus::Valid { public_key } if !trusted.contains(&public_key) => {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "Archive is signed by untrusted key {}",
+                    hex::encode(public_key)
+                ),
+            ))
+        }
+        valid => Ok(valid),
+    }
+}
+
+/// Points out that a valid signature says nothing about who signed, unless
+/// `trusted_keys` pinned the signer.
+pub fn print_signer_note(trusted_keys: &[String]) {
+    if trusted_keys.is_empty() {
+        println!("Note: no --trusted-key given, signer identity was not checked.");
+    }
+}
+
+/// Reads the whole archive: authenticates every payload chunk, decompresses
+/// the payload and its nested tarballs, reads the chunks of a snapshot from
+/// its repository and compares every indexed file with its SHA-256.
+/// Stops at the first corruption, naming the chunk or file.
+pub fn verify_archive(path: &Path, passphrase: Option<&str>) -> io::Result<IntegrityReport> {
+    verify_contents(PbarArchive::open(path, passphrase)?)
+}
+
+/// [`verify_archive`] for an archive that is already open.
+pub fn verify_contents(mut archive: PbarArchive) -> io::Result<IntegrityReport> {
+    let repository = archive.repository()?;
+    let codec = CompressionCodec::from_flags(archive.header.feature_flags);
+
+    let mut report = IntegrityReport::default();
+    let index = archive
+        .read_index()
+        .map_err(|e| io::Error::new(e.kind(), format!("Corrupt archive in the file index: {}", e)))?
+        .unwrap_or_default();
+    let mut expected: BTreeMap<String, FileRecord> = BTreeMap::new();
+    for record in &index.files {
+        if record.stored_in.is_some() {
+            report.files_elsewhere += 1;
+        } else {
+            expected.insert(record.path.clone(), record.clone());
+        }
+    }
+
+    let mut stream = archive.payload_stream();
+    let mut position = "at the start of the payload".to_string();
+    let result = (|| -> io::Result<()> {
+        let mut payload = tar::Archive::new(codec.decoder(&mut stream)?);
+        walk_payload(&mut payload, repository.as_ref(), |entry, data| {
+            let payload_path = entry.payload_path.to_string_lossy().to_string();
+            position = format!("in {}", payload_path);
+            let mut hasher = Sha256::new();
+            let size = io::copy(data, &mut hasher)?;
+            report.entries += 1;
+            report.bytes += size;
+
+            if let Some(record) = expected.remove(&payload_path) {
+                let digest = hex::encode(hasher.finalize());
+                if size != record.size || digest != record.sha256 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "contents do not match the index, read {} bytes with SHA-256 {}, expected {} bytes with SHA-256 {}",
+                            size, digest, record.size, record.sha256
+                        ),
+                    ));
+                }
+                report.files_checked += 1;
+            }
+            position = format!("after {}", payload_path);
+            Ok(())
+        })?;
+
+        // Read to the final chunk, which proves that nothing was cut off
+        position = "at the end of the payload".to_string();
+        io::copy(&mut payload.into_inner(), &mut io::sink())?;
+        Ok(())
+    })();
+    let result = result.and_then(|()| io::copy(&mut stream, &mut io::sink()).map(drop));
+    result.map_err(|e| io::Error::new(e.kind(), format!("Corrupt archive {}: {}", position, e)))?;
+    report.chunks = stream.chunks_read();
+
+    if let Some((path, _)) = expected.iter().next() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Corrupt archive: {} of the indexed files are missing from the payload, first {}",
+                expected.len(),
+                path
+            ),
+        ));
+    }
+    Ok(report)
+}
+
+pub fn handle_verify(args: &VerifyArgs) -> io::Result<()> {
+    let archive_path = expand_user_path(&args.archive_path);
+    let mut file = fs::File::open(&archive_path)?;
+    match check_signature(&mut file, &args.trusted_key)? {
+        SignatureStatus::Unsigned => println!("Archive is not signed."),
+        SignatureStatus::Valid { public_key } => {
+            println!("Signature OK, signed by {}", hex::encode(public_key));
+            print_signer_note(&args.trusted_key);
+        }
+    }
+    if args.signature_only {
+        return Ok(());
+    }
+
+    if PbarHeader::read_from(&mut file)?.is_encrypted() && args.decrypt_key.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Archive is encrypted, pass --decrypt-key to check its contents or --signature-only",
+        ));
+    }
+    let archive = PbarArchive::from_file(file, &archive_path, args.decrypt_key.as_deref())?;
+    let report = verify_contents(archive)?;
+    if report.chunks > 0 {
+        println!("{} encrypted chunks authenticated.", report.chunks);
+    }
+    println!(
+        "{} entries ({} bytes) read, {} files match their SHA-256.",
+        report.entries, report.bytes, report.files_checked
+    );
+    if report.files_elsewhere > 0 {
+        println!(
+            "{} unchanged files are stored in earlier archives, verify those too.",
+            report.files_elsewhere
+        );
+    }
+    println!("Archive OK.");
+    Ok(())
+}
@@ -0,0 +1,56 @@
+//! Fixtures shared by the integration tests. Each test crate uses only some
+//! of them.
+#![allow(dead_code)]
+
+use parch_backup::backup::consolidate::{consolidate_backups, BackupComponentMeta, ComponentSource};
+use parch_backup::cli::BackupArgs;
+use parch_backup::utils::compression::{append_directory, CompressionCodec};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+
+pub fn write_file(path: &Path, contents: &str) {
+    fs::create_dir_all(path.parent().unwrap()).expect("Create parent");
+    fs::write(path, contents).expect("Write file");
+}
+
+/// The home component, capturing all of `home`.
+pub fn home_component(home: &Path) -> BackupComponentMeta {
+    BackupComponentMeta {
+        category: "homeb",
+        source: ComponentSource::Directory {
+            root: home.to_path_buf(),
+            exclude_paths: Vec::new(),
+        },
+        count: 0,
+        size_bytes: 0,
+        extra_info: None,
+    }
+}
+
+/// Writes an archive of `components`, or a snapshot with `--repo`, and
+/// waits until the next one gets a different name.
+pub fn backup(components: &[BackupComponentMeta], args: &BackupArgs) -> PathBuf {
+    let archive =
+        consolidate_backups(components, args, &AtomicBool::new(false)).expect("Create archive");
+    // Archive names only carry whole seconds
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    archive
+}
+
+/// Writes `source` as a compressed tarball to `target`, the way older
+/// writers stored directory components inside the payload.
+pub fn nested_tarball(source: &Path, target: &Path, codec: CompressionCodec) {
+    let file = fs::File::create(target).expect("Create tarball");
+    let mut tar = tar::Builder::new(codec.encoder(file).expect("Encoder"));
+    append_directory(
+        &mut tar,
+        source,
+        Path::new(""),
+        &[],
+        &|_| true,
+        &AtomicBool::new(false),
+    )
+    .expect("Append directory");
+    tar.into_inner().unwrap().finish().expect("Finish tarball");
+}
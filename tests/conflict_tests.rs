@@ -0,0 +1,74 @@
+use parch_backup::restore::conflict::{ConflictPolicy, ConflictResolver, ConflictStats};
+use std::fs;
+use std::path::Path;
+
+fn file_header(mtime: u64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_mtime(mtime);
+    header.set_size(0);
+    header
+}
+
+fn existing_file(dir: &Path) -> std::path::PathBuf {
+    let path = dir.join("notes.txt");
+    fs::write(&path, "edited after the backup").unwrap();
+    path
+}
+
+#[test]
+fn test_missing_file_is_created_under_any_policy() {
+    let dir = tempfile::tempdir().unwrap();
+    let dest = dir.path().join("new.txt");
+    let mut resolver = ConflictResolver::new(ConflictPolicy::Skip);
+
+    assert_eq!(resolver.resolve(&dest, &file_header(0)).unwrap(), Some(dest));
+    assert_eq!(resolver.stats().created, 1);
+}
+
+#[test]
+fn test_skip_and_overwrite() {
+    let dir = tempfile::tempdir().unwrap();
+    let dest = existing_file(dir.path());
+
+    let mut skip = ConflictResolver::new(ConflictPolicy::Skip);
+    assert_eq!(skip.resolve(&dest, &file_header(0)).unwrap(), None);
+
+    let mut overwrite = ConflictResolver::new(ConflictPolicy::Overwrite);
+    assert_eq!(overwrite.resolve(&dest, &file_header(0)).unwrap(), Some(dest));
+    assert_eq!(
+        *overwrite.stats(),
+        ConflictStats {
+            overwritten: 1,
+            ..Default::default()
+        }
+    );
+}
+
+#[test]
+fn test_keep_newer_compares_mtimes() {
+    let dir = tempfile::tempdir().unwrap();
+    let dest = existing_file(dir.path());
+    let mut resolver = ConflictResolver::new(ConflictPolicy::KeepNewer);
+
+    // Archived copy from 1970 is older than the file just written
+    assert_eq!(resolver.resolve(&dest, &file_header(1)).unwrap(), None);
+    // Archived copy from the far future replaces it
+    let future = file_header(u32::MAX as u64);
+    assert_eq!(resolver.resolve(&dest, &future).unwrap(), Some(dest));
+
+    assert_eq!(resolver.stats().kept_newer, 1);
+    assert_eq!(resolver.stats().overwritten, 1);
+}
+
+#[test]
+fn test_rename_with_suffix_picks_free_name() {
+    let dir = tempfile::tempdir().unwrap();
+    let dest = existing_file(dir.path());
+    fs::write(dir.path().join("notes.txt.pbar-restored"), "earlier restore").unwrap();
+    let mut resolver = ConflictResolver::new(ConflictPolicy::RenameWithSuffix);
+
+    let renamed = resolver.resolve(&dest, &file_header(0)).unwrap().unwrap();
+    assert_eq!(renamed, dir.path().join("notes.txt.pbar-restored.1"));
+    assert_eq!(resolver.stats().renamed, 1);
+}
@@ -0,0 +1,185 @@
+mod common;
+
+use common::{home_component, write_file};
+use parch_backup::backup::consolidate::{BackupComponentMeta, ComponentSource};
+use parch_backup::cli::BackupArgs;
+use parch_backup::diff::diff::{diff_snapshots, read_archive, read_live, ComponentDiff, Snapshot};
+use parch_backup::pbar::component::Component;
+use parch_backup::utils::filter::PathFilter;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+fn backup(home: &Path, archive_dir: &Path, packages: &str) -> PathBuf {
+    let components = vec![
+        BackupComponentMeta {
+            category: "appsb",
+            source: ComponentSource::Data {
+                file_name: "apps.txt",
+                contents: packages.as_bytes().to_vec(),
+            },
+            count: 0,
+            size_bytes: 0,
+            extra_info: None,
+        },
+        home_component(home),
+    ];
+    let args = BackupArgs {
+        archive_path: Some(archive_dir.to_string_lossy().to_string()),
+        apps: true,
+        home: true,
+        ..Default::default()
+    };
+    common::backup(&components, &args)
+}
+
+fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+#[test]
+fn test_diff_two_archives() {
+    let work = tempfile::tempdir().unwrap();
+    let home = work.path().join("home");
+    let archive_dir = work.path().join("archives");
+    write_file(&home.join(".bashrc"), "alias ll='ls -l'\n");
+    write_file(&home.join(".ssh/config"), "Host *\n");
+    write_file(&home.join("notes/old.txt"), "old\n");
+    let old = backup(&home, &archive_dir, "bash\nvim\n");
+
+    write_file(&home.join(".bashrc"), "alias la='ls -a'\n");
+    fs::set_permissions(home.join(".ssh/config"), fs::Permissions::from_mode(0o600)).unwrap();
+    fs::remove_file(home.join("notes/old.txt")).unwrap();
+    write_file(&home.join("notes/new.txt"), "new\n");
+    let new = backup(&home, &archive_dir, "bash\nhtop\n");
+
+    let old = read_archive(&old, None).unwrap();
+    let new = read_archive(&new, None).unwrap();
+    let diff = diff_snapshots(&old, &new, &PathFilter::new(&[], &[]).unwrap());
+    assert!(diff.skipped.is_empty());
+    assert_eq!(
+        diff.changes,
+        vec![
+            (
+                Component::Apps,
+                ComponentDiff {
+                    added: names(&["htop"]),
+                    removed: names(&["vim"]),
+                    modified: Vec::new(),
+                }
+            ),
+            (
+                Component::Home,
+                ComponentDiff {
+                    added: names(&["notes/new.txt"]),
+                    removed: names(&["notes/old.txt"]),
+                    modified: names(&[".bashrc", ".ssh/config"]),
+                }
+            ),
+        ]
+    );
+
+    let filter = PathFilter::new(&names(&["notes"]), &[]).unwrap();
+    let diff = diff_snapshots(&old, &new, &filter);
+    assert!(diff.changes[1].1.modified.is_empty());
+    assert_eq!(diff.changes[1].1.added, names(&["notes/new.txt"]));
+}
+
+#[test]
+fn test_diff_against_live_home() {
+    let work = tempfile::tempdir().unwrap();
+    let home = work.path().join("home");
+    write_file(&home.join(".bashrc"), "alias ll='ls -l'\n");
+    write_file(&home.join("cache/big"), "scratch\n");
+    let archive = backup(&home, &home.join("Backups"), "bash\n");
+
+    let old = read_archive(&archive, None).unwrap();
+    let live = read_live(&home, &old, &names(&["cache"]), &archive).unwrap();
+    assert!(live.components.contains(&Component::Home));
+    let diff = diff_snapshots(&old, &live, &PathFilter::new(&[], &[]).unwrap());
+    let home_changes = diff
+        .changes
+        .iter()
+        .find(|(component, _)| *component == Component::Home)
+        .map(|(_, changes)| changes)
+        .unwrap();
+    // The excluded directory is missing from the live scan
+    assert_eq!(home_changes.removed, names(&["cache/big"]));
+    assert!(home_changes.added.is_empty());
+    assert!(home_changes.modified.is_empty());
+
+    write_file(&home.join(".bashrc"), "changed\n");
+    let live = read_live(&home, &old, &[], &archive).unwrap();
+    let diff = diff_snapshots(&old, &live, &PathFilter::new(&[], &[]).unwrap());
+    let home_changes = &diff
+        .changes
+        .iter()
+        .find(|(component, _)| *component == Component::Home)
+        .unwrap()
+        .1;
+    assert_eq!(home_changes.modified, names(&[".bashrc"]));
+    assert!(home_changes.added.is_empty());
+}
+
+#[test]
+fn test_live_scan_leaves_out_backup_excludes() {
+    let work = tempfile::tempdir().unwrap();
+    let home = work.path().join("home");
+    write_file(&home.join(".bashrc"), "alias ll='ls -l'\n");
+    write_file(&home.join(".cache/big"), "scratch\n");
+    let components = vec![BackupComponentMeta {
+        source: ComponentSource::Directory {
+            root: home.clone(),
+            exclude_paths: vec![home.join(".cache")],
+        },
+        ..home_component(&home)
+    }];
+    let args = BackupArgs {
+        archive_path: Some(work.path().join("archives").to_string_lossy().to_string()),
+        home: true,
+        ..Default::default()
+    };
+    let archive = common::backup(&components, &args);
+
+    let old = read_archive(&archive, None).unwrap();
+    assert_eq!(old.exclude_dir, names(&[".cache"]));
+    assert!(!old.files.contains_key("homeb/.cache/big"));
+
+    // Without an --exclude-dir of its own the scan still skips the cache
+    write_file(&home.join(".cache/new"), "more scratch\n");
+    let live = read_live(&home, &old, &[], &archive).unwrap();
+    let diff = diff_snapshots(&old, &live, &PathFilter::new(&[], &[]).unwrap());
+    assert!(diff.changes.iter().all(|(_, changes)| changes.is_empty()));
+}
+
+#[test]
+fn test_diff_package_versions() {
+    let snapshot = |label: &str, packages: &[(&str, &str)]| Snapshot {
+        label: label.to_string(),
+        components: HashSet::from([Component::Apps]),
+        files: BTreeMap::new(),
+        packages: packages
+            .iter()
+            .map(|(name, version)| (name.to_string(), version.to_string()))
+            .collect(),
+        flatpaks: BTreeSet::new(),
+        exclude_dir: Vec::new(),
+    };
+    let old = snapshot("old", &[("bash", "5.2.026-2"), ("vim", "9.1-1"), ("paru", "")]);
+    let new = snapshot("new", &[("bash", "5.2.037-1"), ("htop", "3.3.0-1"), ("paru", "2.0.3-1")]);
+
+    let diff = diff_snapshots(&old, &new, &PathFilter::new(&[], &[]).unwrap());
+    assert_eq!(
+        diff.changes,
+        vec![(
+            Component::Apps,
+            ComponentDiff {
+                added: names(&["htop"]),
+                removed: names(&["vim"]),
+                // Lists without versions cannot tell an upgrade
+                modified: names(&["bash 5.2.026-2 -> 5.2.037-1"]),
+            }
+        )]
+    );
+}
@@ -0,0 +1,40 @@
+use std::path::Path;
+
+use parch_backup::utils::filter::PathFilter;
+
+fn filter(include: &[&str], exclude: &[&str]) -> PathFilter {
+    let include: Vec<String> = include.iter().map(|s| s.to_string()).collect();
+    let exclude: Vec<String> = exclude.iter().map(|s| s.to_string()).collect();
+    PathFilter::new(&include, &exclude).unwrap()
+}
+
+#[test]
+fn test_empty_filter_matches_everything() {
+    let f = filter(&[], &[]);
+    assert!(f.is_empty());
+    assert!(f.matches(Path::new(".bashrc")));
+    assert!(f.matches(Path::new(".config/nvim/init.lua")));
+}
+
+#[test]
+fn test_directory_pattern_selects_contents() {
+    let f = filter(&["~/.config/nvim/"], &[]);
+    assert!(f.matches(Path::new(".config/nvim")));
+    assert!(f.matches(Path::new(".config/nvim/lua/plugins.lua")));
+    assert!(!f.matches(Path::new(".config/nvim-old/init.lua")));
+    assert!(!f.matches(Path::new(".config")));
+}
+
+#[test]
+fn test_exclude_wins_over_include() {
+    let f = filter(&[".config/*"], &[".config/*/cache"]);
+    assert!(f.matches(Path::new(".config/app/settings.json")));
+    assert!(!f.matches(Path::new(".config/app/cache/blob")));
+    assert!(!f.matches(Path::new("docs/a.txt")));
+}
+
+#[test]
+fn test_invalid_pattern_is_rejected() {
+    let include = vec!["[unclosed".to_string()];
+    assert!(PathFilter::new(&include, &[]).is_err());
+}
@@ -0,0 +1,202 @@
+mod common;
+
+use common::{home_component, write_file};
+use parch_backup::backup::consolidate::{consolidate_backups, BackupComponentMeta, ComponentSource};
+use parch_backup::cli::{BackupArgs, RestoreArgs};
+use parch_backup::list::list::{walk_archive, ArchiveEntry};
+use parch_backup::pbar::PbarArchive;
+use parch_backup::restore::conflict::ConflictPolicy;
+use parch_backup::restore::restore::handle_restore;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+
+fn backup(home: &Path, archive_dir: &Path, incremental_from: Option<&str>) -> PathBuf {
+    let args = BackupArgs {
+        archive_path: Some(archive_dir.to_string_lossy().to_string()),
+        home: true,
+        encrypt: true,
+        encrypt_key: Some("pw".to_string()),
+        incremental_from: incremental_from.map(str::to_string),
+        ..Default::default()
+    };
+    common::backup(&[home_component(home)], &args)
+}
+
+fn stored_files(archive: &Path) -> Vec<String> {
+    let mut paths = Vec::new();
+    walk_archive(PbarArchive::open(archive, Some("pw")).unwrap(), |entry, _| {
+        if !entry.entry_type.is_dir() {
+            paths.push(entry.path.to_string_lossy().to_string());
+        }
+        Ok(())
+    })
+    .unwrap();
+    paths.sort();
+    paths
+}
+
+fn restore_args(archive: &Path, target: &Path) -> RestoreArgs {
+    RestoreArgs {
+        archive_path: Some(archive.to_path_buf()),
+        decrypt: true,
+        decrypt_key: Some("pw".to_string()),
+        target: Some(target.to_string_lossy().to_string()),
+        ..Default::default()
+    }
+}
+
+fn restore(archive: &Path, target: &Path) {
+    handle_restore(&restore_args(archive, target)).expect("Restore archive");
+}
+
+#[test]
+fn test_incremental_chain_restores_each_point() {
+    let work = tempfile::tempdir().unwrap();
+    let home = work.path().join("home");
+    let archive_dir = work.path().join("archives");
+
+    write_file(&home.join(".bashrc"), "alias ll='ls -l'\n");
+    write_file(&home.join("notes/a.txt"), "first\n");
+    write_file(&home.join("notes/old.txt"), "to be deleted\n");
+    let full = backup(&home, &archive_dir, None);
+
+    // Same contents rewritten with a new mtime must not be stored again
+    write_file(&home.join(".bashrc"), "alias ll='ls -l'\n");
+    write_file(&home.join("notes/a.txt"), "second\n");
+    write_file(&home.join("notes/b.txt"), "new\n");
+    fs::remove_file(home.join("notes/old.txt")).unwrap();
+    let first = backup(&home, &archive_dir, Some(full.to_str().unwrap()));
+
+    write_file(&home.join("notes/b.txt"), "newer\n");
+    let second = backup(&home, &archive_dir, Some("auto"));
+
+    assert_eq!(stored_files(&first), vec!["notes/a.txt", "notes/b.txt"]);
+    assert_eq!(stored_files(&second), vec!["notes/b.txt"]);
+
+    let mut archive = PbarArchive::open(&first, Some("pw")).unwrap();
+    let increment = archive.manifest.increment.clone().expect("Increment info");
+    assert_eq!(increment.base, full.file_name().unwrap().to_string_lossy());
+    let index = archive.read_index().unwrap().expect("File index");
+    assert_eq!(index.deleted, vec!["homeb/notes/old.txt"]);
+
+    let at_first = work.path().join("at-first");
+    restore(&first, &at_first);
+    assert_eq!(fs::read_to_string(at_first.join(".bashrc")).unwrap(), "alias ll='ls -l'\n");
+    assert_eq!(fs::read_to_string(at_first.join("notes/a.txt")).unwrap(), "second\n");
+    assert_eq!(fs::read_to_string(at_first.join("notes/b.txt")).unwrap(), "new\n");
+    assert!(!at_first.join("notes/old.txt").exists());
+
+    let at_second = work.path().join("at-second");
+    restore(&second, &at_second);
+    assert_eq!(fs::read_to_string(at_second.join(".bashrc")).unwrap(), "alias ll='ls -l'\n");
+    assert_eq!(fs::read_to_string(at_second.join("notes/a.txt")).unwrap(), "second\n");
+    assert_eq!(fs::read_to_string(at_second.join("notes/b.txt")).unwrap(), "newer\n");
+
+    let at_full = work.path().join("at-full");
+    restore(&full, &at_full);
+    assert_eq!(fs::read_to_string(at_full.join("notes/a.txt")).unwrap(), "first\n");
+    assert!(at_full.join("notes/old.txt").exists());
+
+    // Restoring the increment over the full restore only removes the deleted
+    // file with --delete, and not when the conflict policy keeps what is on disk
+    restore(&first, &at_full);
+    assert_eq!(fs::read_to_string(at_full.join("notes/a.txt")).unwrap(), "second\n");
+    assert!(at_full.join("notes/old.txt").exists());
+    handle_restore(&RestoreArgs {
+        conflict: ConflictPolicy::Skip,
+        delete: true,
+        ..restore_args(&first, &at_full)
+    })
+    .expect("Restore archive");
+    assert!(at_full.join("notes/old.txt").exists());
+    handle_restore(&RestoreArgs {
+        delete: true,
+        ..restore_args(&first, &at_full)
+    })
+    .expect("Restore archive");
+    assert!(!at_full.join("notes/old.txt").exists());
+}
+
+#[test]
+fn test_restore_fails_without_base_archive() {
+    let work = tempfile::tempdir().unwrap();
+    let home = work.path().join("home");
+    let archive_dir = work.path().join("archives");
+
+    write_file(&home.join("a.txt"), "a\n");
+    write_file(&home.join("b.txt"), "b\n");
+    let full = backup(&home, &archive_dir, None);
+    write_file(&home.join("b.txt"), "changed\n");
+    let increment = backup(&home, &archive_dir, Some("auto"));
+
+    fs::remove_file(&full).unwrap();
+    let target = work.path().join("target");
+    let result = handle_restore(&RestoreArgs {
+        archive_path: Some(increment.clone()),
+        decrypt: true,
+        decrypt_key: Some("pw".to_string()),
+        target: Some(target.to_string_lossy().to_string()),
+        ..Default::default()
+    });
+    assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+}
+
+#[test]
+fn test_file_index_matches_payload() {
+    let work = tempfile::tempdir().unwrap();
+    let home = work.path().join("home");
+    write_file(&home.join(".bashrc"), "alias ll='ls -l'\n");
+    write_file(&home.join(".ssh/config"), "Host *\n");
+    fs::set_permissions(home.join(".ssh/config"), fs::Permissions::from_mode(0o600)).unwrap();
+
+    let mut components = vec![home_component(&home)];
+    components.push(BackupComponentMeta {
+        category: "appsb",
+        source: ComponentSource::Data {
+            file_name: "apps.txt",
+            contents: b"bash\nvim\n".to_vec(),
+        },
+        count: 2,
+        size_bytes: 0,
+        extra_info: None,
+    });
+    let args = BackupArgs {
+        archive_path: Some(work.path().join("archives").to_string_lossy().to_string()),
+        apps: true,
+        home: true,
+        ..Default::default()
+    };
+    let archive = consolidate_backups(&components, &args, &AtomicBool::new(false)).expect("Create archive");
+
+    let mut archive = PbarArchive::open(&archive, None).unwrap();
+    let mut indexed: Vec<(String, u64, u32, u64)> = archive
+        .read_index()
+        .unwrap()
+        .expect("File index")
+        .files
+        .iter()
+        .map(|record| {
+            let entry = ArchiveEntry::from_record(record).expect("Known component");
+            (entry.path.to_string_lossy().to_string(), entry.size, entry.mode, entry.mtime)
+        })
+        .collect();
+    indexed.sort();
+
+    let mut stored = Vec::new();
+    walk_archive(archive, |entry, _| {
+        if !entry.entry_type.is_dir() {
+            let mode = entry.mode & 0o7777;
+            stored.push((entry.path.to_string_lossy().to_string(), entry.size, mode, entry.mtime));
+        }
+        Ok(())
+    })
+    .unwrap();
+    stored.sort();
+
+    let paths: Vec<&str> = indexed.iter().map(|(path, ..)| path.as_str()).collect();
+    assert_eq!(paths, vec![".bashrc", ".ssh/config", "apps.txt"]);
+    assert_eq!(indexed[1].2, 0o600);
+    assert_eq!(indexed, stored);
+}
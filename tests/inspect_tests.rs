@@ -0,0 +1,148 @@
+mod common;
+
+use common::{home_component, write_file};
+use parch_backup::backup::consolidate::{BackupComponentMeta, ComponentSource};
+use parch_backup::cli::{BackupArgs, InspectArgs};
+use parch_backup::inspect::inspect::inspect_archive;
+use parch_backup::pbar::header::PBAR_VERSION;
+use parch_backup::pbar::stream::{CipherKind, KdfParams};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn backup(work: &Path, args: BackupArgs) -> PathBuf {
+    let home = work.join("home");
+    write_file(&home.join(".bashrc"), "alias ll='ls -l'\n");
+    let components = vec![
+        BackupComponentMeta {
+            category: "appsb",
+            source: ComponentSource::Data {
+                file_name: "apps.txt",
+                contents: b"bash\nvim\n".to_vec(),
+            },
+            count: 2,
+            size_bytes: 9,
+            extra_info: None,
+        },
+        home_component(&home),
+    ];
+    common::backup(
+        &components,
+        &BackupArgs {
+            archive_path: Some(work.join("archives").to_string_lossy().to_string()),
+            apps: true,
+            home: true,
+            ..args
+        },
+    )
+}
+
+fn inspect_args(archive: &Path, decrypt_key: Option<&str>) -> InspectArgs {
+    InspectArgs {
+        archive_path: archive.to_string_lossy().to_string(),
+        decrypt_key: decrypt_key.map(str::to_string),
+        json: true,
+    }
+}
+
+#[test]
+fn test_inspect_plain_archive() {
+    let work = tempfile::tempdir().unwrap();
+    let archive = backup(work.path(), BackupArgs::default());
+
+    let report = inspect_archive(&inspect_args(&archive, None)).unwrap();
+    assert_eq!(report.archive, archive.display().to_string());
+    assert_eq!(report.size_bytes, fs::metadata(&archive).unwrap().len());
+    let header = &report.header;
+    assert_eq!(header.version, PBAR_VERSION);
+    assert_eq!(header.flags, vec!["compressed", "index"]);
+    assert_eq!(header.compression, "gzip");
+    assert_eq!(header.cipher, None);
+    assert_eq!(header.kdf, None);
+    assert!(header.manifest_size > 0);
+    assert_eq!(header.signed_by, None);
+
+    let manifest = report.manifest.as_ref().expect("Plain manifest");
+    assert!(!manifest.security.encrypted);
+    assert!(manifest.archive_contents.apps.included);
+    assert!(manifest.archive_contents.home_dotfiles.included);
+    let index = report.index.expect("Index totals");
+    assert_eq!(index.files, 2);
+    assert_eq!(index.size_bytes, 9 + "alias ll='ls -l'\n".len() as u64);
+    assert_eq!(index.deleted, 0);
+
+    // Fleet scripts read these keys from `inspect --json`
+    let json = serde_json::to_value(&report).unwrap();
+    let keys = |value: &serde_json::Value| {
+        let mut keys: Vec<String> = value.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        keys
+    };
+    assert_eq!(
+        keys(&json),
+        vec!["archive", "header", "index", "manifest", "size_bytes"]
+    );
+    // The file records themselves are left to `list --index`
+    assert_eq!(keys(&json["index"]), vec!["deleted", "files", "size_bytes"]);
+    assert!(json["manifest"].get("files").is_none());
+    assert_eq!(
+        keys(&json["header"]),
+        vec![
+            "cipher",
+            "compression",
+            "feature_flags",
+            "flags",
+            "kdf",
+            "manifest_size",
+            "signed_by",
+            "version"
+        ]
+    );
+}
+
+#[test]
+fn test_inspect_encrypted_archive() {
+    let work = tempfile::tempdir().unwrap();
+    let kdf = KdfParams {
+        memory_kib: 1024,
+        iterations: 1,
+        parallelism: 1,
+    };
+    let archive = backup(
+        work.path(),
+        BackupArgs {
+            encrypt: true,
+            encrypt_key: Some("pw".to_string()),
+            cipher: Some(CipherKind::ChaCha20Poly1305),
+            kdf_memory: Some(kdf.memory_kib),
+            kdf_iterations: Some(kdf.iterations),
+            kdf_parallelism: Some(kdf.parallelism),
+            ..Default::default()
+        },
+    );
+
+    // The header is readable without the key, the manifest is not
+    let report = inspect_archive(&inspect_args(&archive, None)).unwrap();
+    let header = &report.header;
+    assert_eq!(
+        header.flags,
+        vec!["encrypted", "compressed", "chacha20", "encrypted-manifest", "kdf-params", "index"]
+    );
+    assert_eq!(header.compression, "gzip");
+    assert_eq!(header.cipher, Some("ChaCha20-Poly1305"));
+    assert_eq!(header.kdf, Some(kdf));
+    assert_eq!(header.signed_by, None);
+    assert!(report.manifest.is_none());
+    assert!(report.index.is_none());
+    let json = serde_json::to_value(&report).unwrap();
+    assert!(json["manifest"].is_null());
+    assert!(json["index"].is_null());
+
+    let report = inspect_archive(&inspect_args(&archive, Some("pw"))).unwrap();
+    assert_eq!(report.header.kdf, Some(kdf));
+    assert_eq!(report.index.map(|index| index.files), Some(2));
+    let manifest = report.manifest.expect("Decrypted manifest");
+    assert!(manifest.security.encrypted);
+    assert_eq!(manifest.security.cipher, "ChaCha20-Poly1305");
+
+    assert!(inspect_archive(&inspect_args(&archive, Some("wrong"))).is_err());
+}
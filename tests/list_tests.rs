@@ -0,0 +1,111 @@
+mod common;
+
+use common::{nested_tarball, write_file};
+use parch_backup::backup::consolidate::{BackupComponentMeta, ComponentSource};
+use parch_backup::cli::BackupArgs;
+use parch_backup::list::list::walk_archive;
+use parch_backup::pbar::component::Component;
+use parch_backup::pbar::PbarArchive;
+use parch_backup::utils::compression::CompressionCodec;
+use std::fs;
+
+#[test]
+fn test_walk_payload_and_nested_tarballs() {
+    let work = tempfile::tempdir().unwrap();
+    let home = work.path().join("home");
+    let ssh = work.path().join("ssh");
+    write_file(&home.join(".config/nvim/init.lua"), "vim.o.number = true\n");
+    write_file(&home.join("notes/todo.txt"), "back up everything\n");
+    write_file(&ssh.join("config"), "Host *\n");
+
+    // Older archives store the home directory as a nested tarball
+    let nested = work.path().join("home_backup.tar.zst");
+    nested_tarball(&home, &nested, CompressionCodec::Zstd);
+    let components = vec![
+        BackupComponentMeta {
+            category: "appsb",
+            source: ComponentSource::Data {
+                file_name: "apps.txt",
+                contents: b"bash\nvim\n".to_vec(),
+            },
+            count: 2,
+            size_bytes: 9,
+            extra_info: None,
+        },
+        BackupComponentMeta {
+            category: "homeb",
+            source: ComponentSource::File(nested.clone()),
+            count: 2,
+            size_bytes: fs::metadata(&nested).unwrap().len(),
+            extra_info: None,
+        },
+        BackupComponentMeta {
+            category: "sshb",
+            source: ComponentSource::Directory {
+                root: ssh.clone(),
+                exclude_paths: Vec::new(),
+            },
+            count: 1,
+            size_bytes: 7,
+            extra_info: None,
+        },
+    ];
+    let archive = common::backup(
+        &components,
+        &BackupArgs {
+            archive_path: Some(work.path().join("archives").to_string_lossy().to_string()),
+            apps: true,
+            home: true,
+            keys: true,
+            encrypt: true,
+            encrypt_key: Some("pw".to_string()),
+            ..Default::default()
+        },
+    );
+
+    let mut files = Vec::new();
+    walk_archive(PbarArchive::open(&archive, Some("pw")).unwrap(), |entry, data| {
+        if entry.entry_type.is_file() {
+            let mut contents = String::new();
+            data.read_to_string(&mut contents)?;
+            files.push((
+                entry.component,
+                entry.path.to_string_lossy().to_string(),
+                entry.payload_path.to_string_lossy().to_string(),
+                contents,
+            ));
+        }
+        Ok(())
+    })
+    .unwrap();
+    files.sort_by(|a, b| a.2.cmp(&b.2));
+
+    let file = |component, path: &str, payload_path: &str, contents: &str| {
+        (
+            component,
+            path.to_string(),
+            payload_path.to_string(),
+            contents.to_string(),
+        )
+    };
+    // manifest.json and tree.json are not components and are not visited
+    assert_eq!(
+        files,
+        vec![
+            file(Component::Apps, "apps.txt", "appsb/apps.txt", "bash\nvim\n"),
+            file(
+                Component::Home,
+                ".config/nvim/init.lua",
+                "homeb/.config/nvim/init.lua",
+                "vim.o.number = true\n"
+            ),
+            file(
+                Component::Home,
+                "notes/todo.txt",
+                "homeb/notes/todo.txt",
+                "back up everything\n"
+            ),
+            file(Component::Ssh, ".ssh/config", "sshb/config", "Host *\n"),
+        ]
+    );
+}
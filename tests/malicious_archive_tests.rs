@@ -0,0 +1,216 @@
+//! Crafted archives that try to write outside the restore root. Each case
+//! builds a hostile `home_backup.tar.gz`, wraps it in a PBAR container and
+//! checks that restoring it fails without touching anything outside.
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use parch_backup::backup::consolidate::{consolidate_backups, BackupComponentMeta, ComponentSource};
+use parch_backup::cli::{BackupArgs, RestoreArgs};
+use parch_backup::restore::extract::safe_join;
+use parch_backup::restore::restore::handle_restore;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+
+/// One raw tar member. Names are written straight into the header so that
+/// `tar::Builder`'s own path checks do not sanitize them.
+struct Member<'a> {
+    name: &'a [u8],
+    kind: tar::EntryType,
+    link: Option<&'a [u8]>,
+    data: &'a [u8],
+}
+
+fn file<'a>(name: &'a str, data: &'a str) -> Member<'a> {
+    Member {
+        name: name.as_bytes(),
+        kind: tar::EntryType::Regular,
+        link: None,
+        data: data.as_bytes(),
+    }
+}
+
+fn link<'a>(name: &'a str, kind: tar::EntryType, target: &'a str) -> Member<'a> {
+    Member {
+        name: name.as_bytes(),
+        kind,
+        link: Some(target.as_bytes()),
+        data: b"",
+    }
+}
+
+fn dir(name: &str) -> Member<'_> {
+    Member {
+        name: name.as_bytes(),
+        kind: tar::EntryType::Directory,
+        link: None,
+        data: b"",
+    }
+}
+
+fn write_nested_tarball(path: &Path, members: &[Member]) {
+    let enc = GzEncoder::new(fs::File::create(path).unwrap(), Compression::default());
+    let mut builder = tar::Builder::new(enc);
+    for member in members {
+        let mut header = tar::Header::new_gnu();
+        {
+            let gnu = header.as_gnu_mut().unwrap();
+            gnu.name[..member.name.len()].copy_from_slice(member.name);
+            if let Some(target) = member.link {
+                gnu.linkname[..target.len()].copy_from_slice(target);
+            }
+        }
+        header.set_entry_type(member.kind);
+        header.set_mode(if member.kind.is_dir() { 0o755 } else { 0o644 });
+        header.set_size(member.data.len() as u64);
+        header.set_cksum();
+        builder.append(&header, member.data).unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap();
+}
+
+struct Case {
+    _work: tempfile::TempDir,
+    target: PathBuf,
+    outside: PathBuf,
+    archive: PathBuf,
+}
+
+/// Packs `members` as the home component and prepares an empty restore
+/// target plus a sibling directory that must stay untouched.
+fn build_case(members: &[Member]) -> Case {
+    let work = tempfile::tempdir().unwrap();
+    let target = work.path().join("target");
+    let outside = work.path().join("outside");
+    fs::create_dir_all(&target).unwrap();
+    fs::create_dir_all(&outside).unwrap();
+    fs::write(outside.join("secret"), "do not touch").unwrap();
+
+    let component = work.path().join("home_backup.tar.gz");
+    write_nested_tarball(&component, members);
+    let components = vec![BackupComponentMeta {
+        category: "homeb",
+        source: ComponentSource::File(component.clone()),
+        count: 1,
+        size_bytes: fs::metadata(&component).unwrap().len(),
+        extra_info: None,
+    }];
+    let backup_args = BackupArgs {
+        archive_path: Some(work.path().join("archives").to_string_lossy().to_string()),
+        home: true,
+        ..Default::default()
+    };
+    let archive = consolidate_backups(&components, &backup_args, &AtomicBool::new(false)).unwrap();
+
+    Case {
+        _work: work,
+        target,
+        outside,
+        archive,
+    }
+}
+
+fn restore(case: &Case) -> std::io::Result<()> {
+    handle_restore(&RestoreArgs {
+        archive_path: Some(case.archive.clone()),
+        target: Some(case.target.to_string_lossy().to_string()),
+        ..Default::default()
+    })
+}
+
+fn assert_outside_untouched(case: &Case) {
+    let mut names: Vec<_> = fs::read_dir(&case.outside)
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["secret"]);
+    assert_eq!(fs::read_to_string(case.outside.join("secret")).unwrap(), "do not touch");
+}
+
+#[test]
+fn test_parent_dir_traversal_is_rejected() {
+    let case = build_case(&[file("../outside/escaped", "pwned")]);
+    assert!(restore(&case).is_err());
+    assert_outside_untouched(&case);
+}
+
+#[test]
+fn test_nested_parent_dir_traversal_is_rejected() {
+    let case = build_case(&[file("docs/../../outside/escaped", "pwned")]);
+    assert!(restore(&case).is_err());
+    assert_outside_untouched(&case);
+}
+
+#[test]
+fn test_absolute_path_is_rejected() {
+    let work = tempfile::tempdir().unwrap();
+    let absolute = work.path().join("absolute-escape");
+    let case = build_case(&[file(absolute.to_str().unwrap(), "pwned")]);
+    assert!(restore(&case).is_err());
+    assert!(!absolute.exists());
+}
+
+#[test]
+fn test_write_through_symlinked_parent_is_rejected() {
+    let work = tempfile::tempdir().unwrap();
+    let outside = work.path().to_string_lossy().to_string();
+    let case = build_case(&[
+        link("evil", tar::EntryType::Symlink, &outside),
+        file("evil/escaped", "pwned"),
+    ]);
+    assert!(restore(&case).is_err());
+    assert!(!work.path().join("escaped").exists());
+}
+
+#[test]
+fn test_directory_over_symlink_is_rejected() {
+    let work = tempfile::tempdir().unwrap();
+    let outside = work.path().to_string_lossy().to_string();
+    let case = build_case(&[link(".config", tar::EntryType::Symlink, &outside), dir(".config")]);
+    assert!(restore(&case).is_err());
+}
+
+#[test]
+fn test_hard_link_out_of_root_is_rejected() {
+    let case = build_case(&[link("stolen", tar::EntryType::Link, "../outside/secret")]);
+    assert!(restore(&case).is_err());
+    assert!(!case.target.join("stolen").exists());
+    assert_outside_untouched(&case);
+}
+
+#[test]
+fn test_device_node_is_rejected() {
+    let mut node = file("dev-null", "");
+    node.kind = tar::EntryType::Char;
+    let case = build_case(&[node]);
+    assert!(restore(&case).is_err());
+}
+
+#[test]
+fn test_plain_symlinks_and_hard_links_still_restore() {
+    let case = build_case(&[
+        file("dotfiles/bashrc", "export EDITOR=nvim\n"),
+        link(".bashrc", tar::EntryType::Symlink, "dotfiles/bashrc"),
+        link(".bashrc.bak", tar::EntryType::Link, "dotfiles/bashrc"),
+    ]);
+    restore(&case).expect("Benign archive restores");
+
+    let restored = case.target.join(".bashrc");
+    assert!(fs::symlink_metadata(&restored).unwrap().file_type().is_symlink());
+    assert_eq!(
+        fs::read_to_string(case.target.join(".bashrc.bak")).unwrap(),
+        "export EDITOR=nvim\n"
+    );
+}
+
+#[test]
+fn test_safe_join_rejects_unsafe_components() {
+    let base = Path::new("/restore/root");
+    assert_eq!(
+        safe_join(base, Path::new("./a/b")).unwrap(),
+        PathBuf::from("/restore/root/a/b")
+    );
+    assert!(safe_join(base, Path::new("a/../../b")).is_err());
+    assert!(safe_join(base, Path::new("/etc/passwd")).is_err());
+}
@@ -0,0 +1,133 @@
+use parch_backup::pm::packages::{
+    explicit_packages, find_cached_package, parse_query_info, parse_sync_list, plan_install,
+    read_package_list, InstallReason, Package,
+};
+use std::fs;
+use std::path::Path;
+
+const QUERY_INFO: &str = "\
+Name            : bash
+Version         : 5.2.026-2
+Description     : The GNU Bourne Again shell
+Groups          : None
+Optional Deps   : bash-completion: for tab completion
+                  bash-docs: for documentation
+Install Reason  : Explicitly installed
+
+Name            : gcc
+Version         : 14.1.1+r1+g43b730b9134-1
+Groups          : base-devel  devel
+Install Reason  : Installed as a dependency for another package
+
+Name            : paru
+Version         : 1:2.0.3-1
+Groups          : None
+Install Reason  : Explicitly installed
+";
+
+#[test]
+fn test_parse_pacman_output() {
+    let packages = parse_query_info(QUERY_INFO);
+    assert_eq!(packages.len(), 3);
+    assert_eq!(packages[0].name, "bash");
+    assert_eq!(packages[0].version, "5.2.026-2");
+    assert!(packages[0].groups.is_empty());
+    assert_eq!(packages[0].reason, InstallReason::Explicit);
+    assert_eq!(packages[1].groups, vec!["base-devel", "devel"]);
+    assert_eq!(packages[1].reason, InstallReason::Dependency);
+    assert_eq!(packages[2].version, "1:2.0.3-1");
+
+    let names: Vec<String> = explicit_packages(packages).map(|p| p.name).collect();
+    assert_eq!(names, vec!["bash", "paru"]);
+
+    let repos = parse_sync_list("core bash 5.2.026-2 [installed]\ntesting gcc 14.2.0-1\ncore gcc 14.1.1-1\n");
+    assert_eq!(repos["bash"], "core");
+    // The first repository listed wins, like pacman's own order
+    assert_eq!(repos["gcc"], "testing");
+}
+
+#[test]
+fn test_read_package_lists() {
+    let json = r#"[
+        {"name": "bash", "version": "5.2.026-2", "repo": "core", "reason": "explicit"},
+        {"name": "paru", "version": "2.0.3-1", "repo": "foreign", "reason": "explicit", "groups": ["aur"]}
+    ]"#;
+    let packages = read_package_list(Path::new("packages.json"), json).unwrap();
+    assert!(!packages[0].is_foreign());
+    assert!(packages[1].is_foreign());
+    assert_eq!(packages[1].groups, vec!["aur"]);
+    assert!(read_package_list(Path::new("packages.json"), "bash\nvim\n").is_err());
+
+    // Older archives only have names
+    let packages = read_package_list(Path::new("apps.txt"), "bash\n\nvim\n").unwrap();
+    assert_eq!(packages, vec![Package::from_name("bash"), Package::from_name("vim")]);
+    assert!(packages[0].repo.is_empty());
+}
+
+#[test]
+fn test_find_cached_package() {
+    let cache = tempfile::tempdir().unwrap();
+    for name in [
+        "bash-5.2.026-2-x86_64.pkg.tar.zst",
+        "bash-5.2.026-2-x86_64.pkg.tar.zst.sig",
+        "bash-completion-2.14.0-1-any.pkg.tar.zst",
+        "bash-5.2.037-1-x86_64.pkg.tar.zst",
+    ] {
+        fs::write(cache.path().join(name), "").unwrap();
+    }
+
+    let mut bash = Package::from_name("bash");
+    bash.version = "5.2.026-2".to_string();
+    assert_eq!(
+        find_cached_package(cache.path(), &bash),
+        Some(cache.path().join("bash-5.2.026-2-x86_64.pkg.tar.zst"))
+    );
+
+    bash.version = "5.1.016-1".to_string();
+    assert_eq!(find_cached_package(cache.path(), &bash), None);
+    // Without a version there is nothing to pin
+    assert_eq!(find_cached_package(cache.path(), &Package::from_name("bash")), None);
+    let mut completion = Package::from_name("bash-completion");
+    completion.version = "2.14.0-1".to_string();
+    assert!(find_cached_package(cache.path(), &completion).is_some());
+}
+
+#[test]
+fn test_plan_install_splits_native_and_foreign() {
+    let package = |name: &str, version: &str, repo: &str| Package {
+        version: version.to_string(),
+        repo: repo.to_string(),
+        ..Package::from_name(name)
+    };
+    let packages = vec![
+        package("bash", "5.2.026-2", "core"),
+        package("paru", "2.0.3-1", "foreign"),
+        // Names from an older archive are sorted by the sync repositories
+        Package::from_name("vim"),
+        Package::from_name("yay"),
+        // Dropped from the repositories since the backup
+        package("oldtool", "1.0-1", "extra"),
+    ];
+    let sync_repos = parse_sync_list("core bash 5.2.037-1\nextra vim 9.1-1\n");
+
+    let plan = plan_install(&packages, &sync_repos, None);
+    assert!(plan.cached_native.is_empty() && plan.cached_foreign.is_empty());
+    assert_eq!(plan.native, vec!["bash", "vim"]);
+    assert_eq!(plan.foreign, vec!["paru", "yay", "oldtool"]);
+
+    let cache = tempfile::tempdir().unwrap();
+    fs::write(cache.path().join("bash-5.2.026-2-x86_64.pkg.tar.zst"), "").unwrap();
+    fs::write(cache.path().join("paru-2.0.3-1-x86_64.pkg.tar.zst"), "").unwrap();
+    let plan = plan_install(&packages, &sync_repos, Some(cache.path()));
+    // Cached foreign packages still wait for the native ones
+    assert_eq!(
+        plan.cached_native,
+        vec![("bash".to_string(), cache.path().join("bash-5.2.026-2-x86_64.pkg.tar.zst"))]
+    );
+    assert_eq!(plan.native, vec!["vim"]);
+    assert_eq!(
+        plan.cached_foreign,
+        vec![("paru".to_string(), cache.path().join("paru-2.0.3-1-x86_64.pkg.tar.zst"))]
+    );
+    assert_eq!(plan.foreign, vec!["yay", "oldtool"]);
+}
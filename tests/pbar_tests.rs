@@ -1,8 +1,14 @@
-use parch_backup::pbar::header::{PbarHeader, PBAR_MAGIC};
+use parch_backup::pbar::header::{PbarHeader, MAX_MANIFEST_SIZE, PBAR_MAGIC};
+use parch_backup::pbar::index::ArchiveIndex;
 use parch_backup::pbar::manifest::{
-    ArchiveContents, ComponentInfo, HomeInfo, KeysInfo, PbarManifest, SecurityInfo, SystemInfo,
+    ArchiveContents, ComponentInfo, FileRecord, HomeInfo, KeysInfo, PbarManifest, SecurityInfo,
+    SystemInfo,
 };
-use parch_backup::pbar::stream::{derive_argon2_key, PbarChunkReader, PbarChunkWriter};
+use parch_backup::pbar::stream::{
+    derive_argon2_key, CipherKind, KdfParams, PbarChunkReader, PbarChunkWriter,
+};
+use parch_backup::pbar::PbarArchive;
+use parch_backup::utils::compression::CompressionCodec;
 use std::io::{Read, Write};
 
 #[test]
@@ -19,13 +25,13 @@ fn test_header_serialization_roundtrip() {
     assert_eq!(parsed.salt, original.salt);
     assert_eq!(parsed.base_nonce, original.base_nonce);
     assert_eq!(parsed.manifest_size, 512);
+    assert_eq!(parsed.index_offset, None);
     assert!(parsed.is_encrypted());
     assert!(parsed.is_compressed());
 }
 
-#[test]
-fn test_manifest_json_serialization() {
-    let manifest = PbarManifest {
+fn sample_manifest() -> PbarManifest {
+    PbarManifest {
         format_version: "1.2".to_string(),
         created_at: "2026-08-02T12:00:00Z".to_string(),
         creator: "Parch Backup v0.1.0".to_string(),
@@ -60,6 +66,7 @@ fn test_manifest_json_serialization() {
                 included: true,
                 uncompressed_size_bytes: 1048576,
                 file_path: Some("homeb/home_backup.tar.gz".to_string()),
+                exclude_dir: Vec::new(),
             },
             keys: KeysInfo {
                 included: true,
@@ -73,8 +80,14 @@ fn test_manifest_json_serialization() {
                 file_path: None,
             },
         },
-    };
+        increment: None,
+        repository: None,
+    }
+}
 
+#[test]
+fn test_manifest_json_serialization() {
+    let manifest = sample_manifest();
     let json_bytes = manifest.to_json_bytes().expect("To JSON");
     let parsed_manifest = PbarManifest::from_json_slice(&json_bytes).expect("From JSON");
 
@@ -106,3 +119,280 @@ fn test_streaming_encryption_roundtrip() {
 
     assert_eq!(sample_data, decrypted_data);
 }
+
+#[test]
+fn test_zstd_payload_codec_roundtrip() {
+    let mut header = PbarHeader::new(false, true, 0);
+    header.feature_flags |= CompressionCodec::Zstd.header_flag();
+    assert!(header.is_zstd());
+
+    let sample_data = b"zstd payload compressed inside the PBAR chunk stream ".repeat(4000);
+
+    let mut output_buf = Vec::new();
+    {
+        let writer = PbarChunkWriter::new(&mut output_buf, None, header.base_nonce);
+        let mut enc = CompressionCodec::Zstd.encoder(writer).expect("Zstd encoder");
+        enc.write_all(&sample_data).expect("Write data");
+        enc.finish().expect("Finish zstd").finish().expect("Finish writer");
+    }
+    assert!(output_buf.len() < sample_data.len());
+
+    let codec = CompressionCodec::from_flags(header.feature_flags);
+    assert_eq!(codec, CompressionCodec::Zstd);
+    let reader = PbarChunkReader::new(&output_buf[..], None, header.base_nonce);
+    let mut decoded = Vec::new();
+    codec
+        .decoder(reader)
+        .expect("Zstd decoder")
+        .read_to_end(&mut decoded)
+        .expect("Read decoded");
+
+    assert_eq!(sample_data, decoded);
+}
+
+#[test]
+fn test_chacha20_streaming_roundtrip() {
+    let key = derive_argon2_key(b"chacha-passphrase", &[9u8; 16]).expect("Argon2 KDF");
+    let base_nonce = [3u8; 12];
+    let sample_data = b"ChaCha20-Poly1305 chunk stream for machines without AES-NI ".repeat(2000);
+
+    let mut output_buf = Vec::new();
+    {
+        let mut writer = PbarChunkWriter::with_cipher(
+            &mut output_buf,
+            Some(key),
+            CipherKind::ChaCha20Poly1305,
+            base_nonce,
+        );
+        writer.write_all(&sample_data).expect("Write data");
+        writer.finish().expect("Finish writer");
+    }
+
+    let mut header = PbarHeader::new(true, true, 0);
+    header.feature_flags |= CipherKind::ChaCha20Poly1305.header_flag();
+    let cipher = CipherKind::from_flags(header.feature_flags);
+    assert_eq!(cipher, CipherKind::ChaCha20Poly1305);
+
+    let mut reader = PbarChunkReader::with_cipher(&output_buf[..], Some(key), cipher, base_nonce);
+    let mut decrypted_data = Vec::new();
+    reader.read_to_end(&mut decrypted_data).expect("Read decrypted");
+    assert_eq!(sample_data, decrypted_data);
+
+    // The same stream must not authenticate under AES-256-GCM.
+    let mut wrong = PbarChunkReader::new(&output_buf[..], Some(key), base_nonce);
+    assert!(wrong.read_to_end(&mut Vec::new()).is_err());
+}
+
+fn encrypt_chunks(key: [u8; 32], base_nonce: [u8; 12], data: &[u8]) -> Vec<u8> {
+    let mut output_buf = Vec::new();
+    let mut writer = PbarChunkWriter::new(&mut output_buf, Some(key), base_nonce);
+    writer.write_all(data).expect("Write data");
+    writer.finish().expect("Finish writer");
+    output_buf
+}
+
+#[test]
+fn test_truncated_stream_is_rejected() {
+    let key = derive_argon2_key(b"truncation", &[5u8; 16]).expect("Argon2 KDF");
+    let base_nonce = [1u8; 12];
+    // Exactly three full chunks; the third one is sealed as the final chunk.
+    let sample_data = vec![0xABu8; 3 * 64 * 1024];
+    let stream = encrypt_chunks(key, base_nonce, &sample_data);
+
+    let mut decrypted = Vec::new();
+    PbarChunkReader::new(&stream[..], Some(key), base_nonce)
+        .read_to_end(&mut decrypted)
+        .expect("Read complete stream");
+    assert_eq!(decrypted, sample_data);
+
+    // Drop the final chunk: every remaining chunk still authenticates.
+    let full_chunk = 4 + 64 * 1024 + 16;
+    assert_eq!(stream.len(), 3 * full_chunk);
+    let truncated = &stream[..2 * full_chunk];
+    let err = PbarChunkReader::new(truncated, Some(key), base_nonce)
+        .read_to_end(&mut Vec::new())
+        .expect_err("Truncated stream must fail");
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+
+    // Cutting inside a chunk is reported the same way.
+    let err = PbarChunkReader::new(&stream[..full_chunk + 100], Some(key), base_nonce)
+        .read_to_end(&mut Vec::new())
+        .expect_err("Partial chunk must fail");
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn test_data_after_final_chunk_is_rejected() {
+    let key = derive_argon2_key(b"trailing", &[7u8; 16]).expect("Argon2 KDF");
+    let base_nonce = [2u8; 12];
+    let mut stream = encrypt_chunks(key, base_nonce, b"payload");
+    stream.extend_from_slice(b"appended");
+
+    let err = PbarChunkReader::new(&stream[..], Some(key), base_nonce)
+        .read_to_end(&mut Vec::new())
+        .expect_err("Trailing data must fail");
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("after its final chunk"), "{}", err);
+}
+
+#[test]
+fn test_final_marker_cannot_be_forged_by_reordering() {
+    let key = derive_argon2_key(b"reorder", &[6u8; 16]).expect("Argon2 KDF");
+    let base_nonce = [2u8; 12];
+    let stream = encrypt_chunks(key, base_nonce, &vec![0x11u8; 100 * 1024]);
+
+    // Removing the first full chunk shifts the final chunk to index 0.
+    let full_chunk = 4 + 64 * 1024 + 16;
+    let err = PbarChunkReader::new(&stream[full_chunk..], Some(key), base_nonce)
+        .read_to_end(&mut Vec::new())
+        .expect_err("Shifted stream must fail");
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_kdf_params_stored_in_header() {
+    let params = KdfParams {
+        memory_kib: 8 * 1024,
+        iterations: 3,
+        parallelism: 2,
+    };
+    let mut header = PbarHeader::new(true, true, 64);
+    header.set_kdf_params(params);
+
+    let mut buffer = Vec::new();
+    header.write_to(&mut buffer).expect("Write header");
+    let parsed = PbarHeader::read_from(&buffer[..]).expect("Read header");
+    assert_eq!(parsed.kdf_params, Some(params));
+    assert_eq!(parsed.manifest_size, 64);
+
+    let key = parsed.derive_key(b"passphrase").expect("Derive key");
+    assert_eq!(key, header.derive_key(b"passphrase").expect("Derive key"));
+    assert_ne!(key, derive_argon2_key(b"passphrase", &header.salt).expect("Default KDF"));
+
+    // Headers without a KDF block fall back to the Argon2 defaults.
+    let legacy = PbarHeader::new(false, true, 0);
+    assert_eq!(legacy.kdf_params, None);
+    assert_eq!(
+        legacy.derive_key(b"passphrase").expect("Derive key"),
+        derive_argon2_key(b"passphrase", &legacy.salt).expect("Default KDF")
+    );
+}
+
+#[test]
+fn test_oversized_kdf_params_are_rejected() {
+    let within = KdfParams {
+        memory_kib: KdfParams::MAX_MEMORY_KIB,
+        iterations: KdfParams::MAX_ITERATIONS,
+        parallelism: KdfParams::MAX_PARALLELISM,
+    };
+    let malicious = [
+        KdfParams {
+            memory_kib: u32::MAX,
+            ..within
+        },
+        KdfParams {
+            iterations: KdfParams::MAX_ITERATIONS + 1,
+            ..within
+        },
+        KdfParams {
+            parallelism: u32::MAX,
+            ..within
+        },
+    ];
+    for params in malicious {
+        let mut header = PbarHeader::new(true, true, 64);
+        header.set_kdf_params(params);
+        let mut buffer = Vec::new();
+        header.write_to(&mut buffer).expect("Write header");
+
+        // Rejected before any key derivation is attempted
+        let err = PbarHeader::read_from(&buffer[..]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{:?}", params);
+        assert!(header.derive_key(b"passphrase").is_err());
+    }
+
+    let mut header = PbarHeader::new(true, true, 64);
+    header.set_kdf_params(within);
+    let mut buffer = Vec::new();
+    header.write_to(&mut buffer).expect("Write header");
+    assert_eq!(PbarHeader::read_from(&buffer[..]).unwrap().kdf_params, Some(within));
+}
+
+#[test]
+fn test_oversized_manifest_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("huge-manifest.pbar");
+    for size in [MAX_MANIFEST_SIZE + 1, u32::MAX] {
+        let header = PbarHeader::new(false, true, size);
+        let mut buffer = Vec::new();
+        header.write_to(&mut buffer).expect("Write header");
+        buffer.extend_from_slice(b"{}");
+        std::fs::write(&path, &buffer).unwrap();
+
+        // Refused before the block is allocated
+        let err = PbarArchive::open(&path, None).err().expect("Oversized manifest");
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("exceeds the limit"), "{}", err);
+    }
+}
+
+#[test]
+fn test_large_file_index_roundtrip() {
+    // Far more files than a manifest block could index
+    let index = ArchiveIndex {
+        files: (0..300_000)
+            .map(|i| FileRecord {
+                path: format!("homeb/.local/share/project-{}/src/module_{}.rs", i / 100, i),
+                size: i,
+                mode: 0o644,
+                mtime: 1_780_000_000 + i,
+                sha256: format!("{:064x}", i),
+                stored_in: (i % 3 == 0).then(|| "backup-2026-08-01-10-00-00-ah.pbar".to_string()),
+            })
+            .collect(),
+        deleted: vec!["homeb/notes/old.txt".to_string()],
+    };
+    let mut header = PbarHeader::new(true, true, 0);
+    header.set_index_offset(0);
+    let key = [7u8; 32];
+
+    let written = index.write_to(Vec::new(), &header, Some(key)).expect("Write index");
+    let read = ArchiveIndex::read_from(&written[..], &header, Some(key)).expect("Read index");
+    assert_eq!(read, index);
+    assert_eq!(read.total_size(), (0..300_000u64).sum::<u64>());
+
+    assert!(ArchiveIndex::read_from(&written[..], &header, Some([8u8; 32])).is_err());
+}
+
+#[test]
+fn test_large_manifest_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("large-manifest.pbar");
+    let header = PbarHeader::new(false, true, 0);
+
+    // A manifest just below the limit is written and read back
+    let mut manifest = sample_manifest();
+    let excluded = format!(".cache/{}", "x".repeat(1000));
+    let entries = (MAX_MANIFEST_SIZE as usize - 64 * 1024) / (excluded.len() + 16);
+    manifest.archive_contents.home_dotfiles.exclude_dir = vec![excluded.clone(); entries];
+    let block = manifest.to_block(&header, None).expect("Manifest below the limit");
+    assert!(block.len() > MAX_MANIFEST_SIZE as usize - 1024 * 1024);
+
+    let mut header = header;
+    header.manifest_size = block.len() as u32;
+    let mut buffer = Vec::new();
+    header.write_to(&mut buffer).expect("Write header");
+    buffer.extend_from_slice(&block);
+    std::fs::write(&path, &buffer).unwrap();
+    let archive = PbarArchive::open(&path, None).expect("Open large manifest");
+    assert_eq!(archive.manifest.archive_contents.home_dotfiles.exclude_dir.len(), entries);
+
+    // The writer refuses what readers would reject
+    manifest
+        .archive_contents
+        .home_dotfiles
+        .exclude_dir
+        .extend(vec![excluded; 1024]);
+    let err = manifest.to_block(&header, None).expect_err("Manifest above the limit");
+    assert!(err.to_string().contains("exceeds the limit"), "{}", err);
+}
@@ -0,0 +1,132 @@
+use parch_backup::cli::{BackupArgs, RetentionArgs};
+use parch_backup::config::profile::{load_config, save_config, BackupItem, Config, Profile};
+use parch_backup::pbar::CipherKind;
+use parch_backup::utils::compression::CompressionCodec;
+use std::fs;
+
+const CONFIG: &str = r#"
+[profiles.nightly]
+components = ["home", "keys"]
+exclude = [".cache", "Downloads"]
+destination = "/mnt/backups"
+compression = "zstd"
+cipher = "chacha20"
+
+[profiles.nightly.retention]
+keep_daily = 7
+keep_monthly = 12
+
+[profiles.packages]
+components = ["apps", "flatpak"]
+"#;
+
+#[test]
+fn test_load_profiles() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    assert_eq!(load_config(&path).unwrap(), Config::default());
+
+    fs::write(&path, CONFIG).unwrap();
+    let config = load_config(&path).unwrap();
+    let nightly = config.profile("nightly").unwrap();
+    assert_eq!(nightly.components, vec![BackupItem::Home, BackupItem::Keys]);
+    assert_eq!(nightly.compression, Some(CompressionCodec::Zstd));
+    assert_eq!(nightly.cipher, Some(CipherKind::ChaCha20Poly1305));
+    assert_eq!(nightly.retention.keep_daily, 7);
+    assert_eq!(config.profiles["packages"].destination, None);
+
+    let missing = config.profile("weekly").unwrap_err().to_string();
+    assert!(missing.contains("nightly, packages"), "{}", missing);
+
+    // Typos must not be silently ignored
+    fs::write(&path, "[profiles.x]\ncomponent = [\"home\"]\n").unwrap();
+    assert!(load_config(&path).is_err());
+    fs::write(&path, "[profiles.x]\ncomponents = [\"music\"]\n").unwrap();
+    assert!(load_config(&path).is_err());
+}
+
+#[test]
+fn test_save_and_reload_profile() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("parch-backup/config.toml");
+    let mut config = Config::default();
+    config.profiles.insert(
+        "laptop".to_string(),
+        Profile {
+            components: vec![BackupItem::Apps, BackupItem::Home],
+            exclude: vec!["Videos".to_string()],
+            destination: Some("~/Backups/laptop".to_string()),
+            compression: Some(CompressionCodec::Zstd),
+            retention: RetentionArgs {
+                keep_last: 3,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
+    save_config(&path, &config).unwrap();
+    assert_eq!(load_config(&path).unwrap(), config);
+}
+
+#[test]
+fn test_command_line_options_override_profile() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = dir.path().join("key");
+    fs::write(&key_file, "secret\n").unwrap();
+
+    let mut config: Config = toml::from_str(CONFIG).unwrap();
+    let nightly = config.profiles.get_mut("nightly").unwrap();
+    nightly.encrypt_key_file = Some(key_file.to_string_lossy().to_string());
+
+    let defaults = BackupArgs {
+        profile: Some("nightly".to_string()),
+        ..Default::default()
+    };
+    let merged = nightly.apply(&defaults).unwrap();
+    assert!(merged.profile.is_none());
+    assert!(merged.home && merged.keys && !merged.apps && !merged.flatpak);
+    assert_eq!(merged.archive_path.as_deref(), Some("/mnt/backups"));
+    assert_eq!(merged.exclude_dir, vec![".cache", "Downloads"]);
+    assert_eq!(merged.compression, Some(CompressionCodec::Zstd));
+    assert_eq!(merged.cipher, Some(CipherKind::ChaCha20Poly1305));
+    assert!(merged.encrypt);
+    assert_eq!(merged.encrypt_key.as_deref(), Some("secret"));
+    assert_eq!(merged.retention.keep_monthly, 12);
+
+    let explicit = BackupArgs {
+        archive_path: Some("/tmp/elsewhere".to_string()),
+        apps: true,
+        exclude_dir: vec!["Music".to_string()],
+        retention: RetentionArgs {
+            keep_last: 1,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let merged = nightly.apply(&explicit).unwrap();
+    assert!(merged.apps && merged.home);
+    assert_eq!(merged.archive_path.as_deref(), Some("/tmp/elsewhere"));
+    assert_eq!(merged.exclude_dir, vec![".cache", "Downloads", "Music"]);
+    assert_eq!(
+        merged.retention,
+        RetentionArgs {
+            keep_last: 1,
+            ..Default::default()
+        }
+    );
+
+    // Options given with their default value still override the profile
+    let explicit_defaults = BackupArgs {
+        archive_path: Some("~/Backups".to_string()),
+        compression: Some(CompressionCodec::Gzip),
+        cipher: Some(CipherKind::Aes256Gcm),
+        ..Default::default()
+    };
+    let merged = nightly.apply(&explicit_defaults).unwrap();
+    assert_eq!(merged.archive_path.as_deref(), Some("~/Backups"));
+    assert_eq!(merged.compression, Some(CompressionCodec::Gzip));
+    assert_eq!(merged.cipher, Some(CipherKind::Aes256Gcm));
+
+    nightly.encrypt_key_file = Some(dir.path().join("missing").to_string_lossy().to_string());
+    assert!(nightly.apply(&defaults).is_err());
+}
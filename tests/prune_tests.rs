@@ -0,0 +1,115 @@
+mod common;
+
+use chrono::{Local, TimeZone};
+use common::home_component;
+use parch_backup::cli::{BackupArgs, RetentionArgs};
+use parch_backup::prune::prune::{apply_policy, parse_archive_name, prune_archives, PruneItem};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn item(year: i32, month: u32, day: u32, hour: u32) -> PruneItem {
+    let created = Local.with_ymd_and_hms(year, month, day, hour, 0, 0).unwrap();
+    PruneItem {
+        path: PathBuf::new(),
+        name: created.to_rfc3339(),
+        created,
+        flags: "h".to_string(),
+        reasons: Vec::new(),
+    }
+}
+
+fn backup(home: &Path, archive_dir: &Path, incremental_from: Option<&str>) -> PathBuf {
+    let args = BackupArgs {
+        archive_path: Some(archive_dir.to_string_lossy().to_string()),
+        home: true,
+        incremental_from: incremental_from.map(str::to_string),
+        ..Default::default()
+    };
+    common::backup(&[home_component(home)], &args)
+}
+
+#[test]
+fn test_policy_keeps_newest_of_each_period() {
+    // 2026-10-18 is a Sunday, 2026-10-10 falls in the week before
+    let mut items = vec![
+        item(2026, 10, 18, 10),
+        item(2026, 10, 18, 8),
+        item(2026, 10, 17, 12),
+        item(2026, 10, 16, 12),
+        item(2026, 10, 10, 12),
+        item(2026, 9, 20, 12),
+        item(2026, 8, 5, 12),
+    ];
+    let policy = RetentionArgs {
+        keep_last: 1,
+        keep_daily: 2,
+        keep_weekly: 2,
+        keep_monthly: 3,
+    };
+    apply_policy(&mut items, &policy);
+
+    let reasons: Vec<Vec<&str>> = items
+        .iter()
+        .map(|i| i.reasons.iter().map(String::as_str).collect())
+        .collect();
+    assert_eq!(
+        reasons,
+        vec![
+            vec!["last", "daily", "weekly", "monthly"],
+            vec![],
+            vec!["daily"],
+            vec![],
+            vec!["weekly"],
+            vec!["monthly"],
+            vec!["monthly"],
+        ]
+    );
+}
+
+#[test]
+fn test_parse_archive_name() {
+    let (created, flags) = parse_archive_name("backup-2026-10-18-07-02-08-ahke.pbar").unwrap();
+    assert_eq!(created.naive_utc().to_string(), "2026-10-18 07:02:08");
+    assert_eq!(flags, "ahke");
+
+    assert!(parse_archive_name("backup-2026-10-18.pbar").is_none());
+    assert!(parse_archive_name("notes-2026-10-18-07-02-08-h.pbar").is_none());
+    assert!(parse_archive_name("backup-2026-10-18-07-02-08-h.pbar.part").is_none());
+}
+
+#[test]
+fn test_prune_keeps_bases_of_kept_increments() {
+    let work = tempfile::tempdir().unwrap();
+    let home = work.path().join("home");
+    let archive_dir = work.path().join("archives");
+    fs::create_dir_all(&home).unwrap();
+    fs::write(home.join("a.txt"), "a\n").unwrap();
+
+    let full = backup(&home, &archive_dir, None);
+    fs::write(home.join("b.txt"), "b\n").unwrap();
+    let increment = backup(&home, &archive_dir, Some("auto"));
+    // Not an archive name, never touched
+    fs::write(archive_dir.join("notes.txt"), "keep me").unwrap();
+
+    let keep_last = RetentionArgs {
+        keep_last: 1,
+        ..Default::default()
+    };
+    let deleted = prune_archives(&archive_dir, &keep_last, None, false).unwrap();
+    assert!(deleted.is_empty());
+    assert!(full.exists() && increment.exists());
+
+    let newer = backup(&home, &archive_dir, None);
+    let planned = prune_archives(&archive_dir, &keep_last, None, true).unwrap();
+    assert_eq!(planned.len(), 2);
+    assert!(full.exists() && increment.exists());
+
+    let mut deleted = prune_archives(&archive_dir, &keep_last, None, false).unwrap();
+    deleted.sort();
+    assert_eq!(deleted, vec![full.clone(), increment.clone()]);
+    assert!(!full.exists() && !increment.exists());
+    assert!(newer.exists());
+    assert!(archive_dir.join("notes.txt").exists());
+
+    assert!(prune_archives(&archive_dir, &RetentionArgs::default(), None, false).is_err());
+}
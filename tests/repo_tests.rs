@@ -0,0 +1,166 @@
+mod common;
+
+use common::{home_component, write_file};
+use parch_backup::cli::{BackupArgs, RestoreArgs};
+use parch_backup::list::list::walk_archive;
+use parch_backup::pbar::PbarArchive;
+use parch_backup::repo::chunker::{Chunker, ChunkerParams};
+use parch_backup::repo::repo::collect_garbage;
+use parch_backup::repo::store::{list_snapshots, Repository};
+use parch_backup::restore::restore::handle_restore;
+use rand::RngCore;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn snapshot(home: &Path, repo: &Path) -> PathBuf {
+    let args = BackupArgs {
+        home: true,
+        encrypt: true,
+        encrypt_key: Some("pw".to_string()),
+        repo: Some(repo.to_string_lossy().to_string()),
+        ..Default::default()
+    };
+    common::backup(&[home_component(home)], &args)
+}
+
+fn chunk_count(repo: &Path) -> usize {
+    let repo = Repository::open(repo, Some("pw")).unwrap();
+    repo.stored_chunks().unwrap().0.len()
+}
+
+fn restore(repo: &Path, snapshot: Option<&Path>, target: &Path) -> std::io::Result<()> {
+    handle_restore(&RestoreArgs {
+        repo: Some(repo.to_string_lossy().to_string()),
+        snapshot: snapshot.map(|s| s.file_name().unwrap().to_string_lossy().to_string()),
+        decrypt: true,
+        decrypt_key: Some("pw".to_string()),
+        target: Some(target.to_string_lossy().to_string()),
+        ..Default::default()
+    })
+}
+
+#[test]
+fn test_snapshots_store_unchanged_files_once() {
+    let work = tempfile::tempdir().unwrap();
+    let home = work.path().join("home");
+    // A repository inside the backed up tree must not end up in its own snapshots
+    let repo = home.join("Backups/repo");
+
+    write_file(&home.join(".bashrc"), "alias ll='ls -l'\n");
+    write_file(&home.join("notes/a.txt"), "first\n");
+    write_file(&home.join("notes/copy.txt"), "first\n");
+    let first = snapshot(&home, &repo);
+    assert_eq!(chunk_count(&repo), 2);
+
+    write_file(&home.join("notes/a.txt"), "second\n");
+    let second = snapshot(&home, &repo);
+    assert_eq!(chunk_count(&repo), 3);
+    assert_eq!(list_snapshots(&repo).unwrap(), vec![first.clone(), second.clone()]);
+
+    let mut listed = Vec::new();
+    walk_archive(PbarArchive::open(&second, Some("pw")).unwrap(), |entry, _| {
+        if !entry.entry_type.is_dir() {
+            listed.push(entry.path.to_string_lossy().to_string());
+        }
+        Ok(())
+    })
+    .unwrap();
+    listed.sort();
+    assert_eq!(listed, vec![".bashrc", "notes/a.txt", "notes/copy.txt"]);
+
+    let mut archive = PbarArchive::open(&second, Some("pw")).unwrap();
+    assert!(archive.manifest.repository.is_some());
+    assert_eq!(archive.read_index().unwrap().unwrap().files.len(), 3);
+}
+
+#[test]
+fn test_restore_snapshot_from_repo() {
+    let work = tempfile::tempdir().unwrap();
+    let home = work.path().join("home");
+    let repo = work.path().join("repo");
+
+    write_file(&home.join(".config/app/settings.ini"), "theme=dark\n");
+    write_file(&home.join("notes/a.txt"), "first\n");
+    let first = snapshot(&home, &repo);
+    write_file(&home.join("notes/a.txt"), "second\n");
+    snapshot(&home, &repo);
+
+    let latest = work.path().join("latest");
+    restore(&repo, None, &latest).expect("Restore newest snapshot");
+    assert_eq!(fs::read_to_string(latest.join("notes/a.txt")).unwrap(), "second\n");
+    assert_eq!(
+        fs::read_to_string(latest.join(".config/app/settings.ini")).unwrap(),
+        "theme=dark\n"
+    );
+
+    let older = work.path().join("older");
+    restore(&repo, Some(&first), &older).expect("Restore first snapshot");
+    assert_eq!(fs::read_to_string(older.join("notes/a.txt")).unwrap(), "first\n");
+
+    // A wrong key must not open the repository
+    let result = handle_restore(&RestoreArgs {
+        repo: Some(repo.to_string_lossy().to_string()),
+        decrypt: true,
+        decrypt_key: Some("wrong".to_string()),
+        target: Some(work.path().join("wrong").to_string_lossy().to_string()),
+        ..Default::default()
+    });
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_gc_removes_chunks_of_forgotten_snapshots() {
+    let work = tempfile::tempdir().unwrap();
+    let home = work.path().join("home");
+    let repo = work.path().join("repo");
+
+    write_file(&home.join("keep.txt"), "kept\n");
+    write_file(&home.join("a.txt"), "old\n");
+    let first = snapshot(&home, &repo);
+    write_file(&home.join("a.txt"), "new\n");
+    snapshot(&home, &repo);
+    assert_eq!(chunk_count(&repo), 3);
+
+    collect_garbage(&repo, Some("pw")).unwrap();
+    assert_eq!(chunk_count(&repo), 3);
+
+    fs::remove_file(&first).unwrap();
+    collect_garbage(&repo, Some("pw")).unwrap();
+    assert_eq!(chunk_count(&repo), 2);
+
+    let target = work.path().join("target");
+    restore(&repo, None, &target).expect("Restore remaining snapshot");
+    assert_eq!(fs::read_to_string(target.join("a.txt")).unwrap(), "new\n");
+    assert_eq!(fs::read_to_string(target.join("keep.txt")).unwrap(), "kept\n");
+}
+
+#[test]
+fn test_chunk_boundaries_survive_an_insertion() {
+    let params = ChunkerParams {
+        min_size: 2 * 1024,
+        avg_size: 8 * 1024,
+        max_size: 64 * 1024,
+    };
+    let mut data = vec![0u8; 1024 * 1024];
+    rand::thread_rng().fill_bytes(&mut data);
+    let mut shifted = b"inserted at the front".to_vec();
+    shifted.extend_from_slice(&data);
+
+    let chunks = |bytes: &[u8]| -> Vec<Vec<u8>> {
+        Chunker::new(bytes, params).map(|c| c.unwrap()).collect()
+    };
+    let original = chunks(&data);
+    let moved = chunks(&shifted);
+
+    assert_eq!(original.concat(), data);
+    assert!(original.iter().all(|c| c.len() <= params.max_size));
+    let before: HashSet<&Vec<u8>> = original.iter().collect();
+    let shared = moved.iter().filter(|c| before.contains(c)).count();
+    assert!(
+        shared + 2 >= original.len(),
+        "only {} of {} chunks shared",
+        shared,
+        original.len()
+    );
+}
@@ -0,0 +1,216 @@
+mod common;
+
+use common::{nested_tarball, write_file};
+use parch_backup::backup::consolidate::{consolidate_backups, BackupComponentMeta, ComponentSource};
+use clap::Parser;
+use parch_backup::cli::{BackupArgs, Cli, Commands, RestoreArgs};
+use parch_backup::pbar::header::{PbarHeader, FLAG_ENCRYPTED_MANIFEST, PBAR_MAGIC};
+use parch_backup::pbar::manifest::PbarManifest;
+use parch_backup::pbar::stream::CipherKind;
+use parch_backup::list::list::walk_archive;
+use parch_backup::pbar::PbarArchive;
+use parch_backup::restore::restore::handle_restore;
+use parch_backup::utils::compression::CompressionCodec;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+
+#[test]
+fn test_encrypted_backup_restore_roundtrip() {
+    let work = tempfile::tempdir().expect("Temp dir");
+    let source_home = work.path().join("source-home");
+    let restore_home = work.path().join("restore-home");
+    let archive_dir = work.path().join("archives");
+
+    write_file(&source_home.join(".config/nvim/init.lua"), "vim.o.number = true\n");
+    write_file(&source_home.join("notes/todo.txt"), "back up everything\n");
+    fs::create_dir_all(&restore_home).expect("Create restore home");
+
+    let component = work.path().join("home_backup.tar.zst");
+    nested_tarball(&source_home, &component, CompressionCodec::Zstd);
+
+    let components = vec![BackupComponentMeta {
+        category: "homeb",
+        source: ComponentSource::File(component.clone()),
+        count: 1,
+        size_bytes: fs::metadata(&component).unwrap().len(),
+        extra_info: None,
+    }];
+
+    let backup_args = BackupArgs {
+        archive_path: Some(archive_dir.to_string_lossy().to_string()),
+        home: true,
+        encrypt: true,
+        encrypt_key: Some("correct horse".to_string()),
+        compression: Some(CompressionCodec::Zstd),
+        cipher: Some(CipherKind::ChaCha20Poly1305),
+        ..Default::default()
+    };
+    let archive_path = consolidate_backups(&components, &backup_args, &AtomicBool::new(false)).expect("Create archive");
+
+    // Header and manifest framing must be readable without touching the chunk stream.
+    let mut file = fs::File::open(&archive_path).expect("Open archive");
+    let header = PbarHeader::read_from(&mut file).expect("Read header");
+    assert!(header.is_encrypted());
+    assert_ne!(header.feature_flags & FLAG_ENCRYPTED_MANIFEST, 0);
+    let mut manifest_block = vec![0u8; header.manifest_size as usize];
+    file.read_exact(&mut manifest_block).expect("Read manifest block");
+    assert!(PbarManifest::from_json_slice(&manifest_block).is_err());
+    assert!(PbarManifest::from_block(&manifest_block, &header, None).is_err());
+    assert_eq!(&fs::read(&archive_path).unwrap()[0..4], PBAR_MAGIC);
+
+    let wrong_password = RestoreArgs {
+        archive_path: Some(archive_path.clone()),
+        decrypt: true,
+        decrypt_key: Some("wrong".to_string()),
+        target: Some(restore_home.to_string_lossy().to_string()),
+        ..Default::default()
+    };
+    assert!(handle_restore(&wrong_password).is_err());
+    assert!(!restore_home.join("notes/todo.txt").exists());
+
+    let dry_run = RestoreArgs {
+        archive_path: Some(archive_path.clone()),
+        decrypt: true,
+        decrypt_key: Some("correct horse".to_string()),
+        target: Some(restore_home.to_string_lossy().to_string()),
+        dry_run: true,
+        ..Default::default()
+    };
+    handle_restore(&dry_run).expect("Dry-run restore");
+    assert!(!restore_home.join(".config").exists());
+
+    let restore_args = RestoreArgs {
+        archive_path: Some(archive_path.clone()),
+        decrypt: true,
+        decrypt_key: Some("correct horse".to_string()),
+        target: Some(restore_home.to_string_lossy().to_string()),
+        ..Default::default()
+    };
+    handle_restore(&restore_args).expect("Restore archive");
+
+    assert_eq!(
+        fs::read_to_string(restore_home.join(".config/nvim/init.lua")).unwrap(),
+        "vim.o.number = true\n"
+    );
+    assert_eq!(
+        fs::read_to_string(restore_home.join("notes/todo.txt")).unwrap(),
+        "back up everything\n"
+    );
+
+    // Selective restore only brings back the requested directory.
+    let partial_home = work.path().join("partial-home");
+
+    let selective_args = RestoreArgs {
+        include: vec!["~/.config/nvim".to_string()],
+        target: Some(partial_home.to_string_lossy().to_string()),
+        ..restore_args
+    };
+    handle_restore(&selective_args).expect("Selective restore");
+
+    assert!(partial_home.join(".config/nvim/init.lua").exists());
+    assert!(!partial_home.join("notes").exists());
+}
+
+#[test]
+fn test_streamed_home_backup_skips_its_own_archive() {
+    let work = tempfile::tempdir().expect("Temp dir");
+    let source_home = work.path().join("source-home");
+    let restore_home = work.path().join("restore-home");
+    // The archive is written inside the tree being backed up.
+    let archive_dir = source_home.join("Backups");
+
+    write_file(&source_home.join(".bashrc"), "export EDITOR=nvim\n");
+    write_file(&source_home.join(".cache/thumb.png"), "large");
+    write_file(&archive_dir.join("older.pbar"), "previous backup");
+
+    let components = vec![BackupComponentMeta {
+        category: "homeb",
+        source: ComponentSource::Directory {
+            root: source_home.clone(),
+            exclude_paths: vec![source_home.join(".cache")],
+        },
+        count: 3,
+        size_bytes: 0,
+        extra_info: None,
+    }];
+    let backup_args = BackupArgs {
+        archive_path: Some(archive_dir.to_string_lossy().to_string()),
+        home: true,
+        ..Default::default()
+    };
+    let archive_path =
+        consolidate_backups(&components, &backup_args, &AtomicBool::new(false)).expect("Create archive");
+
+    let mut paths = Vec::new();
+    let archive = PbarArchive::open(&archive_path, None).expect("Open archive");
+    walk_archive(archive, |entry, _| {
+        paths.push(entry.path.to_string_lossy().to_string());
+        Ok(())
+    })
+    .expect("Walk archive");
+    paths.sort();
+    assert_eq!(paths, vec![".bashrc", "Backups", "Backups/older.pbar"]);
+
+    let restore_args = RestoreArgs {
+        archive_path: Some(archive_path.clone()),
+        target: Some(restore_home.to_string_lossy().to_string()),
+        ..Default::default()
+    };
+    handle_restore(&restore_args).expect("Restore archive");
+    assert_eq!(
+        fs::read_to_string(restore_home.join(".bashrc")).unwrap(),
+        "export EDITOR=nvim\n"
+    );
+    assert!(!restore_home.join(".cache").exists());
+}
+
+#[test]
+fn test_interrupted_backup_leaves_no_archive() {
+    let work = tempfile::tempdir().expect("Temp dir");
+    let source_home = work.path().join("source-home");
+    let archive_dir = work.path().join("archives");
+    write_file(&source_home.join(".bashrc"), "export EDITOR=nvim\n");
+
+    let components = vec![BackupComponentMeta {
+        category: "homeb",
+        source: ComponentSource::Directory {
+            root: source_home,
+            exclude_paths: Vec::new(),
+        },
+        count: 1,
+        size_bytes: 0,
+        extra_info: None,
+    }];
+    let backup_args = BackupArgs {
+        archive_path: Some(archive_dir.to_string_lossy().to_string()),
+        home: true,
+        ..Default::default()
+    };
+    let err = consolidate_backups(&components, &backup_args, &AtomicBool::new(true)).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Interrupted);
+    assert_eq!(fs::read_dir(&archive_dir).unwrap().count(), 0);
+}
+
+#[test]
+fn test_restore_requires_an_archive_or_repository() {
+    let parse = |args: &[&str]| -> Result<_, clap::Error> {
+        let cli = Cli::try_parse_from(["parch-backup", "restore"].iter().chain(args))?;
+        match cli.command {
+            Commands::Restore(restore) => Ok((restore.archive_path, restore.repo)),
+            _ => unreachable!("parsed as a restore command"),
+        }
+    };
+
+    assert!(parse(&[]).is_err());
+    assert!(parse(&["backup.pbar", "--repo", "/srv/repo"]).is_err());
+    assert_eq!(
+        parse(&["backup.pbar"]).unwrap(),
+        (Some(PathBuf::from("backup.pbar")), None)
+    );
+    assert_eq!(
+        parse(&["--repo", "/srv/repo"]).unwrap(),
+        (None, Some("/srv/repo".to_string()))
+    );
+}
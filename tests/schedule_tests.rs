@@ -0,0 +1,93 @@
+use parch_backup::system::schedule::{
+    check_backup_args, quote_arg, read_schedules, remove_units, render_service, render_timer,
+    write_units, Schedule,
+};
+
+fn schedule(name: &str) -> Schedule {
+    Schedule {
+        name: name.to_string(),
+        on_calendar: "Mon *-*-* 03:00".to_string(),
+        randomized_delay: Some("30min".to_string()),
+        command: vec![
+            "/usr/bin/parch-backup".to_string(),
+            "backup".to_string(),
+            "--home".to_string(),
+            "--archive-path".to_string(),
+            "/mnt/My Backups".to_string(),
+            "--exclude-dir".to_string(),
+            "100%".to_string(),
+        ],
+    }
+}
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+#[test]
+fn test_render_units() {
+    let schedule = schedule("nightly");
+    let service = render_service(&schedule);
+    assert!(service.contains("Type=oneshot\n"));
+    assert!(service.contains(
+        "ExecStart=/usr/bin/parch-backup backup --home --archive-path \"/mnt/My Backups\" --exclude-dir 100%%\n"
+    ));
+
+    let timer = render_timer(&schedule);
+    assert!(timer.contains("OnCalendar=Mon *-*-* 03:00\n"));
+    assert!(timer.contains("Persistent=true\n"));
+    assert!(timer.contains("RandomizedDelaySec=30min\n"));
+    assert!(timer.contains("Unit=parch-backup-nightly.service\n"));
+    assert!(timer.contains("WantedBy=timers.target\n"));
+
+    let immediate = Schedule {
+        randomized_delay: None,
+        ..schedule
+    };
+    assert!(!render_timer(&immediate).contains("RandomizedDelaySec"));
+
+    assert_eq!(quote_arg("say \"hi\" $USER"), "\"say \\\"hi\\\" $$USER\"");
+    assert_eq!(quote_arg(""), "\"\"");
+}
+
+#[test]
+fn test_write_list_and_remove_units() {
+    let dir = tempfile::tempdir().unwrap();
+    let units = dir.path().join("systemd/user");
+    assert!(read_schedules(&units).unwrap().is_empty());
+
+    let (service, timer) = write_units(&units, &schedule("nightly")).unwrap();
+    assert!(service.ends_with("parch-backup-nightly.service"));
+    assert!(timer.ends_with("parch-backup-nightly.timer"));
+    write_units(&units, &schedule("weekly")).unwrap();
+    std::fs::write(units.join("other.timer"), "[Timer]\nOnCalendar=daily\n").unwrap();
+
+    let schedules = read_schedules(&units).unwrap();
+    let names: Vec<&str> = schedules.iter().map(|s| s.name.as_str()).collect();
+    assert_eq!(names, vec!["nightly", "weekly"]);
+    assert_eq!(schedules[0].on_calendar, "Mon *-*-* 03:00");
+    assert!(schedules[0]
+        .exec_start
+        .starts_with("/usr/bin/parch-backup backup --home"));
+
+    assert!(remove_units(&units, "nightly").unwrap());
+    assert!(!remove_units(&units, "nightly").unwrap());
+    assert_eq!(read_schedules(&units).unwrap().len(), 1);
+
+    // Names and values must not escape their unit file or line
+    assert!(write_units(&units, &schedule("../escape")).is_err());
+    let injected = Schedule {
+        on_calendar: "daily\nExecStartPre=/bin/true".to_string(),
+        ..schedule("injected")
+    };
+    assert!(write_units(&units, &injected).is_err());
+}
+
+#[test]
+fn test_check_backup_args() {
+    assert!(check_backup_args(&args(&["--home", "--keys", "--keep-daily", "7"])).is_ok());
+    // Nothing selected would ask for confirmation, which a timer cannot answer
+    assert!(check_backup_args(&args(&["--archive-path", "/mnt"])).is_err());
+    assert!(check_backup_args(&args(&["--home", "--no-such-option"])).is_err());
+    assert!(check_backup_args(&args(&["--home", "--encrypt", "--encrypt-key", "pw"])).is_err());
+}
@@ -0,0 +1,109 @@
+use ed25519_dalek::SigningKey;
+use parch_backup::backup::consolidate::{consolidate_backups, BackupComponentMeta, ComponentSource};
+use parch_backup::cli::BackupArgs;
+use parch_backup::pbar::header::PbarHeader;
+use parch_backup::pbar::signature::{
+    prepare_signed_header, sign_header, verify_archive_signature, HashingWriter, SignatureStatus,
+};
+use parch_backup::pbar::PbarArchive;
+use parch_backup::verify::verify::check_signature;
+use std::fs;
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+
+fn build_archive(signing_key: Option<&SigningKey>, content: &[u8]) -> Vec<u8> {
+    let mut header = PbarHeader::new(false, true, 0);
+    if let Some(key) = signing_key {
+        prepare_signed_header(&mut header, key);
+    }
+
+    let mut hashing_writer = HashingWriter::new(Vec::new());
+    hashing_writer.write_all(content).expect("Write content");
+    let (body, digest) = hashing_writer.finalize();
+
+    if let Some(key) = signing_key {
+        sign_header(&mut header, key, &digest).expect("Sign header");
+    }
+
+    let mut archive = Vec::new();
+    header.write_to(&mut archive).expect("Write header");
+    archive.extend_from_slice(&body);
+    archive
+}
+
+#[test]
+fn test_signed_archive_verifies() {
+    let key = SigningKey::from_bytes(&[11u8; 32]);
+    let archive = build_archive(Some(&key), b"manifest and payload bytes");
+
+    let parsed = PbarHeader::read_from(&archive[..]).expect("Read header");
+    assert!(parsed.is_signed());
+
+    let status = verify_archive_signature(&archive[..]).expect("Verify signature");
+    assert_eq!(
+        status,
+        SignatureStatus::Valid {
+            public_key: key.verifying_key().to_bytes()
+        }
+    );
+}
+
+#[test]
+fn test_tampered_archive_is_rejected() {
+    let key = SigningKey::from_bytes(&[11u8; 32]);
+    let mut archive = build_archive(Some(&key), b"manifest and payload bytes");
+
+    let last = archive.len() - 1;
+    archive[last] ^= 0x01;
+    assert!(verify_archive_signature(&archive[..]).is_err());
+
+    // Flipping a header flag must also invalidate the signature.
+    let mut archive = build_archive(Some(&key), b"manifest and payload bytes");
+    archive[7] ^= 0x02;
+    assert!(verify_archive_signature(&archive[..]).is_err());
+}
+
+#[test]
+fn test_unsigned_archive_reports_unsigned() {
+    let archive = build_archive(None, b"plain payload");
+    let status = verify_archive_signature(&archive[..]).expect("Verify signature");
+    assert_eq!(status, SignatureStatus::Unsigned);
+}
+
+#[test]
+fn test_checked_handle_survives_swapped_archive() {
+    let work = tempfile::tempdir().unwrap();
+    let key_file = work.path().join("signing.key");
+    fs::write(&key_file, hex::encode([11u8; 32])).unwrap();
+    let backup = |dir: &str, contents: &str, sign: bool| {
+        let components = vec![BackupComponentMeta {
+            category: "appsb",
+            source: ComponentSource::Data {
+                file_name: "apps.txt",
+                contents: contents.as_bytes().to_vec(),
+            },
+            count: 0,
+            size_bytes: 0,
+            extra_info: None,
+        }];
+        let args = BackupArgs {
+            archive_path: Some(work.path().join(dir).to_string_lossy().to_string()),
+            apps: true,
+            sign_key: sign.then(|| key_file.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        consolidate_backups(&components, &args, &AtomicBool::new(false)).expect("Create archive")
+    };
+    let signed = backup("signed", "bash\n", true);
+    let forged = backup("forged", "malware\n", false);
+
+    let mut file = fs::File::open(&signed).unwrap();
+    let status = check_signature(&mut file, &[]).expect("Check signature");
+    assert!(matches!(status, SignatureStatus::Valid { .. }));
+
+    // Replacing the file after the check does not affect the open handle
+    fs::rename(&forged, &signed).unwrap();
+    let archive = PbarArchive::from_file(file, &signed, None).expect("Open checked handle");
+    assert!(archive.manifest.security.signed);
+    assert!(!PbarArchive::open(&signed, None).unwrap().manifest.security.signed);
+}
@@ -0,0 +1,188 @@
+mod common;
+
+use common::{home_component, write_file};
+use parch_backup::cli::{BackupArgs, VerifyArgs};
+use parch_backup::pbar::archive::read_header;
+use parch_backup::verify::verify::{handle_verify, verify_archive};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use walkdir::WalkDir;
+
+fn backup(home: &Path, args: BackupArgs) -> PathBuf {
+    let args = BackupArgs {
+        home: true,
+        encrypt: true,
+        encrypt_key: Some("pw".to_string()),
+        ..args
+    };
+    common::backup(&[home_component(home)], &args)
+}
+
+fn home_with_files(root: &Path) -> PathBuf {
+    let home = root.join("home");
+    fs::create_dir_all(home.join("notes")).unwrap();
+    fs::write(home.join(".bashrc"), "alias ll='ls -l'\n").unwrap();
+    fs::write(home.join("notes/a.txt"), "first\n".repeat(50_000)).unwrap();
+    home
+}
+
+#[test]
+fn test_verify_reads_every_file() {
+    let work = tempfile::tempdir().unwrap();
+    let home = home_with_files(work.path());
+    let archive_dir = work.path().join("archives");
+    let archive = backup(
+        &home,
+        BackupArgs {
+            archive_path: Some(archive_dir.to_string_lossy().to_string()),
+            ..Default::default()
+        },
+    );
+
+    let report = verify_archive(&archive, Some("pw")).expect("Verify archive");
+    assert_eq!(report.files_checked, 2);
+    assert_eq!(report.files_elsewhere, 0);
+    assert!(report.chunks >= 1);
+    assert!(verify_archive(&archive, Some("wrong")).is_err());
+
+    handle_verify(&VerifyArgs {
+        archive_path: archive.to_string_lossy().to_string(),
+        decrypt_key: Some("pw".to_string()),
+        ..Default::default()
+    })
+    .expect("Verify command");
+
+    fs::write(home.join(".bashrc"), "changed\n").unwrap();
+    let increment = backup(
+        &home,
+        BackupArgs {
+            archive_path: Some(archive_dir.to_string_lossy().to_string()),
+            incremental_from: Some("auto".to_string()),
+            ..Default::default()
+        },
+    );
+    let report = verify_archive(&increment, Some("pw")).expect("Verify increment");
+    assert_eq!(report.files_checked, 1);
+    assert_eq!(report.files_elsewhere, 1);
+}
+
+#[test]
+fn test_verify_reports_corrupt_and_truncated_payload() {
+    let work = tempfile::tempdir().unwrap();
+    let home = home_with_files(work.path());
+    let archive = backup(
+        &home,
+        BackupArgs {
+            archive_path: Some(work.path().join("archives").to_string_lossy().to_string()),
+            ..Default::default()
+        },
+    );
+    let original = fs::read(&archive).unwrap();
+    let index_offset = read_header(&archive).unwrap().index_offset.unwrap() as usize;
+
+    // The last payload chunk, then the file index after it
+    for at in [index_offset - 20, original.len() - 20] {
+        let mut corrupt = original.clone();
+        corrupt[at] ^= 0x01;
+        fs::write(&archive, &corrupt).unwrap();
+        let error = verify_archive(&archive, Some("pw"))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("Corrupt archive"), "{}", error);
+        assert!(error.contains("chunk"), "{}", error);
+    }
+
+    fs::write(&archive, &original[..original.len() - 40]).unwrap();
+    let error = verify_archive(&archive, Some("pw"))
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("Corrupt archive"), "{}", error);
+
+    fs::write(&archive, &original).unwrap();
+    assert!(verify_archive(&archive, Some("pw")).is_ok());
+}
+
+#[test]
+fn test_verify_names_file_with_corrupt_repository_chunk() {
+    let work = tempfile::tempdir().unwrap();
+    let home = home_with_files(work.path());
+    let repo = work.path().join("repo");
+    let snapshot = backup(
+        &home,
+        BackupArgs {
+            repo: Some(repo.to_string_lossy().to_string()),
+            ..Default::default()
+        },
+    );
+    assert_eq!(
+        verify_archive(&snapshot, Some("pw")).unwrap().files_checked,
+        2
+    );
+
+    for entry in WalkDir::new(repo.join("chunks")) {
+        let entry = entry.unwrap();
+        if entry.file_type().is_file() {
+            let mut data = fs::read(entry.path()).unwrap();
+            let last = data.len() - 1;
+            data[last] ^= 0x01;
+            fs::write(entry.path(), data).unwrap();
+        }
+    }
+    let error = verify_archive(&snapshot, Some("pw"))
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("in homeb/"), "{}", error);
+}
+
+#[test]
+fn test_verify_backup_of_changing_home() {
+    let work = tempfile::tempdir().unwrap();
+    let home = work.path().join("home");
+    let files: Vec<PathBuf> = (0..300).map(|i| home.join(format!("files/{}.txt", i))).collect();
+    for (i, path) in files.iter().enumerate() {
+        write_file(path, &"x".repeat(i * 50));
+    }
+    let args = || BackupArgs {
+        archive_path: Some(work.path().join("archives").to_string_lossy().to_string()),
+        ..Default::default()
+    };
+    let full = backup(&home, args());
+
+    // Rewrite files with other sizes, delete and recreate them while backups run
+    let done = Arc::new(AtomicBool::new(false));
+    let churn = {
+        let done = Arc::clone(&done);
+        let files = files.clone();
+        std::thread::spawn(move || {
+            let mut round = 0;
+            while !done.load(Ordering::SeqCst) {
+                for (i, path) in files.iter().enumerate() {
+                    if (i + round) % 3 == 0 {
+                        let _ = fs::remove_file(path);
+                    } else {
+                        let _ = fs::write(path, "y".repeat((i * 37 + round * 11) % 20_000));
+                    }
+                }
+                round += 1;
+            }
+        })
+    };
+    let during = backup(&home, args());
+    let increment = backup(
+        &home,
+        BackupArgs {
+            incremental_from: Some(full.to_string_lossy().to_string()),
+            ..args()
+        },
+    );
+    done.store(true, Ordering::SeqCst);
+    churn.join().unwrap();
+
+    for archive in [&full, &during, &increment] {
+        if let Err(e) = verify_archive(archive, Some("pw")) {
+            panic!("{}: {}", archive.display(), e);
+        }
+    }
+}